@@ -1,36 +1,60 @@
 use crate::cloud::{
-    key_cmp, Cloud, CloudError, Created, NetworkError, ResponseError, Result, Server,
+    check_response, fetch_all_pages, found, key_cmp, poll_until, Cloud, CloudError, Created,
+    RateLimit, ResponseError, Result, Server, SpawnOptions,
 };
+use crate::http;
 use crate::CreatedAuth;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use futures_util::stream::FuturesUnordered;
 use futures_util::TryStreamExt;
-use petname::petname;
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
 use std::time::Duration;
 use thrussh_keys::key::KeyPair;
 use thrussh_keys::PublicKeyBase64;
-use tokio::time::sleep;
-use tracing::{debug, info, instrument};
+use tracing::{debug, info, instrument, warn};
+
+/// Maximum time to wait for a droplet action like a resize to complete
+const ACTION_TIMEOUT: Duration = Duration::from_secs(15 * 60);
 
 pub struct DigitalOcean {
-    region: String,
+    /// Regions to try in order when spawning a server
+    regions: Vec<String>,
     plan: String,
+    managed_tag: String,
     token: String,
     client: Client,
+    api_attempts: u32,
+    rate_limit: RateLimit,
+    /// Slug of the image to create droplets from
+    image: String,
+    /// Id of the vpc to create droplets in, the default vpc of the region if not set
+    vpc_id: Option<String>,
 }
 
 impl DigitalOcean {
-    pub fn new(token: String, region: String, plan: String) -> Self {
+    pub fn new(
+        token: String,
+        regions: Vec<String>,
+        plan: String,
+        managed_tag: String,
+        image: String,
+        vpc_id: Option<String>,
+        api_attempts: u32,
+    ) -> Self {
         DigitalOcean {
             token,
-            region,
+            regions,
             plan,
-            client: Client::default(),
+            managed_tag,
+            client: http::client(),
+            api_attempts,
+            rate_limit: RateLimit::default(),
+            image,
+            vpc_id,
         }
     }
 }
@@ -38,29 +62,19 @@ impl DigitalOcean {
 #[async_trait]
 impl Cloud for DigitalOcean {
     async fn list(&self) -> Result<Vec<Server>> {
-        let response = self
-            .client
-            .get("https://api.digitalocean.com/v2/droplets")
-            .bearer_auth(&self.token)
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())?;
-
-        let response: DigitalOceanListResponse =
-            response.json().await.map_err(ResponseError::from)?;
+        let droplets = fetch_all_pages(|next| self.list_page(next)).await?;
 
-        Ok(response
-            .droplets
+        Ok(droplets
             .into_iter()
-            .filter(|instance| instance.tags.iter().any(|tag| tag == "spire"))
+            .filter(|instance| instance.tags.contains(&self.managed_tag))
             .map(Server::from)
             .collect())
     }
 
-    #[instrument(skip(self, ssh_keys))]
-    async fn spawn(&self, ssh_keys: &[String]) -> Result<Created> {
-        let startup_key = Arc::new(KeyPair::generate_ed25519().unwrap());
+    #[instrument(skip_all, fields(label = options.label))]
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created> {
+        let SpawnOptions { ssh_keys, .. } = *options;
+        let startup_key = options.deploy_key();
         let startup_key_id = self
             .create_key(
                 "Dispenser Deploy Key",
@@ -81,74 +95,421 @@ impl Cloud for DigitalOcean {
             .await?;
         key_ids.push(startup_key_id);
 
-        let response_res = self
-            .client
-            .post("https://api.digitalocean.com/v2/droplets")
-            .bearer_auth(&self.token)
-            .json(&DigitalOceanCreateParams {
-                region: self.region.as_str(),
-                size: self.plan.as_str(),
-                tags: &["spire"],
-                name: petname(2, "-").expect("failed to generate name"),
-                image: "docker-20-04",
-                ssh_keys: key_ids,
-                ipv6: true,
-            })
-            .send()
-            .await
-            .map_err(NetworkError::from);
-
-        self.remove_key(startup_key_id).await?;
-
-        // remove the deploy key, even if the spawn request failed
-        let response = response_res?;
+        let image = match options.snapshot {
+            Some(snapshot) => DigitalOceanImage::Id(snapshot.parse().map_err(|_| {
+                CloudError::InvalidConfig(format!("Invalid snapshot id {}", snapshot))
+            })?),
+            None => DigitalOceanImage::Slug(&self.image),
+        };
 
-        CloudError::from_status_code(response.status())?;
+        let mut response_res = Err(CloudError::InvalidConfig("No regions configured".into()));
+        for region in &self.regions {
+            response_res = self.create_droplet(region, options, image, &key_ids).await;
+            match &response_res {
+                Ok(_) => {
+                    info!(region = region.as_str(), "created droplet");
+                    break;
+                }
+                Err(e) => warn!(
+                    region = region.as_str(),
+                    error = %e,
+                    "failed to create droplet in region"
+                ),
+            }
+        }
 
-        if response.status().is_success() {
-            let response: DigitalOceanCreateResponse =
-                response.json().await.map_err(ResponseError::from)?;
-            Ok((response.droplet, startup_key).into())
-        } else {
-            Err(ResponseError::Other(response.text().await.map_err(NetworkError::from)?).into())
+        // remove the deploy key, even if the spawn request failed
+        // a leftover key shouldn't cause the created droplet to be lost
+        if let Err(e) = self.remove_key(startup_key_id).await {
+            warn!(error = %e, "failed to remove deploy key");
         }
+
+        Ok((response_res?.droplet, startup_key).into())
     }
 
     async fn kill(&self, id: &str) -> Result<()> {
         let response = self
-            .client
-            .delete(format!("https://api.digitalocean.com/v2/droplets/{}", id))
-            .bearer_auth(&self.token)
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())
+            .rate_limit
+            .send(
+                self.client
+                    .delete(format!("https://api.digitalocean.com/v2/droplets/{}", id))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        check_response(response).await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Server>> {
+        Ok(found(self.get_instance(id).await)?.map(Server::from))
+    }
+
+    async fn wait_for_ip(&self, id: &str, timeout: Duration) -> Result<Server> {
+        poll_until(timeout, || async {
+            let server = Server::from(self.get_instance(id).await?);
+            Ok(server.has_ip().then_some(server))
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn reboot(&self, id: &str) -> Result<()> {
+        self.run_action(
+            id,
+            &DigitalOceanActionParams {
+                kind: "power_cycle",
+                ..DigitalOceanActionParams::default()
+            },
+        )
+        .await
+    }
+
+    /// Droplets need to be powered off to be resized, the droplet is powered back on afterwards
+    #[instrument(skip(self))]
+    async fn resize(&self, id: &str, plan: &str) -> Result<()> {
+        info!("powering off droplet");
+        self.run_action(
+            id,
+            &DigitalOceanActionParams {
+                kind: "power_off",
+                ..DigitalOceanActionParams::default()
+            },
+        )
+        .await?;
+        info!("resizing droplet");
+        let resized = self
+            .run_action(
+                id,
+                &DigitalOceanActionParams {
+                    kind: "resize",
+                    size: Some(plan),
+                    disk: Some(false),
+                    ..DigitalOceanActionParams::default()
+                },
+            )
+            .await;
+        // power the droplet back on, even if the resize failed
+        info!("powering on droplet");
+        self.run_action(
+            id,
+            &DigitalOceanActionParams {
+                kind: "power_on",
+                ..DigitalOceanActionParams::default()
+            },
+        )
+        .await?;
+        resized
+    }
+
+    #[instrument(skip(self))]
+    async fn attach_ip(&self, id: &str, ip: IpAddr) -> Result<()> {
+        let droplet_id = id.parse().map_err(|_| CloudError::ServerNotFound)?;
+        self.run_action_at(
+            format!(
+                "https://api.digitalocean.com/v2/reserved_ips/{}/actions",
+                ip
+            ),
+            &DigitalOceanActionParams {
+                kind: "assign",
+                droplet_id: Some(droplet_id),
+                ..DigitalOceanActionParams::default()
+            },
+        )
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn detach_ip(&self, ip: IpAddr) -> Result<()> {
+        self.run_action_at(
+            format!(
+                "https://api.digitalocean.com/v2/reserved_ips/{}/actions",
+                ip
+            ),
+            &DigitalOceanActionParams {
+                kind: "unassign",
+                ..DigitalOceanActionParams::default()
+            },
+        )
+        .await
+    }
+
+    #[instrument(skip(self))]
+    async fn snapshot(&self, id: &str, name: &str) -> Result<String> {
+        self.run_action(
+            id,
+            &DigitalOceanActionParams {
+                kind: "snapshot",
+                name: Some(name),
+                ..DigitalOceanActionParams::default()
+            },
+        )
+        .await?;
+
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .get(format!(
+                        "https://api.digitalocean.com/v2/droplets/{}/snapshots",
+                        id
+                    ))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: DigitalOceanSnapshotsResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        response
+            .snapshots
+            .into_iter()
+            .rev()
+            .find(|snapshot| snapshot.name == name)
+            .map(|snapshot| snapshot.id.to_string())
+            .ok_or_else(|| ResponseError::Other(format!("Snapshot {} not found", name)).into())
+    }
+
+    async fn hourly_price(&self, plan: &str) -> Result<Option<f64>> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .get("https://api.digitalocean.com/v2/sizes?per_page=200")
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: DigitalOceanSizesResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        Ok(response
+            .sizes
+            .into_iter()
+            .find(|size| size.slug == plan)
+            .map(|size| size.price_hourly))
+    }
+
+    /// Digitalocean reports the balance including this month's usage as a negative number for remaining credit
+    async fn account_balance(&self) -> Result<Option<f64>> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .get("https://api.digitalocean.com/v2/customers/my/balance")
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: DigitalOceanBalanceResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        let balance: f64 = response.month_to_date_balance.parse().map_err(|_| {
+            ResponseError::Other(format!(
+                "Invalid balance {}",
+                response.month_to_date_balance
+            ))
+        })?;
+        Ok(Some(-balance))
     }
 
-    async fn wait_for_ip(&self, id: &str) -> Result<Server> {
-        let instance = loop {
-            let instance = self.get_instance(id).await?;
-            let ip = instance.networks.v4().next();
-            if ip.is_some() {
-                break instance;
-            } else {
-                sleep(Duration::from_millis(500)).await;
+    async fn validate(&self) -> Result<()> {
+        self.list().await?;
+
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .get("https://api.digitalocean.com/v2/regions?per_page=200")
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: DigitalOceanRegionsResponse =
+            response.json().await.map_err(ResponseError::from)?;
+
+        for slug in &self.regions {
+            let region = response
+                .regions
+                .iter()
+                .find(|region| &region.slug == slug)
+                .ok_or_else(|| CloudError::InvalidConfig(format!("Unknown region {}", slug)))?;
+            if !region.available {
+                return Err(CloudError::InvalidConfig(format!(
+                    "Region {} is not available",
+                    slug
+                )));
             }
-        };
-        Ok(instance.into())
+            if !region.sizes.contains(&self.plan) {
+                return Err(CloudError::InvalidConfig(format!(
+                    "Plan {} is not available in region {}",
+                    self.plan, slug
+                )));
+            }
+        }
+
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .get(format!(
+                        "https://api.digitalocean.com/v2/images/{}",
+                        self.image
+                    ))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(CloudError::InvalidConfig(format!(
+                "Unknown image {}",
+                self.image
+            )));
+        }
+        let response = check_response(response).await?;
+        let response: DigitalOceanImageResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        if let Some(slug) = self
+            .regions
+            .iter()
+            .find(|slug| !response.image.regions.contains(slug))
+        {
+            return Err(CloudError::InvalidConfig(format!(
+                "Image {} is not available in region {}",
+                self.image, slug
+            )));
+        }
+        Ok(())
     }
 }
 
 impl DigitalOcean {
+    /// Fetch a single page of droplets, `next` is the url of the next page as returned by the previous page
+    async fn list_page(
+        &self,
+        next: Option<String>,
+    ) -> Result<(Vec<DigitalOceanInstanceResponse>, Option<String>)> {
+        let url = next.unwrap_or_else(|| {
+            format!(
+                "https://api.digitalocean.com/v2/droplets?per_page={}",
+                LIST_PAGE_SIZE
+            )
+        });
+        let response = self
+            .rate_limit
+            .send(
+                self.client.get(url).bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+
+        let response: DigitalOceanListResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        Ok(response.into_page())
+    }
+
+    async fn create_droplet(
+        &self,
+        region: &str,
+        options: &SpawnOptions<'_>,
+        image: DigitalOceanImage<'_>,
+        key_ids: &[u32],
+    ) -> Result<DigitalOceanCreateResponse> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .post("https://api.digitalocean.com/v2/droplets")
+                    .bearer_auth(&self.token)
+                    .json(&DigitalOceanCreateParams {
+                        region,
+                        size: options.plan.unwrap_or(&self.plan),
+                        tags: &[&self.managed_tag],
+                        name: options.label,
+                        image,
+                        ssh_keys: key_ids,
+                        ipv6: true,
+                        user_data: options.user_data,
+                        vpc_uuid: self.vpc_id.as_deref(),
+                    }),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        Ok(response.json().await.map_err(ResponseError::from)?)
+    }
+
+    /// Start an action on a droplet and wait for it to complete
+    async fn run_action(&self, id: &str, params: &DigitalOceanActionParams<'_>) -> Result<()> {
+        self.run_action_at(
+            format!("https://api.digitalocean.com/v2/droplets/{}/actions", id),
+            params,
+        )
+        .await
+    }
+
+    /// Start an action trough the given actions endpoint and wait for it to complete
+    async fn run_action_at(
+        &self,
+        url: String,
+        params: &DigitalOceanActionParams<'_>,
+    ) -> Result<()> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client.post(url).bearer_auth(&self.token).json(params),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: DigitalOceanActionResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        let action_id = response.action.id;
+
+        let action = poll_until(ACTION_TIMEOUT, || async {
+            let response = self
+                .rate_limit
+                .send(
+                    self.client
+                        .get(format!(
+                            "https://api.digitalocean.com/v2/actions/{}",
+                            action_id
+                        ))
+                        .bearer_auth(&self.token),
+                    self.api_attempts,
+                )
+                .await?;
+            let response = check_response(response).await?;
+            let response: DigitalOceanActionResponse =
+                response.json().await.map_err(ResponseError::from)?;
+            Ok((response.action.status != "in-progress").then_some(response.action))
+        })
+        .await?;
+
+        if action.status == "completed" {
+            Ok(())
+        } else {
+            Err(ResponseError::Other(format!(
+                "{} action ended with status {}",
+                params.kind, action.status
+            ))
+            .into())
+        }
+    }
+
     async fn get_instance(&self, id: &str) -> Result<DigitalOceanInstanceResponse> {
         let response = self
-            .client
-            .get(format!("https://api.digitalocean.com/v2/droplets/{}", id))
-            .bearer_auth(&self.token)
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())?;
+            .rate_limit
+            .send(
+                self.client
+                    .get(format!("https://api.digitalocean.com/v2/droplets/{}", id))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
 
         let response: DigitalOceanGetResponse =
             response.json().await.map_err(ResponseError::from)?;
@@ -158,19 +519,15 @@ impl DigitalOcean {
     #[instrument(skip(self))]
     async fn get_ssh_key_id(&self, ssh_key: &str) -> Result<u32> {
         let response = self
-            .client
-            .get("https://api.digitalocean.com/v2/account/keys/")
-            .bearer_auth(&self.token)
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())?;
-
-        if !response.status().is_success() {
-            return Err(
-                ResponseError::Other(response.text().await.map_err(NetworkError::from)?).into(),
-            );
-        }
+            .rate_limit
+            .send(
+                self.client
+                    .get("https://api.digitalocean.com/v2/account/keys/")
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
 
         let response: DigitalOceanSshListResponse =
             response.json().await.map_err(ResponseError::from)?;
@@ -190,18 +547,19 @@ impl DigitalOcean {
     #[instrument(skip(self))]
     async fn create_key(&self, name: &str, ssh_key: &str) -> Result<u32> {
         let response = self
-            .client
-            .post("https://api.digitalocean.com/v2/account/keys/")
-            .bearer_auth(&self.token)
-            .json(&DigitalOceanCreateSshKeyParams {
-                name,
-                public_key: ssh_key,
-            })
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())?;
-        let response = response.error_for_status().map_err(NetworkError)?;
+            .rate_limit
+            .send(
+                self.client
+                    .post("https://api.digitalocean.com/v2/account/keys/")
+                    .bearer_auth(&self.token)
+                    .json(&DigitalOceanCreateSshKeyParams {
+                        name,
+                        public_key: ssh_key,
+                    }),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
         let response: DigitalOceanSshCreateResponse =
             response.json().await.map_err(ResponseError::from)?;
 
@@ -211,16 +569,18 @@ impl DigitalOcean {
     #[instrument(skip(self))]
     async fn remove_key(&self, key_id: u32) -> Result<()> {
         let response = self
-            .client
-            .delete(format!(
-                "https://api.digitalocean.com/v2/account/keys/{}",
-                key_id
-            ))
-            .bearer_auth(&self.token)
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())?;
+            .rate_limit
+            .send(
+                self.client
+                    .delete(format!(
+                        "https://api.digitalocean.com/v2/account/keys/{}",
+                        key_id
+                    ))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        check_response(response).await?;
 
         Ok(())
     }
@@ -228,18 +588,127 @@ impl DigitalOcean {
 
 #[derive(Serialize)]
 struct DigitalOceanCreateParams<'a> {
-    name: String,
+    name: &'a str,
     region: &'a str,
     size: &'a str,
     tags: &'a [&'a str],
-    image: &'a str,
-    ssh_keys: Vec<u32>,
+    image: DigitalOceanImage<'a>,
+    ssh_keys: &'a [u32],
     ipv6: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_data: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vpc_uuid: Option<&'a str>,
+}
+
+/// Droplets are created from a public image by slug, or from a snapshot by id
+#[derive(Clone, Copy, Serialize)]
+#[serde(untagged)]
+enum DigitalOceanImage<'a> {
+    Slug(&'a str),
+    Id(u64),
+}
+
+#[derive(Serialize, Default)]
+struct DigitalOceanActionParams<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disk: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    droplet_id: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanSnapshotsResponse {
+    snapshots: Vec<DigitalOceanSnapshot>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanSnapshot {
+    id: u64,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanActionResponse {
+    action: DigitalOceanAction,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanAction {
+    id: u64,
+    status: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanBalanceResponse {
+    /// Decimal amount as string
+    month_to_date_balance: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanImageResponse {
+    image: DigitalOceanImageInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanImageInfo {
+    regions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanSizesResponse {
+    sizes: Vec<DigitalOceanSize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanSize {
+    slug: String,
+    price_hourly: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigitalOceanRegionsResponse {
+    regions: Vec<DigitalOceanRegion>,
 }
 
+#[derive(Debug, Deserialize)]
+struct DigitalOceanRegion {
+    slug: String,
+    available: bool,
+    sizes: Vec<String>,
+}
+
+const LIST_PAGE_SIZE: u32 = 200;
+
 #[derive(Debug, Deserialize)]
 struct DigitalOceanListResponse {
     droplets: Vec<DigitalOceanInstanceResponse>,
+    #[serde(default)]
+    links: DigitalOceanLinks,
+}
+
+impl DigitalOceanListResponse {
+    fn into_page(self) -> (Vec<DigitalOceanInstanceResponse>, Option<String>) {
+        (self.droplets, self.links.pages.next)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DigitalOceanLinks {
+    #[serde(default)]
+    pages: DigitalOceanPages,
+}
+
+/// Urls of the other pages, only set if there is such a page
+#[derive(Debug, Default, Deserialize)]
+struct DigitalOceanPages {
+    next: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -256,11 +725,14 @@ struct DigitalOceanCreateResponse {
 #[derive(Debug, Deserialize)]
 struct DigitalOceanInstanceResponse {
     id: u32,
+    name: String,
     memory: u64,
     networks: DigitalOceanNetworks,
     vcpus: u16,
     created_at: DateTime<Utc>,
     tags: Vec<String>,
+    region: DigitalOceanRegion,
+    size_slug: String,
 }
 
 #[allow(dead_code)]
@@ -278,6 +750,13 @@ impl DigitalOceanNetworks {
             .map(|net| net.ip_address)
     }
 
+    fn private_v4(&self) -> Option<IpAddr> {
+        self.v4
+            .iter()
+            .find(|net| net.ty == DigitalOceanNetworkType::Private)
+            .map(|net| net.ip_address)
+    }
+
     fn v6(&self) -> impl Iterator<Item = IpAddr> + '_ {
         self.v6
             .iter()
@@ -312,6 +791,7 @@ impl From<DigitalOceanInstanceResponse> for Server {
     fn from(instance: DigitalOceanInstanceResponse) -> Self {
         Server {
             id: instance.id.to_string(),
+            label: instance.name,
             created: instance.created_at,
             ip: instance
                 .networks
@@ -319,6 +799,9 @@ impl From<DigitalOceanInstanceResponse> for Server {
                 .next()
                 .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
             ip_v6: instance.networks.v6().next(),
+            private_ip: instance.networks.private_v4(),
+            region: instance.region.slug,
+            plan: instance.size_slug,
         }
     }
 }
@@ -362,3 +845,51 @@ struct DigitalOceanSshKey {
     public_key: String,
     name: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn droplet(id: u32) -> String {
+        format!(
+            r#"{{"id": {id}, "name": "spire", "memory": 2048, "vcpus": 1, "created_at": "2024-06-01T12:00:00Z",
+            "tags": ["spire"], "size_slug": "s-1vcpu-2gb",
+            "region": {{"slug": "ams3", "available": true, "sizes": []}},
+            "networks": {{"v4": [{{"ip_address": "192.0.2.1", "gateway": "192.0.2.254", "type": "public"}}], "v6": []}}}}"#
+        )
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() {
+        let next = "https://api.digitalocean.com/v2/droplets?page=2&per_page=200";
+        let pages = [
+            format!(
+                r#"{{"droplets": [{}, {}], "links": {{"pages": {{"next": "{next}", "last": "{next}"}}}}, "meta": {{"total": 3}}}}"#,
+                droplet(1),
+                droplet(2)
+            ),
+            // the last page has no links
+            format!(
+                r#"{{"droplets": [{}], "meta": {{"total": 3}}}}"#,
+                droplet(3)
+            ),
+        ];
+        let mut urls = Vec::new();
+        let droplets = fetch_all_pages(|url| {
+            let page = match url.as_deref() {
+                None => &pages[0],
+                Some(url) if url == next => &pages[1],
+                Some(url) => panic!("unexpected url {}", url),
+            };
+            urls.push(url);
+            let response = serde_json::from_str::<DigitalOceanListResponse>(page).unwrap();
+            async move { Ok(response.into_page()) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(urls, [None, Some(next.to_string())]);
+        let ids: Vec<_> = droplets.iter().map(|droplet| droplet.id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::cloud::{Cloud, CloudError, Created, CreatedAuth, Result, Server, SpawnOptions};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+/// Wraps a cloud provider, only logging the actions that would modify servers
+///
+/// Listing servers is still passed to the wrapped provider, with any servers "spawned" in the dry run added.
+pub struct DryRunCloud {
+    inner: Arc<dyn Cloud>,
+    description: String,
+    servers: Mutex<Vec<Server>>,
+    spawned: AtomicU8,
+}
+
+impl DryRunCloud {
+    pub fn new(inner: Arc<dyn Cloud>, description: String) -> Self {
+        DryRunCloud {
+            inner,
+            description,
+            servers: Mutex::default(),
+            spawned: AtomicU8::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Cloud for DryRunCloud {
+    async fn list(&self) -> Result<Vec<Server>> {
+        let mut servers = self.inner.list().await?;
+        servers.extend(self.servers.lock().unwrap().iter().cloned());
+        Ok(servers)
+    }
+
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created> {
+        let label = options.label;
+        let index = self.spawned.fetch_add(1, Ordering::SeqCst) + 1;
+        let id = format!("dry-run-{}", index);
+        info!(
+            label = label,
+            id = id,
+            "dry run: would spawn server on {}",
+            self.description
+        );
+        self.servers.lock().unwrap().push(Server {
+            id: id.clone(),
+            label: label.into(),
+            created: Utc::now(),
+            // TEST-NET-1, reserved for documentation
+            ip: IpAddr::V4(Ipv4Addr::new(192, 0, 2, index)),
+            ip_v6: None,
+            private_ip: None,
+            region: "dry-run".into(),
+            plan: options.plan.unwrap_or("dry-run").into(),
+        });
+        Ok(Created {
+            id,
+            auth: CreatedAuth::Password("dry-run".into()),
+        })
+    }
+
+    async fn kill(&self, id: &str) -> Result<()> {
+        info!(id = id, "dry run: would kill server");
+        self.servers
+            .lock()
+            .unwrap()
+            .retain(|server| server.id != id);
+        Ok(())
+    }
+
+    async fn wait_for_ip(&self, id: &str, _timeout: Duration) -> Result<Server> {
+        self.servers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|server| server.id == id)
+            .cloned()
+            .ok_or(CloudError::ServerNotFound)
+    }
+
+    async fn resize(&self, id: &str, plan: &str) -> Result<()> {
+        info!(id = id, plan = plan, "dry run: would resize server");
+        Ok(())
+    }
+
+    async fn reboot(&self, id: &str) -> Result<()> {
+        info!(id = id, "dry run: would reboot server");
+        Ok(())
+    }
+
+    async fn attach_ip(&self, id: &str, ip: IpAddr) -> Result<()> {
+        info!(id = id, ip = %ip, "dry run: would attach reserved ip");
+        Ok(())
+    }
+
+    async fn detach_ip(&self, ip: IpAddr) -> Result<()> {
+        info!(ip = %ip, "dry run: would detach reserved ip");
+        Ok(())
+    }
+
+    async fn snapshot(&self, id: &str, name: &str) -> Result<String> {
+        info!(id = id, name = name, "dry run: would create snapshot");
+        Ok(String::from("dry-run-snapshot"))
+    }
+
+    async fn hourly_price(&self, plan: &str) -> Result<Option<f64>> {
+        self.inner.hourly_price(plan).await
+    }
+
+    async fn account_balance(&self) -> Result<Option<f64>> {
+        self.inner.account_balance().await
+    }
+
+    async fn validate(&self) -> Result<()> {
+        self.inner.validate().await
+    }
+}
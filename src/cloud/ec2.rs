@@ -0,0 +1,445 @@
+use crate::cloud::{
+    poll_until, send_with_retry, Cloud, CloudError, Created, CreatedAuth, NetworkError,
+    ResponseError, Result, Server, SpawnOptions,
+};
+use crate::http;
+use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{Client, RequestBuilder, Response};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use thrussh_keys::PublicKeyBase64;
+use tracing::{instrument, warn};
+
+const API_VERSION: &str = "2016-11-15";
+
+/// Cloud-init config to allow logging in as root with the deploy key
+const USER_DATA: &str = "#cloud-config\ndisable_root: false\n";
+
+pub struct Ec2 {
+    access_key_id: String,
+    secret_access_key: String,
+    region: String,
+    instance_type: String,
+    ami: String,
+    spot: bool,
+    spot_max_price: Option<String>,
+    security_group_ids: Vec<String>,
+    managed_tag: String,
+    client: Client,
+    api_attempts: u32,
+}
+
+/// Instance options for ec2
+pub struct Ec2Options {
+    pub region: String,
+    pub instance_type: String,
+    pub ami: String,
+    pub spot: bool,
+    pub spot_max_price: Option<String>,
+    pub security_group_ids: Vec<String>,
+    /// Value of the `dispenser` tag used to recognize managed instances
+    pub managed_tag: String,
+}
+
+impl Ec2 {
+    pub fn new(
+        access_key_id: String,
+        secret_access_key: String,
+        options: Ec2Options,
+        api_attempts: u32,
+    ) -> Self {
+        Ec2 {
+            access_key_id,
+            secret_access_key,
+            region: options.region,
+            instance_type: options.instance_type,
+            ami: options.ami,
+            spot: options.spot,
+            spot_max_price: options.spot_max_price,
+            security_group_ids: options.security_group_ids,
+            managed_tag: options.managed_tag,
+            client: http::client(),
+            api_attempts,
+        }
+    }
+}
+
+#[async_trait]
+impl Cloud for Ec2 {
+    async fn list(&self) -> Result<Vec<Server>> {
+        let response: Ec2DescribeInstancesResponse = self
+            .call(
+                "DescribeInstances",
+                vec![
+                    ("Filter.1.Name".into(), "tag:dispenser".into()),
+                    ("Filter.1.Value.1".into(), self.managed_tag.clone()),
+                    ("Filter.2.Name".into(), "instance-state-name".into()),
+                    ("Filter.2.Value.1".into(), "pending".into()),
+                    ("Filter.2.Value.2".into(), "running".into()),
+                ],
+            )
+            .await?;
+
+        Ok(response.instances().map(Server::from).collect())
+    }
+
+    #[instrument(skip_all, fields(label = options.label))]
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created> {
+        let SpawnOptions {
+            label, ssh_keys, ..
+        } = *options;
+        if options.snapshot.is_some() {
+            return Err(CloudError::Unsupported("booting from a snapshot"));
+        }
+        let startup_key = options.deploy_key();
+        let key_name = format!("dispenser-deploy-{}-{}", label, Utc::now().timestamp());
+        self.call::<Ec2Ignored>(
+            "ImportKeyPair",
+            vec![
+                ("KeyName".into(), key_name.clone()),
+                (
+                    "PublicKeyMaterial".into(),
+                    BASE64_STANDARD.encode(format!(
+                        "{} {} {}",
+                        startup_key.name(),
+                        startup_key.public_key_base64(),
+                        "dispenser-deploy"
+                    )),
+                ),
+            ],
+        )
+        .await?;
+
+        // ec2 only supports a single key pair, add the configured keys trough cloud-init
+        let mut user_data = String::from(USER_DATA);
+        if !ssh_keys.is_empty() {
+            user_data.push_str("ssh_authorized_keys:\n");
+            for key in ssh_keys {
+                user_data.push_str(&format!("  - {}\n", key));
+            }
+        }
+
+        let mut params = vec![
+            ("ImageId".into(), self.ami.clone()),
+            (
+                "InstanceType".into(),
+                options.plan.unwrap_or(&self.instance_type).into(),
+            ),
+            ("MinCount".into(), "1".into()),
+            ("MaxCount".into(), "1".into()),
+            ("KeyName".into(), key_name.clone()),
+            ("UserData".into(), BASE64_STANDARD.encode(user_data)),
+            ("TagSpecification.1.ResourceType".into(), "instance".into()),
+            ("TagSpecification.1.Tag.1.Key".into(), "dispenser".into()),
+            (
+                "TagSpecification.1.Tag.1.Value".into(),
+                self.managed_tag.clone(),
+            ),
+            ("TagSpecification.1.Tag.2.Key".into(), "Name".into()),
+            ("TagSpecification.1.Tag.2.Value".into(), label.into()),
+        ];
+        for (i, group) in self.security_group_ids.iter().enumerate() {
+            params.push((format!("SecurityGroupId.{}", i + 1), group.clone()));
+        }
+        if self.spot {
+            params.push(("InstanceMarketOptions.MarketType".into(), "spot".into()));
+            if let Some(max_price) = &self.spot_max_price {
+                params.push((
+                    "InstanceMarketOptions.SpotOptions.MaxPrice".into(),
+                    max_price.clone(),
+                ));
+            }
+        }
+
+        let response_res = self
+            .call::<Ec2RunInstancesResponse>("RunInstances", params)
+            .await;
+
+        // remove the deploy key, even if the spawn request failed
+        // a leftover key shouldn't cause the created instance to be lost
+        if let Err(e) = self
+            .call::<Ec2Ignored>("DeleteKeyPair", vec![("KeyName".into(), key_name)])
+            .await
+        {
+            warn!(error = %e, "failed to remove deploy key");
+        }
+
+        let instance = response_res?
+            .instances_set
+            .item
+            .into_iter()
+            .next()
+            .ok_or_else(|| ResponseError::Other("No instance created".into()))?;
+
+        Ok(Created {
+            id: instance.instance_id,
+            auth: CreatedAuth::Ssh(startup_key),
+        })
+    }
+
+    async fn kill(&self, id: &str) -> Result<()> {
+        self.call::<Ec2Ignored>(
+            "TerminateInstances",
+            vec![("InstanceId.1".into(), id.into())],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Server>> {
+        let response: Result<Ec2DescribeInstancesResponse> = self
+            .call(
+                "DescribeInstances",
+                vec![
+                    ("InstanceId.1".into(), id.into()),
+                    ("Filter.1.Name".into(), "instance-state-name".into()),
+                    ("Filter.1.Value.1".into(), "pending".into()),
+                    ("Filter.1.Value.2".into(), "running".into()),
+                ],
+            )
+            .await;
+        match response {
+            Ok(response) => Ok(response.instances().next().map(Server::from)),
+            Err(CloudError::ApiError { message, .. })
+                if message.starts_with("InvalidInstanceID.NotFound") =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn wait_for_ip(&self, id: &str, timeout: Duration) -> Result<Server> {
+        let instance = poll_until(timeout, || async {
+            let instance = self.get_instance(id).await?;
+            let ready = instance.ip_address.is_some();
+            Ok(ready.then_some(instance))
+        })
+        .await?;
+        Ok(instance.into())
+    }
+}
+
+impl Ec2 {
+    async fn get_instance(&self, id: &str) -> Result<Ec2Instance> {
+        let response: Ec2DescribeInstancesResponse = self
+            .call(
+                "DescribeInstances",
+                vec![("InstanceId.1".into(), id.into())],
+            )
+            .await?;
+        response
+            .instances()
+            .next()
+            .ok_or(CloudError::ServerNotFound)
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        action: &str,
+        mut params: Vec<(String, String)>,
+    ) -> Result<T> {
+        params.push(("Action".into(), action.into()));
+        params.push(("Version".into(), API_VERSION.into()));
+        let body = serde_urlencoded::to_string(&params)
+            .map_err(|e| ResponseError::Other(e.to_string()))?;
+
+        let response = send_with_retry(self.signed(body, Utc::now()), self.api_attempts).await?;
+        CloudError::from_status_code(response.status())?;
+        parse_response(response).await
+    }
+
+    fn host(&self) -> String {
+        format!("ec2.{}.amazonaws.com", self.region)
+    }
+
+    /// Build a request signed with aws signature version 4
+    fn signed(&self, body: String, now: DateTime<Utc>) -> RequestBuilder {
+        const CONTENT_TYPE: &str = "application/x-www-form-urlencoded; charset=utf-8";
+
+        let host = self.host();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/ec2/aws4_request", date, self.region);
+
+        let canonical_request = format!(
+            "POST\n/\n\ncontent-type:{}\nhost:{}\nx-amz-date:{}\n\ncontent-type;host;x-amz-date\n{}",
+            CONTENT_TYPE,
+            host,
+            amz_date,
+            hex::encode(Sha256::digest(body.as_bytes()))
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = hmac_sha256(
+            format!("AWS4{}", self.secret_access_key).as_bytes(),
+            date.as_bytes(),
+        );
+        let key = hmac_sha256(&key, self.region.as_bytes());
+        let key = hmac_sha256(&key, b"ec2");
+        let key = hmac_sha256(&key, b"aws4_request");
+        let signature = hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        self.client
+            .post(format!("https://{}/", host))
+            .header("content-type", CONTENT_TYPE)
+            .header("x-amz-date", amz_date)
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=content-type;host;x-amz-date, Signature={}",
+                    self.access_key_id, scope, signature
+                ),
+            )
+            .body(body)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+async fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T> {
+    let status = response.status();
+    let text = response.text().await.map_err(NetworkError::from)?;
+    if status.is_success() {
+        quick_xml::de::from_str(&text).map_err(|e| ResponseError::Other(e.to_string()).into())
+    } else {
+        let message = quick_xml::de::from_str::<Ec2ErrorResponse>(&text)
+            .ok()
+            .and_then(|response| response.errors.error.into_iter().next())
+            .map(|error| format!("{}: {}", error.code, error.message))
+            .unwrap_or(text);
+        Err(CloudError::ApiError { status, message })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Ec2ErrorResponse {
+    errors: Ec2Errors,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Ec2Errors {
+    #[serde(default)]
+    error: Vec<Ec2Error>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Ec2Error {
+    code: String,
+    message: String,
+}
+
+/// Response for actions where we don't care about the content
+#[derive(Debug, Deserialize)]
+struct Ec2Ignored {}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Ec2DescribeInstancesResponse {
+    #[serde(default)]
+    reservation_set: Ec2ItemSet<Ec2Reservation>,
+}
+
+impl Ec2DescribeInstancesResponse {
+    fn instances(self) -> impl Iterator<Item = Ec2Instance> {
+        self.reservation_set
+            .item
+            .into_iter()
+            .flat_map(|reservation| reservation.instances_set.item)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Ec2RunInstancesResponse {
+    instances_set: Ec2ItemSet<Ec2Instance>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Ec2ItemSet<T> {
+    #[serde(default = "Vec::new")]
+    item: Vec<T>,
+}
+
+impl<T> Default for Ec2ItemSet<T> {
+    fn default() -> Self {
+        Ec2ItemSet { item: Vec::new() }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Ec2Reservation {
+    #[serde(default)]
+    instances_set: Ec2ItemSet<Ec2Instance>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Ec2Instance {
+    instance_id: String,
+    launch_time: DateTime<Utc>,
+    ip_address: Option<IpAddr>,
+    ipv6_address: Option<IpAddr>,
+    #[serde(default)]
+    tag_set: Ec2ItemSet<Ec2Tag>,
+    #[serde(default)]
+    instance_type: String,
+    #[serde(default)]
+    placement: Ec2Placement,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Ec2Placement {
+    #[serde(default)]
+    availability_zone: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Ec2Tag {
+    key: String,
+    value: String,
+}
+
+impl From<Ec2Instance> for Server {
+    fn from(instance: Ec2Instance) -> Self {
+        let label = instance
+            .tag_set
+            .item
+            .into_iter()
+            .find(|tag| tag.key == "Name")
+            .map(|tag| tag.value)
+            .unwrap_or_default();
+        Server {
+            id: instance.instance_id,
+            label,
+            created: instance.launch_time,
+            ip: instance
+                .ip_address
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            ip_v6: instance.ipv6_address,
+            private_ip: None,
+            region: instance.placement.availability_zone,
+            plan: instance.instance_type,
+        }
+    }
+}
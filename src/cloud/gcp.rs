@@ -0,0 +1,435 @@
+use crate::cloud::{
+    check_response, poll_until, send_with_retry, Cloud, CloudError, Created, CreatedAuth,
+    ResponseError, Result, Server, SpawnOptions,
+};
+use crate::http;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thrussh_keys::PublicKeyBase64;
+use tracing::instrument;
+
+const SCOPE: &str = "https://www.googleapis.com/auth/compute";
+
+/// Container-optimized OS comes with docker pre-installed
+const IMAGE: &str = "projects/cos-cloud/global/images/family/cos-stable";
+
+/// Container-optimized OS doesn't allow root logins by default, enable it and authorize the keys from the `ssh-keys` metadata
+const STARTUP_SCRIPT: &str = "#!/bin/bash
+mkdir -p /root/.ssh
+curl -sf -H 'Metadata-Flavor: Google' http://metadata.google.internal/computeMetadata/v1/instance/attributes/ssh-keys | sed 's/^root://' > /root/.ssh/authorized_keys
+chmod 700 /root/.ssh && chmod 600 /root/.ssh/authorized_keys
+sed -i 's/^PermitRootLogin.*/PermitRootLogin prohibit-password/' /etc/ssh/sshd_config
+systemctl restart sshd
+";
+
+/// Refresh the access token a bit before it expires
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+pub struct Gcp {
+    key: GcpServiceAccountKey,
+    encoding_key: EncodingKey,
+    project: String,
+    zone: String,
+    machine_type: String,
+    network: String,
+    network_tags: Vec<String>,
+    managed_tag: String,
+    client: Client,
+    api_attempts: u32,
+    /// Access token and the time it expires, cached until shortly before it expires
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+/// The relevant fields from a service account json key
+#[derive(Debug, Deserialize)]
+pub struct GcpServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+/// Instance options for gcp
+pub struct GcpOptions {
+    pub project: String,
+    pub zone: String,
+    pub machine_type: String,
+    pub network: String,
+    pub network_tags: Vec<String>,
+    pub managed_tag: String,
+}
+
+impl Gcp {
+    pub fn new(key: GcpServiceAccountKey, options: GcpOptions, api_attempts: u32) -> Result<Self> {
+        let encoding_key = EncodingKey::from_rsa_pem(key.private_key.as_bytes()).map_err(|e| {
+            CloudError::InvalidConfig(format!("Invalid service account private key: {}", e))
+        })?;
+        Ok(Gcp {
+            key,
+            encoding_key,
+            project: options.project,
+            zone: options.zone,
+            machine_type: options.machine_type,
+            network: options.network,
+            network_tags: options.network_tags,
+            managed_tag: options.managed_tag,
+            client: http::client(),
+            api_attempts,
+            token: Mutex::default(),
+        })
+    }
+
+    fn instances_url(&self) -> String {
+        format!(
+            "https://compute.googleapis.com/compute/v1/projects/{}/zones/{}/instances",
+            self.project, self.zone
+        )
+    }
+}
+
+#[async_trait]
+impl Cloud for Gcp {
+    async fn list(&self) -> Result<Vec<Server>> {
+        let request = self
+            .client
+            .get(self.instances_url())
+            .query(&[("filter", format!("labels.{}=true", self.managed_tag))]);
+        let response = self.send(request).await?;
+        let response: GcpListResponse = response.json().await.map_err(ResponseError::from)?;
+
+        Ok(response.items.into_iter().map(Server::from).collect())
+    }
+
+    #[instrument(skip_all, fields(label = options.label))]
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created> {
+        let SpawnOptions {
+            label, ssh_keys, ..
+        } = *options;
+        if options.snapshot.is_some() {
+            return Err(CloudError::Unsupported("booting from a snapshot"));
+        }
+        let startup_key = options.deploy_key();
+
+        // the deploy key is part of the instance metadata, no need to register or remove it
+        let authorized_keys = ssh_keys
+            .iter()
+            .cloned()
+            .chain(Some(format!(
+                "{} {} {}",
+                startup_key.name(),
+                startup_key.public_key_base64(),
+                "dispenser-deploy"
+            )))
+            .map(|key| format!("root:{}", key))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        // names need to be unique, also while the instance from a failed attempt is still being deleted
+        let name = format!("{}-{}", label, Utc::now().timestamp());
+        let network = format!("global/networks/{}", self.network);
+        let request = self
+            .client
+            .post(self.instances_url())
+            .json(&GcpCreateParams {
+                name: &name,
+                machine_type: format!(
+                    "zones/{}/machineTypes/{}",
+                    self.zone,
+                    options.plan.unwrap_or(&self.machine_type)
+                ),
+                labels: HashMap::from([(self.managed_tag.as_str(), "true")]),
+                disks: vec![GcpAttachedDisk {
+                    boot: true,
+                    auto_delete: true,
+                    initialize_params: GcpDiskParams {
+                        source_image: IMAGE,
+                    },
+                }],
+                tags: GcpTags {
+                    items: &self.network_tags,
+                },
+                network_interfaces: vec![GcpCreateNetworkInterface {
+                    network: &network,
+                    access_configs: vec![GcpCreateAccessConfig {
+                        name: "External NAT",
+                        kind: "ONE_TO_ONE_NAT",
+                    }],
+                }],
+                metadata: GcpMetadata {
+                    items: vec![
+                        GcpMetadataItem {
+                            key: "ssh-keys",
+                            value: &authorized_keys,
+                        },
+                        GcpMetadataItem {
+                            key: "startup-script",
+                            value: STARTUP_SCRIPT,
+                        },
+                    ],
+                },
+            });
+        self.send(request).await?;
+
+        // instances are addressed by name in the api
+        Ok(Created {
+            id: name,
+            auth: CreatedAuth::Ssh(startup_key),
+        })
+    }
+
+    async fn kill(&self, id: &str) -> Result<()> {
+        let request = self
+            .client
+            .delete(format!("{}/{}", self.instances_url(), id));
+        self.send(request).await?;
+        Ok(())
+    }
+
+    async fn wait_for_ip(&self, id: &str, timeout: Duration) -> Result<Server> {
+        let instance = poll_until(timeout, || async {
+            let request = self.client.get(format!("{}/{}", self.instances_url(), id));
+            let instance: GcpInstance = self
+                .send(request)
+                .await?
+                .json()
+                .await
+                .map_err(ResponseError::from)?;
+            let ready = instance.nat_ip().is_some();
+            Ok(ready.then_some(instance))
+        })
+        .await?;
+        Ok(instance.into())
+    }
+}
+
+impl Gcp {
+    async fn send(&self, request: RequestBuilder) -> Result<reqwest::Response> {
+        let token = self.access_token().await?;
+        let response = send_with_retry(request.bearer_auth(token), self.api_attempts).await?;
+        check_response(response).await
+    }
+
+    /// Get an access token for the service account, using the cached token while it's valid
+    async fn access_token(&self) -> Result<String> {
+        if let Some((token, expires)) = self.token.lock().unwrap().as_ref() {
+            if Instant::now() + TOKEN_MARGIN < *expires {
+                return Ok(token.clone());
+            }
+        }
+
+        let now = Utc::now().timestamp();
+        let claims = GcpClaims {
+            iss: &self.key.client_email,
+            scope: SCOPE,
+            aud: &self.key.token_uri,
+            iat: now,
+            exp: now + 3600,
+        };
+        let assertion = encode(&Header::new(Algorithm::RS256), &claims, &self.encoding_key)
+            .map_err(|e| ResponseError::Other(format!("Failed to sign token request: {}", e)))?;
+
+        let response = send_with_retry(
+            self.client.post(&self.key.token_uri).form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ]),
+            self.api_attempts,
+        )
+        .await?;
+        let response = check_response(response).await?;
+        let response: GcpTokenResponse = response.json().await.map_err(ResponseError::from)?;
+
+        *self.token.lock().unwrap() = Some((
+            response.access_token.clone(),
+            Instant::now() + Duration::from_secs(response.expires_in),
+        ));
+        Ok(response.access_token)
+    }
+}
+
+#[derive(Serialize)]
+struct GcpClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct GcpTokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpCreateParams<'a> {
+    name: &'a str,
+    machine_type: String,
+    labels: HashMap<&'a str, &'a str>,
+    disks: Vec<GcpAttachedDisk<'a>>,
+    tags: GcpTags<'a>,
+    network_interfaces: Vec<GcpCreateNetworkInterface<'a>>,
+    metadata: GcpMetadata<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpAttachedDisk<'a> {
+    boot: bool,
+    auto_delete: bool,
+    initialize_params: GcpDiskParams<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpDiskParams<'a> {
+    source_image: &'a str,
+}
+
+/// Network tags, which firewall rules can target
+#[derive(Serialize)]
+struct GcpTags<'a> {
+    items: &'a [String],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpCreateNetworkInterface<'a> {
+    network: &'a str,
+    access_configs: Vec<GcpCreateAccessConfig<'a>>,
+}
+
+#[derive(Serialize)]
+struct GcpCreateAccessConfig<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    kind: &'a str,
+}
+
+#[derive(Serialize)]
+struct GcpMetadata<'a> {
+    items: Vec<GcpMetadataItem<'a>>,
+}
+
+#[derive(Serialize)]
+struct GcpMetadataItem<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct GcpListResponse {
+    /// Omitted when there are no instances
+    #[serde(default)]
+    items: Vec<GcpInstance>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpInstance {
+    name: String,
+    creation_timestamp: DateTime<Utc>,
+    #[serde(default)]
+    network_interfaces: Vec<GcpNetworkInterface>,
+    /// Url of the zone
+    #[serde(default)]
+    zone: String,
+    /// Url of the machine type
+    #[serde(default)]
+    machine_type: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpNetworkInterface {
+    #[serde(default)]
+    access_configs: Vec<GcpAccessConfig>,
+    #[serde(default)]
+    ipv6_access_configs: Vec<GcpIpv6AccessConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GcpAccessConfig {
+    /// Only set once the external ip has been assigned
+    #[serde(rename = "natIP")]
+    nat_ip: Option<IpAddr>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GcpIpv6AccessConfig {
+    external_ipv6: Option<IpAddr>,
+}
+
+impl GcpInstance {
+    fn nat_ip(&self) -> Option<IpAddr> {
+        self.network_interfaces
+            .iter()
+            .flat_map(|interface| &interface.access_configs)
+            .find_map(|config| config.nat_ip)
+    }
+
+    fn external_ipv6(&self) -> Option<IpAddr> {
+        self.network_interfaces
+            .iter()
+            .flat_map(|interface| &interface.ipv6_access_configs)
+            .find_map(|config| config.external_ipv6)
+    }
+}
+
+/// The server label from an instance name, without the timestamp added to make the name unique
+fn instance_label(name: &str) -> &str {
+    match name.rsplit_once('-') {
+        Some((label, suffix))
+            if suffix.len() >= 10 && suffix.bytes().all(|c| c.is_ascii_digit()) =>
+        {
+            label
+        }
+        _ => name,
+    }
+}
+
+/// Last segment of a resource url like `.../zones/europe-west4-a`
+fn resource_name(url: &str) -> &str {
+    url.rsplit('/').next().unwrap_or(url)
+}
+
+impl From<GcpInstance> for Server {
+    fn from(instance: GcpInstance) -> Self {
+        Server {
+            ip: instance
+                .nat_ip()
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            ip_v6: instance.external_ipv6(),
+            private_ip: None,
+            created: instance.creation_timestamp,
+            region: resource_name(&instance.zone).into(),
+            plan: resource_name(&instance.machine_type).into(),
+            label: instance_label(&instance.name).into(),
+            id: instance.name,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_label() {
+        assert_eq!(instance_label("spire-1718000000"), "spire");
+        assert_eq!(
+            instance_label("event-server-1-1718000000"),
+            "event-server-1"
+        );
+        assert_eq!(instance_label("event-server-1"), "event-server-1");
+    }
+}
@@ -0,0 +1,229 @@
+use crate::cloud::{
+    check_response, found, poll_until, send_with_retry, Cloud, CloudError, Created, CreatedAuth,
+    ResponseError, Result, Server, SpawnOptions,
+};
+use crate::http;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use thrussh_keys::PublicKeyBase64;
+use tracing::instrument;
+
+/// StackScript for the "Docker" marketplace app
+const DOCKER_STACKSCRIPT_ID: u32 = 607433;
+
+pub struct Linode {
+    region: String,
+    plan: String,
+    managed_tag: String,
+    token: String,
+    client: Client,
+    api_attempts: u32,
+}
+
+impl Linode {
+    pub fn new(
+        token: String,
+        region: String,
+        plan: String,
+        managed_tag: String,
+        api_attempts: u32,
+    ) -> Self {
+        Linode {
+            token,
+            region,
+            plan,
+            managed_tag,
+            client: http::client(),
+            api_attempts,
+        }
+    }
+}
+
+#[async_trait]
+impl Cloud for Linode {
+    async fn list(&self) -> Result<Vec<Server>> {
+        let response = send_with_retry(
+            self.client
+                .get("https://api.linode.com/v4/linode/instances")
+                .bearer_auth(&self.token),
+            self.api_attempts,
+        )
+        .await?;
+        let response = check_response(response).await?;
+
+        let response: LinodeListResponse = response.json().await.map_err(ResponseError::from)?;
+
+        Ok(response
+            .data
+            .into_iter()
+            .filter(|instance| instance.tags.contains(&self.managed_tag))
+            .map(Server::from)
+            .collect())
+    }
+
+    #[instrument(skip_all, fields(label = options.label))]
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created> {
+        let SpawnOptions {
+            label, ssh_keys, ..
+        } = *options;
+        if options.snapshot.is_some() {
+            return Err(CloudError::Unsupported("booting from a snapshot"));
+        }
+        let startup_key = options.deploy_key();
+
+        // linode takes the public keys directly, no need to register them first
+        let mut authorized_keys = ssh_keys.to_vec();
+        authorized_keys.push(format!(
+            "{} {} {}",
+            startup_key.name(),
+            startup_key.public_key_base64(),
+            "dispenser-deploy"
+        ));
+
+        // a root password is required, even if we only log in using the deploy key
+        let root_pass: String = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        let response = send_with_retry(
+            self.client
+                .post("https://api.linode.com/v4/linode/instances")
+                .bearer_auth(&self.token)
+                .json(&LinodeCreateParams {
+                    region: self.region.as_str(),
+                    ty: options.plan.unwrap_or(&self.plan),
+                    tags: &[&self.managed_tag],
+                    label,
+                    image: "linode/ubuntu22.04",
+                    stackscript_id: DOCKER_STACKSCRIPT_ID,
+                    root_pass,
+                    authorized_keys,
+                }),
+            self.api_attempts,
+        )
+        .await?;
+        let response = check_response(response).await?;
+
+        let response: LinodeInstanceResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        Ok(Created {
+            id: response.id.to_string(),
+            auth: CreatedAuth::Ssh(startup_key),
+        })
+    }
+
+    async fn kill(&self, id: &str) -> Result<()> {
+        let response = send_with_retry(
+            self.client
+                .delete(format!("https://api.linode.com/v4/linode/instances/{}", id))
+                .bearer_auth(&self.token),
+            self.api_attempts,
+        )
+        .await?;
+        check_response(response).await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Server>> {
+        Ok(found(self.get_instance(id).await)?.map(Server::from))
+    }
+
+    async fn wait_for_ip(&self, id: &str, timeout: Duration) -> Result<Server> {
+        let instance = poll_until(timeout, || async {
+            let instance = self.get_instance(id).await?;
+            let ready = instance.public_v4().is_some();
+            Ok(ready.then_some(instance))
+        })
+        .await?;
+        Ok(instance.into())
+    }
+}
+
+impl Linode {
+    async fn get_instance(&self, id: &str) -> Result<LinodeInstanceResponse> {
+        let response = send_with_retry(
+            self.client
+                .get(format!("https://api.linode.com/v4/linode/instances/{}", id))
+                .bearer_auth(&self.token),
+            self.api_attempts,
+        )
+        .await?;
+        let response = check_response(response).await?;
+
+        Ok(response.json().await.map_err(ResponseError::from)?)
+    }
+}
+
+#[derive(Serialize)]
+struct LinodeCreateParams<'a> {
+    label: &'a str,
+    region: &'a str,
+    #[serde(rename = "type")]
+    ty: &'a str,
+    tags: &'a [&'a str],
+    image: &'a str,
+    stackscript_id: u32,
+    root_pass: String,
+    authorized_keys: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LinodeListResponse {
+    data: Vec<LinodeInstanceResponse>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+struct LinodeInstanceResponse {
+    id: u64,
+    label: String,
+    ipv4: Vec<Ipv4Addr>,
+    /// SLAAC address including the prefix length, e.g. "2600:3c03::f03c:91ff:fe24:3a2f/128"
+    ipv6: Option<String>,
+    created: NaiveDateTime,
+    tags: Vec<String>,
+    region: String,
+    #[serde(rename = "type")]
+    plan: String,
+}
+
+impl LinodeInstanceResponse {
+    fn public_v4(&self) -> Option<IpAddr> {
+        self.ipv4
+            .iter()
+            .find(|ip| !ip.is_private())
+            .copied()
+            .map(IpAddr::V4)
+    }
+
+    fn public_v6(&self) -> Option<IpAddr> {
+        let ip = self.ipv6.as_deref()?;
+        let ip = ip.split('/').next().unwrap_or(ip);
+        ip.parse().ok()
+    }
+}
+
+impl From<LinodeInstanceResponse> for Server {
+    fn from(instance: LinodeInstanceResponse) -> Self {
+        Server {
+            id: instance.id.to_string(),
+            created: instance.created.and_utc(),
+            ip: instance
+                .public_v4()
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            ip_v6: instance.public_v6(),
+            private_ip: None,
+            label: instance.label,
+            region: instance.region,
+            plan: instance.plan,
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use crate::cloud::{
+    poll_until, Cloud, CloudError, Created, CreatedAuth, Result, Server, SpawnOptions,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// In-memory cloud for testing, servers are available as soon as they're spawned
+#[derive(Default)]
+pub struct MockCloud {
+    servers: Mutex<Vec<Server>>,
+    spawned: AtomicU8,
+    /// Simulate spawned servers that never get an ip
+    never_ready: bool,
+}
+
+impl MockCloud {
+    pub fn with_servers(servers: Vec<Server>) -> Self {
+        MockCloud {
+            servers: Mutex::new(servers),
+            spawned: AtomicU8::default(),
+            never_ready: false,
+        }
+    }
+
+    pub fn never_ready() -> Self {
+        MockCloud {
+            never_ready: true,
+            ..MockCloud::default()
+        }
+    }
+
+    pub fn servers(&self) -> Vec<Server> {
+        self.servers.lock().unwrap().clone()
+    }
+
+    /// Number of servers spawned trough this cloud
+    pub fn spawn_count(&self) -> u8 {
+        self.spawned.load(Ordering::SeqCst)
+    }
+
+    pub fn server(id: &str) -> Server {
+        Server {
+            id: id.into(),
+            label: id.into(),
+            created: Utc::now(),
+            // loopback so rcon connections get refused without waiting for a timeout
+            ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            ip_v6: None,
+            private_ip: None,
+            region: "mock".into(),
+            plan: "mock".into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Cloud for MockCloud {
+    async fn list(&self) -> Result<Vec<Server>> {
+        Ok(self.servers())
+    }
+
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created> {
+        let index = self.spawned.fetch_add(1, Ordering::SeqCst) + 1;
+        let id = format!("mock-{}", index);
+        let mut server = MockCloud::server(&id);
+        server.label = options.label.into();
+        server.ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, index));
+        self.servers.lock().unwrap().push(server);
+        Ok(Created {
+            id,
+            auth: CreatedAuth::Password("mock".into()),
+        })
+    }
+
+    async fn kill(&self, id: &str) -> Result<()> {
+        let mut servers = self.servers.lock().unwrap();
+        let count = servers.len();
+        servers.retain(|server| server.id != id);
+        if servers.len() == count {
+            Err(CloudError::ServerNotFound)
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn wait_for_ip(&self, id: &str, timeout: Duration) -> Result<Server> {
+        if self.never_ready {
+            return poll_until(timeout, || async { Ok(None) }).await;
+        }
+        self.servers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|server| server.id == id)
+            .cloned()
+            .ok_or(CloudError::ServerNotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_for_ip_timeout() {
+        let cloud = MockCloud::never_ready();
+        let created = cloud
+            .spawn(&SpawnOptions {
+                label: "spire",
+                ssh_keys: &[],
+                snapshot: None,
+                deploy_key: None,
+                plan: None,
+                user_data: None,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            cloud
+                .wait_for_ip(&created.id, Duration::from_millis(1200))
+                .await,
+            Err(CloudError::StartTimeout)
+        ));
+    }
+}
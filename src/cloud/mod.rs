@@ -1,14 +1,28 @@
 use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::net::IpAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use reqwest::StatusCode;
+use rand::{thread_rng, Rng};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{RequestBuilder, Response, StatusCode};
 use thiserror::Error;
 use thrussh_keys::key::KeyPair;
+use tokio::time::sleep;
+use tracing::{debug, warn};
 
 pub mod digitalocean;
+pub mod dry_run;
+pub mod ec2;
+pub mod gcp;
+pub mod linode;
+#[cfg(test)]
+pub mod mock;
+pub mod ovh;
+pub mod scaleway;
 pub mod vultr;
 
 #[derive(Debug, Error)]
@@ -23,11 +37,17 @@ pub enum CloudError {
     InvalidResponse(#[from] ResponseError),
     #[error("Server boot timed out")]
     StartTimeout,
+    #[error("Operation not supported by this provider: {0}")]
+    Unsupported(&'static str),
+    #[error("Invalid provider configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Error from cloud provider ({status}): {message}")]
+    ApiError { status: StatusCode, message: String },
 }
 
 /// Intentionally opaque error
 #[derive(Debug, Error)]
-#[error("{0}")]
+#[error("{}", crate::http::describe_error(.0))]
 pub struct NetworkError(reqwest::Error);
 
 impl CloudError {
@@ -39,6 +59,65 @@ impl CloudError {
     }
 }
 
+/// Turn error responses into a `CloudError`, including the error message reported by the provider
+async fn check_response(response: Response) -> Result<Response> {
+    let status = response.status();
+    CloudError::from_status_code(status)?;
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.map_err(NetworkError::from)?;
+    Err(CloudError::ApiError {
+        status,
+        message: error_message(&body).unwrap_or(body),
+    })
+}
+
+/// Turn a not found error from looking up a server into `None`
+fn found<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(CloudError::ServerNotFound) => Ok(None),
+        Err(CloudError::ApiError {
+            status: StatusCode::NOT_FOUND,
+            ..
+        }) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Extract the error message from the json body of an error response
+///
+/// Digitalocean uses `{"message": ".."}`, vultr `{"error": ".."}`, gcp `{"error": {"message": ".."}}`
+/// and linode `{"errors": [{"reason": ".."}]}`
+fn error_message(body: &str) -> Option<String> {
+    let body: serde_json::Value = serde_json::from_str(body).ok()?;
+    if let Some(message) = body
+        .get("message")
+        .or_else(|| body.get("error"))
+        .and_then(|message| {
+            message
+                .as_str()
+                .or_else(|| message.get("message")?.as_str())
+        })
+    {
+        return Some(message.into());
+    }
+    let messages: Vec<String> = body
+        .get("errors")?
+        .as_array()?
+        .iter()
+        .filter_map(|error| {
+            let reason = error.get("reason")?.as_str()?;
+            Some(match error.get("field").and_then(|field| field.as_str()) {
+                Some(field) => format!("{}: {}", field, reason),
+                None => reason.into(),
+            })
+        })
+        .collect();
+    (!messages.is_empty()).then(|| messages.join(", "))
+}
+
 /// Intentionally opaque error
 #[derive(Debug, Error)]
 pub enum ResponseError {
@@ -62,24 +141,295 @@ impl From<reqwest::Error> for ResponseError {
 
 pub type Result<T, E = CloudError> = std::result::Result<T, E>;
 
+/// Send a request, retrying transient failures (5xx and 429 responses) with exponential backoff
+///
+/// The response of the last attempt is returned if all attempts fail
+async fn send_with_retry(request: RequestBuilder, attempts: u32) -> Result<Response> {
+    let mut delay = Duration::from_secs(1);
+    let mut tries = 0;
+    loop {
+        tries += 1;
+        let attempt = request
+            .try_clone()
+            .expect("streaming request bodies are not used");
+        let response = attempt.send().await.map_err(NetworkError::from)?;
+        let status = response.status();
+        let retryable = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+        if !retryable || tries >= attempts {
+            return Ok(response);
+        }
+
+        let wait = retry_after(&response).unwrap_or(delay);
+        warn!(
+            tries = tries,
+            status = status.as_u16(),
+            "transient error from cloud provider, retrying in {}s",
+            wait.as_secs()
+        );
+        sleep(wait).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Start spacing out requests once fewer than this many requests are left in the rate limit window
+const RATE_LIMIT_LOW: u64 = 20;
+const MAX_RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+
+/// Rate limit state as reported by the `RateLimit-*` or `X-RateLimit-*` response headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitState {
+    pub limit: Option<u64>,
+    pub remaining: u64,
+    /// Unix timestamp at which the limit resets
+    pub reset: Option<u64>,
+}
+
+impl RateLimitState {
+    fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .or_else(|| headers.get(format!("x-{}", name)))?
+                .to_str()
+                .ok()?
+                .trim()
+                .parse::<u64>()
+                .ok()
+        };
+        Some(RateLimitState {
+            limit: header("ratelimit-limit"),
+            remaining: header("ratelimit-remaining")?,
+            reset: header("ratelimit-reset"),
+        })
+    }
+
+    /// Delay to spread the remaining requests over the time until the limit resets, if few requests are left
+    fn delay(&self, now: u64) -> Option<Duration> {
+        if self.remaining >= RATE_LIMIT_LOW {
+            return None;
+        }
+        let until_reset = self.reset?.checked_sub(now).filter(|&secs| secs > 0)?;
+        let delay = Duration::from_secs(until_reset) / (self.remaining.max(1) as u32);
+        Some(delay.min(MAX_RATE_LIMIT_DELAY))
+    }
+}
+
+/// Tracks the rate limit reported by a provider and delays requests when the limit is almost reached
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    state: Mutex<Option<RateLimitState>>,
+}
+
+impl RateLimit {
+    /// The last rate limit state reported by the provider
+    pub fn state(&self) -> Option<RateLimitState> {
+        *self.state.lock().unwrap()
+    }
+
+    /// Send a request with `send_with_retry`, waiting first if the rate limit is almost exhausted
+    async fn send(&self, request: RequestBuilder, attempts: u32) -> Result<Response> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if let Some(state) = self.state() {
+            if let Some(delay) = state.delay(now) {
+                debug!(
+                    remaining = state.remaining,
+                    limit = state.limit,
+                    "close to the rate limit of the cloud provider, waiting {}ms",
+                    delay.as_millis()
+                );
+                sleep(delay).await;
+            }
+        }
+        let response = send_with_retry(request, attempts).await?;
+        if let Some(state) = RateLimitState::from_headers(response.headers()) {
+            *self.state.lock().unwrap() = Some(state);
+        }
+        Ok(response)
+    }
+}
+
+const INITIAL_POLL_DELAY: Duration = Duration::from_millis(500);
+const MAX_POLL_DELAY: Duration = Duration::from_secs(10);
+
+/// Poll until a value is available, with capped exponential backoff and jitter between attempts
+///
+/// Returns `CloudError::StartTimeout` if no value is available before the timeout
+async fn poll_until<T, F, Fut>(timeout: Duration, mut poll: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Option<T>>>,
+{
+    let deadline = Instant::now() + timeout;
+    let mut delay = INITIAL_POLL_DELAY;
+    loop {
+        if let Some(value) = poll().await? {
+            return Ok(value);
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(CloudError::StartTimeout);
+        }
+        // sleep somewhere between half and the full delay, so multiple pollers don't line up
+        let jittered = delay.mul_f64(thread_rng().gen_range(0.5..=1.0));
+        sleep(jittered.min(remaining)).await;
+        delay = (delay * 2).min(MAX_POLL_DELAY);
+    }
+}
+
+/// Fetch every page of a paginated list
+///
+/// `fetch` is called with the cursor for the next page, starting with `None` for the first page,
+/// and returns the items of the page with the cursor of the page after it
+async fn fetch_all_pages<T, F, Fut>(mut fetch: F) -> Result<Vec<T>>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = Result<(Vec<T>, Option<String>)>>,
+{
+    let mut items = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (page, next) = fetch(cursor.take()).await?;
+        items.extend(page);
+        match next {
+            Some(next) => cursor = Some(next),
+            None => return Ok(items),
+        }
+    }
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let seconds = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    Some(Duration::from_secs(seconds.trim().parse().ok()?))
+}
+
 #[async_trait]
 pub trait Cloud: Send + Sync + 'static {
     /// List all running servers on this cloud
     async fn list(&self) -> Result<Vec<Server>>;
+    /// Look up a single server, returns `None` if the server doesn't exist (anymore)
+    ///
+    /// By default this searches the list of running servers
+    async fn get(&self, id: &str) -> Result<Option<Server>> {
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .find(|server| server.id == id))
+    }
     /// Create a new server with the given parameter
-    async fn spawn(&self, ssh_keys: &[String]) -> Result<Created>;
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created>;
     /// Destroy a given server
     async fn kill(&self, id: &str) -> Result<()>;
-    /// Wait until the server has an ip
-    async fn wait_for_ip(&self, id: &str) -> Result<Server>;
+    /// Wait until the server has an ip, giving up after the timeout
+    async fn wait_for_ip(&self, id: &str, timeout: Duration) -> Result<Server>;
+    /// Change the plan of a running server, this can restart the server
+    async fn resize(&self, _id: &str, _plan: &str) -> Result<()> {
+        Err(CloudError::Unsupported("resize"))
+    }
+    /// Hard reboot a server
+    async fn reboot(&self, _id: &str) -> Result<()> {
+        Err(CloudError::Unsupported("reboot"))
+    }
+    /// Assign a reserved ip to a server, so the server is reachable on a fixed address
+    async fn attach_ip(&self, _id: &str, _ip: IpAddr) -> Result<()> {
+        Err(CloudError::Unsupported("reserved ips"))
+    }
+    /// Remove a reserved ip from the server it's assigned to
+    async fn detach_ip(&self, _ip: IpAddr) -> Result<()> {
+        Err(CloudError::Unsupported("reserved ips"))
+    }
+    /// Create a snapshot of a server that new servers can be booted from, returns the id of the snapshot
+    async fn snapshot(&self, _id: &str, _name: &str) -> Result<String> {
+        Err(CloudError::Unsupported("snapshot"))
+    }
+    /// The hourly price of a plan, if the provider reports it
+    async fn hourly_price(&self, _plan: &str) -> Result<Option<f64>> {
+        Ok(None)
+    }
+    /// The remaining prepaid credit on the account, if the provider reports it
+    async fn account_balance(&self) -> Result<Option<f64>> {
+        Ok(None)
+    }
+    /// Check that the credentials and configured options are accepted by the provider, without creating any servers
+    ///
+    /// By default this only verifies that the servers can be listed
+    async fn validate(&self) -> Result<()> {
+        self.list().await?;
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
+/// Parameters for creating a new server
+#[derive(Debug, Clone, Copy)]
+pub struct SpawnOptions<'a> {
+    pub label: &'a str,
+    pub ssh_keys: &'a [String],
+    /// Snapshot to boot the server from instead of the provider's docker image
+    pub snapshot: Option<&'a str>,
+    /// Key to use for setting up the server, a new key is generated if not set
+    pub deploy_key: Option<&'a Arc<KeyPair>>,
+    /// Plan to create the server with instead of the configured default plan
+    pub plan: Option<&'a str>,
+    /// Script to run on first boot trough cloud-init, only supported for vultr and digitalocean
+    pub user_data: Option<&'a str>,
+}
+
+impl SpawnOptions<'_> {
+    /// The configured deploy key, or a newly generated one
+    pub fn deploy_key(&self) -> Arc<KeyPair> {
+        match self.deploy_key {
+            Some(key) => key.clone(),
+            None => Arc::new(KeyPair::generate_ed25519().unwrap()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Server {
     pub id: String,
+    pub label: String,
     pub created: DateTime<Utc>,
     pub ip: IpAddr,
     pub ip_v6: Option<IpAddr>,
+    /// Address of the server in the private network, if it's attached to one
+    pub private_ip: Option<IpAddr>,
+    /// Region or zone the server is running in
+    pub region: String,
+    /// Plan, size or instance type of the server
+    pub plan: String,
+}
+
+impl Server {
+    /// Address to reach the server on, the ipv6 address for ipv6-only servers
+    pub fn reachable_ip(&self) -> IpAddr {
+        match self.ip_v6 {
+            Some(ip_v6) if self.ip.is_unspecified() => ip_v6,
+            _ => self.ip,
+        }
+    }
+
+    /// Whether the server has been assigned a public ipv4 or ipv6 address
+    pub fn has_ip(&self) -> bool {
+        !self.ip.is_unspecified() || self.ip_v6.is_some()
+    }
+
+    /// How long the server has been running
+    pub fn lifetime(&self) -> chrono::Duration {
+        (Utc::now() - self.created).max(chrono::Duration::zero())
+    }
+
+    /// Estimated cost of the server since it was created, given the hourly price of its plan
+    pub fn accrued_cost(&self, hourly_price: f64) -> f64 {
+        self.lifetime().num_seconds() as f64 / 3600.0 * hourly_price
+    }
 }
 
 #[derive(Debug)]
@@ -92,12 +442,14 @@ pub struct Created {
 pub enum CreatedAuth {
     Password(String),
     Ssh(Arc<KeyPair>),
+    /// Authenticate with the key, falling back to the password if the key is rejected
+    PasswordOrKey(String, Arc<KeyPair>),
 }
 
 impl Display for CreatedAuth {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            CreatedAuth::Password(s) => s.fmt(f),
+            CreatedAuth::Password(s) | CreatedAuth::PasswordOrKey(s, _) => s.fmt(f),
             CreatedAuth::Ssh(_) => write!(f, "public key only"),
         }
     }
@@ -110,3 +462,52 @@ fn key_cmp(a: &str, b: &str) -> bool {
     // compare the first 2 space-seperated parts
     a_parts.next() == b_parts.next() && a_parts.next() == b_parts.next()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_rate_limit_delay() {
+        let mut headers = HeaderMap::new();
+        headers.insert("ratelimit-limit", HeaderValue::from_static("5000"));
+        headers.insert("ratelimit-remaining", HeaderValue::from_static("10"));
+        headers.insert("ratelimit-reset", HeaderValue::from_static("1100"));
+        let state = RateLimitState::from_headers(&headers).unwrap();
+        assert_eq!(
+            state,
+            RateLimitState {
+                limit: Some(5000),
+                remaining: 10,
+                reset: Some(1100),
+            }
+        );
+        assert_eq!(state.delay(1000), Some(Duration::from_secs(10)));
+        assert_eq!(state.delay(1200), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_static("100"));
+        let state = RateLimitState::from_headers(&headers).unwrap();
+        assert_eq!(state.remaining, 100);
+        assert_eq!(state.delay(1000), None);
+
+        assert_eq!(RateLimitState::from_headers(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_accrued_cost() {
+        let server = Server {
+            id: "1234".into(),
+            label: "spire".into(),
+            created: Utc::now() - chrono::Duration::minutes(90),
+            ip: "10.0.0.1".parse().unwrap(),
+            ip_v6: None,
+            private_ip: None,
+            region: "ams".into(),
+            plan: "vc2-1c-2gb".into(),
+        };
+        assert_eq!(server.lifetime().num_minutes(), 90);
+        assert!((server.accrued_cost(0.02) - 0.03).abs() < 0.001);
+    }
+}
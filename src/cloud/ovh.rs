@@ -0,0 +1,378 @@
+use crate::cloud::{
+    check_response, poll_until, send_with_retry, Cloud, CloudError, Created, CreatedAuth,
+    ResponseError, Result, Server, SpawnOptions,
+};
+use crate::http;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::{Client, Method, Response};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use thrussh_keys::PublicKeyBase64;
+use tracing::{instrument, warn};
+
+/// Cloud-init config to allow logging in as root and to install docker
+const USER_DATA: &str = "#cloud-config
+disable_root: false
+package_update: true
+packages:
+  - docker.io
+runcmd:
+  - systemctl enable --now docker
+";
+
+pub struct Ovh {
+    signer: OvhSigner,
+    project_id: String,
+    region: String,
+    flavor: String,
+    image: String,
+    /// OVH public cloud instances don't support tags, so the servers are recognized by their name prefix
+    name_prefix: String,
+    client: Client,
+    api_attempts: u32,
+}
+
+/// Credentials for the ovh api
+pub struct OvhCredentials {
+    pub endpoint: String,
+    pub application_key: String,
+    pub application_secret: String,
+    pub consumer_key: String,
+}
+
+/// Instance options for ovh
+pub struct OvhOptions {
+    pub project_id: String,
+    pub region: String,
+    pub flavor: String,
+    pub image: String,
+    /// Prefix for the instance names used to recognize managed instances
+    pub managed_tag: String,
+}
+
+impl Ovh {
+    pub fn new(credentials: OvhCredentials, options: OvhOptions, api_attempts: u32) -> Self {
+        Ovh {
+            signer: OvhSigner {
+                endpoint: credentials.endpoint.trim_end_matches('/').into(),
+                application_key: credentials.application_key,
+                application_secret: credentials.application_secret,
+                consumer_key: credentials.consumer_key,
+            },
+            project_id: options.project_id,
+            region: options.region,
+            flavor: options.flavor,
+            image: options.image,
+            name_prefix: format!("{}-", options.managed_tag),
+            client: http::client(),
+            api_attempts,
+        }
+    }
+}
+
+#[async_trait]
+impl Cloud for Ovh {
+    async fn list(&self) -> Result<Vec<Server>> {
+        let instances: Vec<OvhInstance> = self
+            .get(&format!(
+                "/cloud/project/{}/instance?region={}",
+                self.project_id, self.region
+            ))
+            .await?;
+
+        Ok(instances
+            .into_iter()
+            .filter(|instance| instance.name.starts_with(&self.name_prefix))
+            .map(|instance| self.server(instance))
+            .collect())
+    }
+
+    #[instrument(skip_all, fields(label = options.label))]
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created> {
+        let SpawnOptions {
+            label, ssh_keys, ..
+        } = *options;
+        if options.snapshot.is_some() {
+            return Err(CloudError::Unsupported("booting from a snapshot"));
+        }
+        let flavor_id = self
+            .find_id("flavor", options.plan.unwrap_or(&self.flavor))
+            .await?;
+        let image_id = self.find_id("image", &self.image).await?;
+
+        let startup_key = options.deploy_key();
+        let startup_key_response: OvhSshKey = self
+            .send(
+                Method::POST,
+                &format!("/cloud/project/{}/sshkey", self.project_id),
+                Some(&OvhCreateSshKeyParams {
+                    name: &format!("dispenser-deploy-{}-{}", label, Utc::now().timestamp()),
+                    public_key: &format!(
+                        "{} {} {}",
+                        startup_key.name(),
+                        startup_key.public_key_base64(),
+                        "dispenser-deploy"
+                    ),
+                    region: &self.region,
+                }),
+            )
+            .await?
+            .json()
+            .await
+            .map_err(ResponseError::from)?;
+
+        // only a single key can be passed on creation, add the configured keys trough cloud-init
+        let mut user_data = String::from(USER_DATA);
+        if !ssh_keys.is_empty() {
+            user_data.push_str("ssh_authorized_keys:\n");
+            for key in ssh_keys {
+                user_data.push_str(&format!("  - {}\n", key));
+            }
+        }
+
+        let response_res = self
+            .send(
+                Method::POST,
+                &format!("/cloud/project/{}/instance", self.project_id),
+                Some(&OvhCreateParams {
+                    name: &format!("{}{}", self.name_prefix, label),
+                    flavor_id: &flavor_id,
+                    image_id: &image_id,
+                    region: &self.region,
+                    ssh_key_id: &startup_key_response.id,
+                    user_data: &user_data,
+                }),
+            )
+            .await;
+
+        // remove the deploy key, even if the spawn request failed
+        // a leftover key shouldn't cause the created instance to be lost
+        if let Err(e) = self
+            .send(
+                Method::DELETE,
+                &format!(
+                    "/cloud/project/{}/sshkey/{}",
+                    self.project_id, startup_key_response.id
+                ),
+                None::<&()>,
+            )
+            .await
+        {
+            warn!(error = %e, "failed to remove deploy key");
+        }
+
+        let instance: OvhInstance = response_res?.json().await.map_err(ResponseError::from)?;
+
+        Ok(Created {
+            id: instance.id,
+            auth: CreatedAuth::Ssh(startup_key),
+        })
+    }
+
+    async fn kill(&self, id: &str) -> Result<()> {
+        self.send(
+            Method::DELETE,
+            &format!("/cloud/project/{}/instance/{}", self.project_id, id),
+            None::<&()>,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn wait_for_ip(&self, id: &str, timeout: Duration) -> Result<Server> {
+        let instance = poll_until(timeout, || async {
+            let instance: OvhInstance = self
+                .get(&format!(
+                    "/cloud/project/{}/instance/{}",
+                    self.project_id, id
+                ))
+                .await?;
+            let ready = instance.public_v4().is_some();
+            Ok(ready.then_some(instance))
+        })
+        .await?;
+        Ok(self.server(instance))
+    }
+}
+
+impl Ovh {
+    fn server(&self, instance: OvhInstance) -> Server {
+        Server {
+            ip: instance
+                .public_v4()
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            ip_v6: instance.public_v6(),
+            private_ip: None,
+            created: instance.created,
+            region: instance.region,
+            plan: instance.flavor_id,
+            label: instance
+                .name
+                .strip_prefix(&self.name_prefix)
+                .unwrap_or(&instance.name)
+                .into(),
+            id: instance.id,
+        }
+    }
+
+    /// Find the id of a flavor or image by name
+    async fn find_id(&self, kind: &str, name: &str) -> Result<String> {
+        let items: Vec<OvhNamed> = self
+            .get(&format!(
+                "/cloud/project/{}/{}?region={}",
+                self.project_id, kind, self.region
+            ))
+            .await?;
+        Ok(items
+            .into_iter()
+            .find_map(|item| (item.name == name).then_some(item.id))
+            .ok_or_else(|| {
+                ResponseError::Other(format!(
+                    "{} \"{}\" not found in region {}",
+                    kind, name, self.region
+                ))
+            })?)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        Ok(self
+            .send(Method::GET, path, None::<&()>)
+            .await?
+            .json()
+            .await
+            .map_err(ResponseError::from)?)
+    }
+
+    async fn send<B: Serialize>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&B>,
+    ) -> Result<Response> {
+        let body = match body {
+            Some(body) => {
+                serde_json::to_string(body).map_err(|e| ResponseError::Other(e.to_string()))?
+            }
+            None => String::new(),
+        };
+        let url = format!("{}{}", self.signer.endpoint, path);
+        let mut request = self.client.request(method.clone(), &url);
+        if !body.is_empty() {
+            request = request.header("content-type", "application/json");
+        }
+        for (name, value) in self.signer.headers(&method, &url, &body, Utc::now()) {
+            request = request.header(name, value);
+        }
+        let response = send_with_retry(request.body(body), self.api_attempts).await?;
+        check_response(response).await
+    }
+}
+
+/// Signs requests using ovh's application key scheme
+///
+/// See https://help.ovhcloud.com/csm/en-api-getting-started-ovhcloud-api
+struct OvhSigner {
+    endpoint: String,
+    application_key: String,
+    application_secret: String,
+    consumer_key: String,
+}
+
+impl OvhSigner {
+    fn headers(
+        &self,
+        method: &Method,
+        url: &str,
+        body: &str,
+        now: DateTime<Utc>,
+    ) -> [(&'static str, String); 4] {
+        let timestamp = now.timestamp().to_string();
+        let signature = Sha1::digest(
+            format!(
+                "{}+{}+{}+{}+{}+{}",
+                self.application_secret, self.consumer_key, method, url, body, timestamp
+            )
+            .as_bytes(),
+        );
+        [
+            ("x-ovh-application", self.application_key.clone()),
+            ("x-ovh-consumer", self.consumer_key.clone()),
+            ("x-ovh-timestamp", timestamp),
+            ("x-ovh-signature", format!("$1${}", hex::encode(signature))),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OvhCreateParams<'a> {
+    name: &'a str,
+    flavor_id: &'a str,
+    image_id: &'a str,
+    region: &'a str,
+    ssh_key_id: &'a str,
+    user_data: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OvhCreateSshKeyParams<'a> {
+    name: &'a str,
+    public_key: &'a str,
+    region: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct OvhSshKey {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OvhNamed {
+    id: String,
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OvhInstance {
+    id: String,
+    name: String,
+    created: DateTime<Utc>,
+    #[serde(default)]
+    ip_addresses: Vec<OvhIpAddress>,
+    #[serde(default)]
+    region: String,
+    /// Id of the flavor, the name isn't included in the instance
+    #[serde(default)]
+    flavor_id: String,
+}
+
+impl OvhInstance {
+    fn public(&self, version: u8) -> Option<IpAddr> {
+        self.ip_addresses
+            .iter()
+            .find(|address| address.kind == "public" && address.version == version)
+            .map(|address| address.ip)
+    }
+
+    fn public_v4(&self) -> Option<IpAddr> {
+        self.public(4)
+    }
+
+    fn public_v6(&self) -> Option<IpAddr> {
+        self.public(6)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OvhIpAddress {
+    ip: IpAddr,
+    #[serde(rename = "type")]
+    kind: String,
+    version: u8,
+}
@@ -0,0 +1,289 @@
+use crate::cloud::{
+    check_response, poll_until, send_with_retry, Cloud, CloudError, Created, CreatedAuth,
+    ResponseError, Result, Server, SpawnOptions,
+};
+use crate::http;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::{Client, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use thrussh_keys::PublicKeyBase64;
+use tracing::instrument;
+
+/// Cloud-init config to allow logging in as root and to install docker
+const USER_DATA: &str = "#cloud-config
+disable_root: false
+package_update: true
+packages:
+  - docker.io
+runcmd:
+  - systemctl enable --now docker
+";
+
+pub struct Scaleway {
+    secret_key: String,
+    project_id: String,
+    zone: String,
+    commercial_type: String,
+    image: String,
+    managed_tag: String,
+    client: Client,
+    api_attempts: u32,
+}
+
+/// Instance options for scaleway
+pub struct ScalewayOptions {
+    pub project_id: String,
+    pub zone: String,
+    pub commercial_type: String,
+    pub image: String,
+    pub managed_tag: String,
+}
+
+impl Scaleway {
+    pub fn new(secret_key: String, options: ScalewayOptions, api_attempts: u32) -> Self {
+        Scaleway {
+            secret_key,
+            project_id: options.project_id,
+            zone: options.zone,
+            commercial_type: options.commercial_type,
+            image: options.image,
+            managed_tag: options.managed_tag,
+            client: http::client(),
+            api_attempts,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!(
+            "https://api.scaleway.com/instance/v1/zones/{}/servers{}",
+            self.zone, path
+        )
+    }
+
+    fn volume_url(&self, id: &str) -> String {
+        format!(
+            "https://api.scaleway.com/instance/v1/zones/{}/volumes/{}",
+            self.zone, id
+        )
+    }
+}
+
+#[async_trait]
+impl Cloud for Scaleway {
+    async fn list(&self) -> Result<Vec<Server>> {
+        let response = self
+            .send(
+                self.client
+                    .get(self.url(""))
+                    .query(&[("tags", &self.managed_tag)]),
+            )
+            .await?;
+        let response: ScalewayListResponse = response.json().await.map_err(ResponseError::from)?;
+
+        Ok(response
+            .servers
+            .into_iter()
+            .filter(|server| server.tags.contains(&self.managed_tag))
+            .map(Server::from)
+            .collect())
+    }
+
+    #[instrument(skip_all, fields(label = options.label))]
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created> {
+        let SpawnOptions {
+            label, ssh_keys, ..
+        } = *options;
+        if options.snapshot.is_some() {
+            return Err(CloudError::Unsupported("booting from a snapshot"));
+        }
+        let startup_key = options.deploy_key();
+
+        let response = self
+            .send(self.client.post(self.url("")).json(&ScalewayCreateParams {
+                name: label,
+                project: &self.project_id,
+                commercial_type: options.plan.unwrap_or(&self.commercial_type),
+                image: &self.image,
+                tags: &[&self.managed_tag],
+                dynamic_ip_required: true,
+            }))
+            .await?;
+        let response: ScalewayServerResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        let id = response.server.id;
+
+        // the keys are added trough cloud-init, which needs to be set before the server is powered on
+        let mut user_data = String::from(USER_DATA);
+        user_data.push_str("ssh_authorized_keys:\n");
+        for key in ssh_keys {
+            user_data.push_str(&format!("  - {}\n", key));
+        }
+        user_data.push_str(&format!(
+            "  - {} {} dispenser-deploy\n",
+            startup_key.name(),
+            startup_key.public_key_base64()
+        ));
+
+        let powered_on = async {
+            self.send(
+                self.client
+                    .patch(self.url(&format!("/{}/user_data/cloud-init", id)))
+                    .header("content-type", "text/plain")
+                    .body(user_data),
+            )
+            .await?;
+            self.action(&id, "poweron").await
+        }
+        .await;
+
+        // don't leave a stopped server behind when it can't be started
+        if let Err(e) = powered_on {
+            self.kill(&id).await?;
+            return Err(e);
+        }
+
+        Ok(Created {
+            id,
+            auth: CreatedAuth::Ssh(startup_key),
+        })
+    }
+
+    /// Terminating a server also deletes its volumes and ip, but is only possible for running servers
+    async fn kill(&self, id: &str) -> Result<()> {
+        let server = self.get_server(id).await?;
+        if server.state == "stopped" {
+            self.delete_stopped(server).await
+        } else {
+            self.action(id, "terminate").await
+        }
+    }
+
+    async fn wait_for_ip(&self, id: &str, timeout: Duration) -> Result<Server> {
+        let server = poll_until(timeout, || async {
+            let server = self.get_server(id).await?;
+            let ready = server.public_ip.is_some();
+            Ok(ready.then_some(server))
+        })
+        .await?;
+        Ok(server.into())
+    }
+}
+
+impl Scaleway {
+    async fn get_server(&self, id: &str) -> Result<ScalewayServer> {
+        let response = self
+            .send(self.client.get(self.url(&format!("/{}", id))))
+            .await?;
+        let response: ScalewayServerResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        Ok(response.server)
+    }
+
+    /// Delete a server that was never powered on, the volumes are left behind by deleting the server
+    async fn delete_stopped(&self, server: ScalewayServer) -> Result<()> {
+        self.send(self.client.delete(self.url(&format!("/{}", server.id))))
+            .await?;
+        for volume in server.volumes.values() {
+            self.send(self.client.delete(self.volume_url(&volume.id)))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn action(&self, id: &str, action: &str) -> Result<()> {
+        self.send(
+            self.client
+                .post(self.url(&format!("/{}/action", id)))
+                .json(&ScalewayActionParams { action }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        let response = send_with_retry(
+            request.header("x-auth-token", &self.secret_key),
+            self.api_attempts,
+        )
+        .await?;
+        check_response(response).await
+    }
+}
+
+#[derive(Serialize)]
+struct ScalewayCreateParams<'a> {
+    name: &'a str,
+    project: &'a str,
+    commercial_type: &'a str,
+    /// Image id or label, for example "ubuntu_jammy"
+    image: &'a str,
+    tags: &'a [&'a str],
+    dynamic_ip_required: bool,
+}
+
+#[derive(Serialize)]
+struct ScalewayActionParams<'a> {
+    action: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScalewayListResponse {
+    servers: Vec<ScalewayServer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScalewayServerResponse {
+    server: ScalewayServer,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScalewayServer {
+    id: String,
+    name: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    creation_date: DateTime<Utc>,
+    public_ip: Option<ScalewayIp>,
+    ipv6: Option<ScalewayIp>,
+    #[serde(default)]
+    commercial_type: String,
+    #[serde(default)]
+    zone: String,
+    #[serde(default)]
+    state: String,
+    /// Volumes keyed by their index
+    #[serde(default)]
+    volumes: HashMap<String, ScalewayVolume>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScalewayVolume {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScalewayIp {
+    address: IpAddr,
+}
+
+impl From<ScalewayServer> for Server {
+    fn from(server: ScalewayServer) -> Self {
+        Server {
+            id: server.id,
+            label: server.name,
+            created: server.creation_date,
+            ip: server
+                .public_ip
+                .map(|ip| ip.address)
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            ip_v6: server.ipv6.map(|ip| ip.address),
+            private_ip: None,
+            region: server.zone,
+            plan: server.commercial_type,
+        }
+    }
+}
@@ -1,31 +1,64 @@
 use crate::cloud::{
-    key_cmp, Cloud, CloudError, Created, CreatedAuth, NetworkError, ResponseError, Result, Server,
+    check_response, fetch_all_pages, found, key_cmp, poll_until, Cloud, CloudError, Created,
+    CreatedAuth, RateLimit, ResponseError, Result, Server, SpawnOptions,
 };
+use crate::http;
 use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
 use chrono::{DateTime, Utc};
 use futures_util::stream::FuturesUnordered;
 use futures_util::TryStreamExt;
-use petname::petname;
 use reqwest::Client;
+use serde::de::Error as _;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::time::sleep;
+use thrussh_keys::key::KeyPair;
+use thrussh_keys::PublicKeyBase64;
+use tracing::{debug, info, instrument, warn};
+
+/// Maximum time to wait for a snapshot to complete
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
 
 pub struct Vultr {
-    region: String,
+    /// Regions to try in order when spawning a server
+    regions: Vec<String>,
     plan: String,
+    managed_tag: String,
     token: String,
     client: Client,
+    api_attempts: u32,
+    rate_limit: RateLimit,
+    /// Short name of the marketplace application to create servers from
+    application: String,
+    /// Image id of the application, cached after the first lookup
+    app_image_id: Mutex<Option<String>>,
+    /// Id of the vpc to attach new servers to
+    network_id: Option<String>,
 }
 
 impl Vultr {
-    pub fn new(token: String, region: String, plan: String) -> Self {
+    pub fn new(
+        token: String,
+        regions: Vec<String>,
+        plan: String,
+        managed_tag: String,
+        application: String,
+        network_id: Option<String>,
+        api_attempts: u32,
+    ) -> Self {
         Vultr {
             token,
-            region,
+            regions,
             plan,
-            client: Client::default(),
+            managed_tag,
+            client: http::client(),
+            api_attempts,
+            rate_limit: RateLimit::default(),
+            application,
+            app_image_id: Mutex::default(),
+            network_id,
         }
     }
 }
@@ -33,134 +66,471 @@ impl Vultr {
 #[async_trait]
 impl Cloud for Vultr {
     async fn list(&self) -> Result<Vec<Server>> {
-        let response = self
-            .client
-            .get("https://api.vultr.com/v2/instances")
-            .bearer_auth(&self.token)
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())?;
+        let instances = fetch_all_pages(|cursor| self.list_page(cursor)).await?;
 
-        let response: VultrListResponse = response.json().await.map_err(ResponseError::from)?;
-
-        Ok(response
-            .instances
+        Ok(instances
             .into_iter()
-            .filter(|instance| instance.tag == "spire")
+            .filter(|instance| instance.tag == self.managed_tag)
             .map(Server::from)
             .collect())
     }
 
-    async fn spawn(&self, ssh_keys: &[String]) -> Result<Created> {
-        let key_ids = ssh_keys
+    #[instrument(skip_all, fields(label = options.label))]
+    async fn spawn(&self, options: &SpawnOptions<'_>) -> Result<Created> {
+        let SpawnOptions { ssh_keys, .. } = *options;
+        let startup_key = options.deploy_key();
+        let startup_key_id = self
+            .create_key(
+                "Dispenser Deploy Key",
+                &format!(
+                    "{} {} {}",
+                    startup_key.name(),
+                    startup_key.public_key_base64(),
+                    "dispenser-deploy"
+                ),
+            )
+            .await?;
+
+        let mut key_ids = ssh_keys
             .iter()
             .map(|key| self.get_ssh_key_id(key))
             .collect::<FuturesUnordered<_>>()
             .try_collect::<Vec<String>>()
             .await?;
+        key_ids.push(startup_key_id.clone());
+
+        let mut response_res = Err(CloudError::InvalidConfig("No regions configured".into()));
+        for region in &self.regions {
+            response_res = match options.snapshot {
+                Some(snapshot) => {
+                    self.send_create(
+                        region,
+                        options,
+                        VultrImage::Snapshot(snapshot.into()),
+                        key_ids.clone(),
+                    )
+                    .await
+                }
+                None => self.create_instance(region, options, key_ids.clone()).await,
+            };
+            match &response_res {
+                Ok(_) => {
+                    info!(region = region.as_str(), "created instance");
+                    break;
+                }
+                Err(e) => warn!(
+                    region = region.as_str(),
+                    error = %e,
+                    "failed to create instance in region"
+                ),
+            }
+        }
 
+        // remove the deploy key, even if the spawn request failed
+        // a leftover key shouldn't cause the created instance to be lost
+        if let Err(e) = self.remove_key(&startup_key_id).await {
+            warn!(error = %e, "failed to remove deploy key");
+        }
+
+        let response = response_res?;
+        Ok((response.instance, startup_key).into())
+    }
+
+    async fn kill(&self, id: &str) -> Result<()> {
         let response = self
-            .client
-            .post("https://api.vultr.com/v2/instances")
-            .bearer_auth(&self.token)
-            .json(&VultrCreateParams {
-                region: self.region.as_str(),
-                plan: self.plan.as_str(),
-                tag: "spire",
-                label: petname(2, "-").expect("failed to generate name"),
-                image_id: self.get_app_image_id("docker").await?,
-                sshkey_id: key_ids,
-                enable_ipv6: true,
-            })
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())?;
+            .rate_limit
+            .send(
+                self.client
+                    .delete(format!("https://api.vultr.com/v2/instances/{}", id))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        check_response(response).await?;
+        Ok(())
+    }
 
-        if response.status().is_success() {
-            let response: VultrCreateResponse =
+    async fn get(&self, id: &str) -> Result<Option<Server>> {
+        Ok(found(self.get_instance(id).await)?.map(Server::from))
+    }
+
+    async fn wait_for_ip(&self, id: &str, timeout: Duration) -> Result<Server> {
+        poll_until(timeout, || async {
+            let server = Server::from(self.get_instance(id).await?);
+            Ok(server.has_ip().then_some(server))
+        })
+        .await
+    }
+
+    /// Vultr only supports upgrading to a bigger plan, the instance is restarted automatically
+    #[instrument(skip(self))]
+    async fn resize(&self, id: &str, plan: &str) -> Result<()> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .patch(format!("https://api.vultr.com/v2/instances/{}", id))
+                    .bearer_auth(&self.token)
+                    .json(&VultrUpdateParams { plan }),
+                self.api_attempts,
+            )
+            .await?;
+        check_response(response).await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn reboot(&self, id: &str) -> Result<()> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .post(format!("https://api.vultr.com/v2/instances/{}/reboot", id))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        check_response(response).await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn attach_ip(&self, id: &str, ip: IpAddr) -> Result<()> {
+        let reserved_id = self.get_reserved_ip_id(ip).await?;
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .post(format!(
+                        "https://api.vultr.com/v2/reserved-ips/{}/attach",
+                        reserved_id
+                    ))
+                    .bearer_auth(&self.token)
+                    .json(&VultrAttachParams { instance_id: id }),
+                self.api_attempts,
+            )
+            .await?;
+        check_response(response).await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn detach_ip(&self, ip: IpAddr) -> Result<()> {
+        let reserved_id = self.get_reserved_ip_id(ip).await?;
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .post(format!(
+                        "https://api.vultr.com/v2/reserved-ips/{}/detach",
+                        reserved_id
+                    ))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        check_response(response).await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn snapshot(&self, id: &str, name: &str) -> Result<String> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .post("https://api.vultr.com/v2/snapshots")
+                    .bearer_auth(&self.token)
+                    .json(&VultrSnapshotParams {
+                        instance_id: id,
+                        description: name,
+                    }),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: VultrSnapshotResponse = response.json().await.map_err(ResponseError::from)?;
+        let snapshot_id = response.snapshot.id;
+
+        info!(snapshot = snapshot_id, "waiting for snapshot to complete");
+        poll_until(SNAPSHOT_TIMEOUT, || async {
+            let response = self
+                .rate_limit
+                .send(
+                    self.client
+                        .get(format!(
+                            "https://api.vultr.com/v2/snapshots/{}",
+                            snapshot_id
+                        ))
+                        .bearer_auth(&self.token),
+                    self.api_attempts,
+                )
+                .await?;
+            let response = check_response(response).await?;
+            let response: VultrSnapshotResponse =
                 response.json().await.map_err(ResponseError::from)?;
-            Ok(response.instance.into())
-        } else {
-            Err(ResponseError::Other(response.text().await.map_err(NetworkError::from)?).into())
-        }
+            Ok((response.snapshot.status == "complete").then_some(()))
+        })
+        .await?;
+        Ok(snapshot_id)
     }
 
-    async fn kill(&self, id: &str) -> Result<()> {
+    /// Vultr only lists the monthly price of a plan
+    async fn hourly_price(&self, plan: &str) -> Result<Option<f64>> {
+        Ok(self
+            .get_plans()
+            .await?
+            .into_iter()
+            .find(|vultr_plan| vultr_plan.id == plan)
+            .map(|plan| plan.monthly_cost / BILLED_HOURS_PER_MONTH))
+    }
+
+    /// Vultr reports the balance as a negative number for remaining credit
+    async fn account_balance(&self) -> Result<Option<f64>> {
         let response = self
-            .client
-            .delete(format!("https://api.vultr.com/v2/instances/{}", id))
-            .bearer_auth(&self.token)
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())
+            .rate_limit
+            .send(
+                self.client
+                    .get("https://api.vultr.com/v2/account")
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: VultrAccountResponse = response.json().await.map_err(ResponseError::from)?;
+        Ok(Some(
+            -(response.account.balance + response.account.pending_charges),
+        ))
     }
 
-    async fn wait_for_ip(&self, id: &str) -> Result<Server> {
-        let instance = loop {
-            let instance = self.get_instance(id).await?;
-            if !instance.main_ip.is_unspecified() {
-                break instance;
-            } else {
-                sleep(Duration::from_millis(500)).await;
+    async fn validate(&self) -> Result<()> {
+        self.list().await?;
+
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .get("https://api.vultr.com/v2/regions?per_page=500")
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: VultrRegionsResponse = response.json().await.map_err(ResponseError::from)?;
+        for region in &self.regions {
+            if !response.regions.iter().any(|known| &known.id == region) {
+                return Err(CloudError::InvalidConfig(format!(
+                    "Unknown region {}",
+                    region
+                )));
             }
-        };
-        Ok(instance.into())
+        }
+
+        let plan = self
+            .get_plans()
+            .await?
+            .into_iter()
+            .find(|plan| plan.id == self.plan)
+            .ok_or_else(|| CloudError::InvalidConfig(format!("Unknown plan {}", self.plan)))?;
+        for region in &self.regions {
+            if !plan.locations.contains(region) {
+                return Err(CloudError::InvalidConfig(format!(
+                    "Plan {} is not available in region {}",
+                    self.plan, region
+                )));
+            }
+        }
+        self.app_image_id().await?;
+        Ok(())
     }
 }
 
+/// Vultr bills hourly, up to this many hours per month
+const BILLED_HOURS_PER_MONTH: f64 = 672.0;
+
 impl Vultr {
-    async fn get_app_image_id(&self, short_name: &str) -> Result<String> {
-        let response = self
+    /// Fetch a single page of instances, starting at the cursor returned by the previous page
+    async fn list_page(
+        &self,
+        cursor: Option<String>,
+    ) -> Result<(Vec<VultrInstanceResponse>, Option<String>)> {
+        let mut request = self
             .client
-            .get("https://api.vultr.com/v2/applications")
-            .send()
+            .get("https://api.vultr.com/v2/instances")
+            .query(&[("per_page", LIST_PAGE_SIZE)])
+            .bearer_auth(&self.token);
+        if let Some(cursor) = cursor {
+            request = request.query(&[("cursor", cursor)]);
+        }
+        let response = self.rate_limit.send(request, self.api_attempts).await?;
+        let response = check_response(response).await?;
+
+        let response: VultrListResponse = response.json().await.map_err(ResponseError::from)?;
+        Ok(response.into_page())
+    }
+
+    async fn get_plans(&self) -> Result<Vec<VultrPlan>> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .get("https://api.vultr.com/v2/plans?per_page=500")
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: VultrPlansResponse = response.json().await.map_err(ResponseError::from)?;
+        Ok(response.plans)
+    }
+
+    async fn create_instance(
+        &self,
+        region: &str,
+        options: &SpawnOptions<'_>,
+        key_ids: Vec<String>,
+    ) -> Result<VultrCreateResponse> {
+        let cached = self.app_image_id.lock().unwrap().clone();
+        let image_id = match cached.as_ref() {
+            Some(image_id) => image_id.clone(),
+            None => self.app_image_id().await?,
+        };
+        match self
+            .send_create(
+                region,
+                options,
+                VultrImage::Application(image_id),
+                key_ids.clone(),
+            )
             .await
-            .map_err(NetworkError::from)?;
+        {
+            Err(CloudError::ApiError { status, message }) if cached.is_some() => {
+                // the cached image id might have become invalid, try again once with a fresh one
+                warn!(
+                    status = status.as_u16(),
+                    message = message,
+                    "creating instance failed with a cached image id, retrying with a fresh one"
+                );
+                *self.app_image_id.lock().unwrap() = None;
+                let image_id = self.app_image_id().await?;
+                self.send_create(region, options, VultrImage::Application(image_id), key_ids)
+                    .await
+            }
+            result => result,
+        }
+    }
+
+    async fn send_create(
+        &self,
+        region: &str,
+        options: &SpawnOptions<'_>,
+        image: VultrImage,
+        key_ids: Vec<String>,
+    ) -> Result<VultrCreateResponse> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .post("https://api.vultr.com/v2/instances")
+                    .bearer_auth(&self.token)
+                    .json(&VultrCreateParams {
+                        region,
+                        plan: options.plan.unwrap_or(&self.plan),
+                        tag: &self.managed_tag,
+                        label: options.label,
+                        image,
+                        sshkey_id: key_ids,
+                        enable_ipv6: true,
+                        user_data: options
+                            .user_data
+                            .map(|user_data| BASE64_STANDARD.encode(user_data)),
+                        attach_vpc: self.network_id.as_slice(),
+                    }),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        Ok(response.json().await.map_err(ResponseError::from)?)
+    }
+
+    /// Lookup the image id for the configured application, the result is cached for later spawns
+    async fn app_image_id(&self) -> Result<String> {
+        let image_id = self.get_app_image_id(&self.application).await?;
+        *self.app_image_id.lock().unwrap() = Some(image_id.clone());
+        Ok(image_id)
+    }
+
+    async fn get_app_image_id(&self, short_name: &str) -> Result<String> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client.get("https://api.vultr.com/v2/applications"),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
         let response: VultrApplicationsResponse =
             response.json().await.map_err(ResponseError::from)?;
-        Ok(response
+        response
             .applications
             .into_iter()
             .find_map(|application| {
                 (application.short_name == short_name).then_some(application.image_id)
             })
             .ok_or_else(|| {
-                ResponseError::Other(format!("Application \"{}\" not found", short_name))
-            })?)
+                CloudError::InvalidConfig(format!("Unknown application \"{}\"", short_name))
+            })
     }
 
     async fn get_instance(&self, id: &str) -> Result<VultrInstanceResponse> {
         let response = self
-            .client
-            .get(format!("https://api.vultr.com/v2/instances/{}", id))
-            .bearer_auth(&self.token)
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())?;
+            .rate_limit
+            .send(
+                self.client
+                    .get(format!("https://api.vultr.com/v2/instances/{}", id))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
 
         let response: VultrGetResponse = response.json().await.map_err(ResponseError::from)?;
         Ok(response.instance)
     }
 
-    async fn get_ssh_key_id(&self, ssh_key: &str) -> Result<String> {
+    /// Reserved ips are addressed by id in the api, look up the id for the address
+    async fn get_reserved_ip_id(&self, ip: IpAddr) -> Result<String> {
         let response = self
-            .client
-            .get("https://api.vultr.com/v2/ssh-keys")
-            .bearer_auth(&self.token)
-            .send()
-            .await
-            .map_err(NetworkError::from)?;
-        CloudError::from_status_code(response.status())?;
+            .rate_limit
+            .send(
+                self.client
+                    .get("https://api.vultr.com/v2/reserved-ips?per_page=500")
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: VultrReservedIpsResponse =
+            response.json().await.map_err(ResponseError::from)?;
+        response
+            .reserved_ips
+            .into_iter()
+            .find(|reserved| reserved.subnet == ip)
+            .map(|reserved| reserved.id)
+            .ok_or_else(|| CloudError::InvalidConfig(format!("Unknown reserved ip {}", ip)))
+    }
 
-        if !response.status().is_success() {
-            return Err(
-                ResponseError::Other(response.text().await.map_err(NetworkError::from)?).into(),
-            );
-        }
+    async fn get_ssh_key_id(&self, ssh_key: &str) -> Result<String> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .get("https://api.vultr.com/v2/ssh-keys")
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
 
         let response: VultrSshListResponse = response.json().await.map_err(ResponseError::from)?;
         if let Some(key) = response
@@ -168,26 +538,48 @@ impl Vultr {
             .into_iter()
             .find(|key| key_cmp(&key.ssh_key, ssh_key))
         {
+            debug!(id = key.id, "key found");
             Ok(key.id)
         } else {
-            let response = self
-                .client
-                .post("https://api.vultr.com/v2/ssh-keys")
-                .bearer_auth(&self.token)
-                .json(&VultrCreateSshKeyParams {
-                    name: "Dispenser Key",
-                    ssh_key,
-                })
-                .send()
-                .await
-                .map_err(NetworkError::from)?;
-            CloudError::from_status_code(response.status())?;
-            let response: VultrSshCreateResponse =
-                response.json().await.map_err(ResponseError::from)?;
-
-            Ok(response.ssh_key.id)
+            info!("key doesn't exist, creating");
+            self.create_key("Dispenser Key", ssh_key).await
         }
     }
+
+    #[instrument(skip(self))]
+    async fn create_key(&self, name: &str, ssh_key: &str) -> Result<String> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .post("https://api.vultr.com/v2/ssh-keys")
+                    .bearer_auth(&self.token)
+                    .json(&VultrCreateSshKeyParams { name, ssh_key }),
+                self.api_attempts,
+            )
+            .await?;
+        let response = check_response(response).await?;
+        let response: VultrSshCreateResponse =
+            response.json().await.map_err(ResponseError::from)?;
+
+        Ok(response.ssh_key.id)
+    }
+
+    #[instrument(skip(self))]
+    async fn remove_key(&self, key_id: &str) -> Result<()> {
+        let response = self
+            .rate_limit
+            .send(
+                self.client
+                    .delete(format!("https://api.vultr.com/v2/ssh-keys/{}", key_id))
+                    .bearer_auth(&self.token),
+                self.api_attempts,
+            )
+            .await?;
+        check_response(response).await?;
+
+        Ok(())
+    }
 }
 
 #[derive(Serialize)]
@@ -195,15 +587,124 @@ struct VultrCreateParams<'a> {
     region: &'a str,
     plan: &'a str,
     tag: &'a str,
-    label: String,
-    image_id: String,
+    label: &'a str,
+    #[serde(flatten)]
+    image: VultrImage,
     sshkey_id: Vec<String>,
     enable_ipv6: bool,
+    /// Base64 encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_data: Option<String>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    attach_vpc: &'a [String],
+}
+
+#[derive(Serialize)]
+enum VultrImage {
+    #[serde(rename = "image_id")]
+    Application(String),
+    #[serde(rename = "snapshot_id")]
+    Snapshot(String),
+}
+
+#[derive(Serialize)]
+struct VultrAttachParams<'a> {
+    instance_id: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrReservedIpsResponse {
+    reserved_ips: Vec<VultrReservedIp>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrReservedIp {
+    id: String,
+    subnet: IpAddr,
+}
+
+#[derive(Serialize)]
+struct VultrSnapshotParams<'a> {
+    instance_id: &'a str,
+    description: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrSnapshotResponse {
+    snapshot: VultrSnapshot,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrSnapshot {
+    id: String,
+    status: String,
+}
+
+#[derive(Serialize)]
+struct VultrUpdateParams<'a> {
+    plan: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrRegionsResponse {
+    regions: Vec<VultrRegion>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrRegion {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrAccountResponse {
+    account: VultrAccount,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrAccount {
+    balance: f64,
+    pending_charges: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrPlansResponse {
+    plans: Vec<VultrPlan>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VultrPlan {
+    id: String,
+    locations: Vec<String>,
+    monthly_cost: f64,
 }
 
+const LIST_PAGE_SIZE: &str = "100";
+
 #[derive(Debug, Deserialize)]
 struct VultrListResponse {
     instances: Vec<VultrInstanceResponse>,
+    #[serde(default)]
+    meta: VultrMeta,
+}
+
+impl VultrListResponse {
+    fn into_page(self) -> (Vec<VultrInstanceResponse>, Option<String>) {
+        let next = Some(self.meta.links.next).filter(|next| !next.is_empty());
+        (self.instances, next)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct VultrMeta {
+    #[serde(default)]
+    links: VultrLinks,
+}
+
+/// Cursors for the previous and next page, empty if there is no such page
+#[derive(Debug, Default, Deserialize)]
+struct VultrLinks {
+    #[serde(default)]
+    next: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -220,24 +721,30 @@ struct VultrCreateResponse {
 #[derive(Debug, Deserialize)]
 struct VultrInstanceResponse {
     id: String,
+    label: String,
     os: String,
     ram: u64,
     main_ip: IpAddr,
-    #[serde(deserialize_with = "ok_or_default")]
+    #[serde(default, deserialize_with = "empty_as_none")]
     v6_main_ip: Option<IpAddr>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    internal_ip: Option<IpAddr>,
     region: String,
+    plan: String,
     vcpu_count: u16,
     date_created: DateTime<Utc>,
     tag: String,
 }
 
-fn ok_or_default<'a, T, D>(deserializer: D) -> Result<T, D::Error>
+/// Vultr returns an empty string for servers without an ipv6 or internal address
+fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<IpAddr>, D::Error>
 where
-    T: Deserialize<'a> + Default,
-    D: Deserializer<'a>,
+    D: Deserializer<'de>,
 {
-    let v: T = Deserialize::deserialize(deserializer).unwrap_or_default();
-    Ok(v)
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("") => Ok(None),
+        Some(ip) => ip.parse().map(Some).map_err(D::Error::custom),
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -250,18 +757,22 @@ impl From<VultrInstanceResponse> for Server {
     fn from(instance: VultrInstanceResponse) -> Self {
         Server {
             id: instance.id,
+            label: instance.label,
             created: instance.date_created,
             ip: instance.main_ip,
             ip_v6: instance.v6_main_ip,
+            private_ip: instance.internal_ip,
+            region: instance.region,
+            plan: instance.plan,
         }
     }
 }
 
-impl From<VultrCreatedInstanceResponse> for Created {
-    fn from(instance: VultrCreatedInstanceResponse) -> Self {
+impl From<(VultrCreatedInstanceResponse, Arc<KeyPair>)> for Created {
+    fn from((instance, key): (VultrCreatedInstanceResponse, Arc<KeyPair>)) -> Self {
         Created {
             id: instance.id,
-            auth: CreatedAuth::Password(instance.default_password),
+            auth: CreatedAuth::PasswordOrKey(instance.default_password, key),
         }
     }
 }
@@ -300,3 +811,72 @@ struct VultrCreateSshKeyParams<'a> {
     name: &'a str,
     ssh_key: &'a str,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(id: &str, tag: &str) -> String {
+        format!(
+            r#"{{"id": "{id}", "label": "spire", "os": "Docker", "ram": 2048, "main_ip": "192.0.2.1",
+            "v6_main_ip": "", "internal_ip": "10.1.96.3", "region": "ams", "plan": "vc2-1c-2gb", "vcpu_count": 1,
+            "date_created": "2024-06-01T12:00:00+00:00", "tag": "{tag}"}}"#
+        )
+    }
+
+    #[tokio::test]
+    async fn test_list_pages() {
+        let pages = [
+            format!(
+                r#"{{"instances": [{}, {}], "meta": {{"total": 3, "links": {{"next": "page2", "prev": ""}}}}}}"#,
+                instance("1", "spire"),
+                instance("2", "other")
+            ),
+            format!(
+                r#"{{"instances": [{}], "meta": {{"total": 3, "links": {{"next": "", "prev": "page1"}}}}}}"#,
+                instance("3", "spire")
+            ),
+        ];
+        let mut cursors = Vec::new();
+        let instances = fetch_all_pages(|cursor| {
+            let page = match cursor.as_deref() {
+                None => &pages[0],
+                Some("page2") => &pages[1],
+                Some(cursor) => panic!("unexpected cursor {}", cursor),
+            };
+            cursors.push(cursor);
+            let response = serde_json::from_str::<VultrListResponse>(page).unwrap();
+            async move { Ok(response.into_page()) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(cursors, [None, Some("page2".to_string())]);
+        let ids: Vec<_> = instances
+            .iter()
+            .map(|instance| instance.id.as_str())
+            .collect();
+        assert_eq!(ids, ["1", "2", "3"]);
+
+        let server = Server::from(instances.into_iter().next().unwrap());
+        assert_eq!(server.private_ip, Some("10.1.96.3".parse().unwrap()));
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct V6 {
+        #[serde(default, deserialize_with = "empty_as_none")]
+        v6_main_ip: Option<IpAddr>,
+    }
+
+    #[test]
+    fn test_v6_main_ip() {
+        let parse = |json: &str| serde_json::from_str::<V6>(json).map(|v| v.v6_main_ip);
+        assert_eq!(parse(r#"{"v6_main_ip": ""}"#).unwrap(), None);
+        assert_eq!(parse(r#"{}"#).unwrap(), None);
+        assert_eq!(
+            parse(r#"{"v6_main_ip": "2001:db8::1"}"#).unwrap(),
+            Some("2001:db8::1".parse().unwrap())
+        );
+        assert!(parse(r#"{"v6_main_ip": "2001:db8::zz"}"#).is_err());
+    }
+}
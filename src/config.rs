@@ -1,14 +1,37 @@
 use crate::cloud::digitalocean::DigitalOcean;
+use crate::cloud::ec2::{Ec2, Ec2Options};
+use crate::cloud::gcp::{Gcp, GcpOptions, GcpServiceAccountKey};
+use crate::cloud::linode::Linode;
+use crate::cloud::ovh::{Ovh, OvhCredentials, OvhOptions};
+use crate::cloud::scaleway::{Scaleway, ScalewayOptions};
 use crate::cloud::vultr::Vultr;
-use crate::cloud::Cloud;
+use crate::cloud::{Cloud, CloudError, Server};
+use crate::dns::DynDnsAuth;
+use crate::http::{parse_proxy, ProxyError};
+use crate::notify::{DiscordNotifier, MatrixNotifier, Notifier};
+use crate::rcon::PlayerFilter;
 use camino::Utf8PathBuf;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use petname::{Generator, Petnames};
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
 use secretfile::{load, SecretError};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::fs::read_to_string;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use tracing::warn;
 
 #[derive(Debug, Error)]
 pub enum ConfigError {
@@ -20,6 +43,36 @@ pub enum ConfigError {
     NoProvider,
     #[error("Multiple cloud providers configured")]
     MultipleProviders,
+    #[error("No servers configured")]
+    NoServers,
+    #[error("Multiple servers configured with the name \"{0}\"")]
+    DuplicateServer(String),
+    #[error("Invalid {0} schedule: {1}")]
+    InvalidSchedule(&'static str, cron::error::Error),
+    #[error("Host port {0}/{1} is mapped multiple times")]
+    DuplicatePort(u16, PortProtocol),
+    #[error("Dyndns requires either a username and password or a token")]
+    DynDnsAuth,
+    #[error("Invalid environment variable name \"{0}\"")]
+    InvalidEnvName(String),
+    #[error("Invalid docker memory limit \"{0}\", expected a number with a unit like \"512m\" or \"2g\"")]
+    InvalidDockerMemory(String),
+    #[error("Invalid docker cpu limit {0}, expected a positive number of cpus")]
+    InvalidDockerCpus(f32),
+    #[error("Setting up servers trough cloud-init is only supported for vultr and digitalocean")]
+    CloudInitUnsupported,
+    #[error("{0} can't be set up on the read-only container-optimized os used on gcp, set {1}")]
+    ReadOnlyImage(&'static str, &'static str),
+    #[error("Invalid proxy url: {0}")]
+    Proxy(#[from] ProxyError),
+    #[error("Invalid service account key \"{0}\": {1}")]
+    InvalidServiceAccountKey(Utf8PathBuf, String),
+    #[error("{0}")]
+    Cloud(#[from] CloudError),
+    #[error("Unsupported ssh {0} \"{1}\", supported are: {2}")]
+    UnsupportedSshAlgorithm(&'static str, String, String),
+    #[error("At least one ssh {0} needs to be configured")]
+    NoSshAlgorithms(&'static str),
 }
 
 /// Intentionally opaque error
@@ -37,31 +90,342 @@ impl From<toml::de::Error> for TomlError {
 pub struct Config {
     pub vultr: Option<VultrConfig>,
     pub digital_ocean: Option<DigitalOceanConfig>,
-    pub server: ServerConfig,
+    pub linode: Option<LinodeConfig>,
+    pub ec2: Option<Ec2Config>,
+    pub ovh: Option<OvhConfig>,
+    pub gcp: Option<GcpConfig>,
+    pub scaleway: Option<ScalewayConfig>,
+    #[serde(rename = "server", deserialize_with = "deserialize_one_or_many")]
+    pub servers: Vec<ServerConfig>,
     pub dyndns: Option<DynDnsConfig>,
     pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    pub notifications: Option<NotificationConfig>,
+    #[serde(default)]
+    pub palantir: PalantirConfig,
+    #[serde(default)]
+    pub ssh: SshConfig,
+    /// Address to serve prometheus metrics on
+    pub metrics_listen: Option<SocketAddr>,
+    /// File to persist the daemon state in, so a restarted daemon picks up the running servers
+    pub state_file: Option<Utf8PathBuf>,
+    /// File to write the id, ip and login credentials of spawned servers to
+    pub credentials_file: Option<Utf8PathBuf>,
+    /// Destroy a new server when setting it up fails, instead of leaving it running for inspection
+    #[serde(default)]
+    pub cleanup_on_failure: bool,
+    /// Proxy to send all outbound requests trough, like `http://proxy.example.com:3128`
+    pub proxy_url: Option<String>,
+    /// Warn when the prepaid credit on the cloud account drops below this amount, only supported on vultr and digitalocean
+    pub min_balance: Option<f64>,
+    /// Plan to use for servers with a specific `config_mode`, instead of the plan from the provider config
+    #[serde(default)]
+    pub mode_plans: HashMap<String, String>,
+    /// Hourly price per plan, used to estimate the cost of running servers instead of the price reported by the provider
+    #[serde(default)]
+    pub plan_prices: HashMap<String, f64>,
+    /// Only log the actions that would be taken, set from the command line
+    #[serde(skip)]
+    pub dry_run: bool,
+    /// Print machine readable json instead of the human readable output, set from the command line
+    #[serde(skip)]
+    pub json_output: bool,
 }
 
 impl Config {
     pub fn from_file<P: AsRef<Path> + Into<Utf8PathBuf>>(path: P) -> Result<Self, ConfigError> {
         let content = read_to_string(path.as_ref()).map_err(|_| ConfigError::Open(path.into()))?;
-        Ok(toml::from_str(&content).map_err(TomlError::from)?)
+        let mut config: Config = toml::from_str(&content).map_err(TomlError::from)?;
+        config.schedule.override_schedules(
+            std::env::var(START_SCHEDULE_ENV).ok(),
+            std::env::var(STOP_SCHEDULE_ENV).ok(),
+        );
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.schedule.schedules()?;
+        if self.dyndns.as_ref().is_some_and(|dns| dns.auth().is_none()) {
+            return Err(ConfigError::DynDnsAuth);
+        }
+        if self.servers.is_empty() {
+            return Err(ConfigError::NoServers);
+        }
+        if let Some(proxy_url) = &self.proxy_url {
+            parse_proxy(proxy_url)?;
+        }
+        self.ssh.validate()?;
+        if self.gcp.is_some() && self.palantir.enabled {
+            return Err(ConfigError::ReadOnlyImage(
+                "Palantir",
+                "palantir.enabled = false",
+            ));
+        }
+        let mut labels = HashSet::new();
+        for server in &self.servers {
+            if !labels.insert(server.label()) {
+                return Err(ConfigError::DuplicateServer(server.name.clone()));
+            }
+            if let Some(name) = server.env.keys().find(|name| !valid_env_name(name)) {
+                return Err(ConfigError::InvalidEnvName(name.clone()));
+            }
+            if let Some(memory) = server
+                .docker_memory
+                .as_ref()
+                .filter(|memory| !valid_docker_memory(memory))
+            {
+                return Err(ConfigError::InvalidDockerMemory(memory.clone()));
+            }
+            if let Some(cpus) = server
+                .docker_cpus
+                .filter(|cpus| !cpus.is_finite() || *cpus <= 0.0)
+            {
+                return Err(ConfigError::InvalidDockerCpus(cpus));
+            }
+            if self.gcp.is_some() && server.swap_mb > 0 {
+                return Err(ConfigError::ReadOnlyImage("Swap", "swap_mb = 0"));
+            }
+            if server.cloud_init && self.vultr.is_none() && self.digital_ocean.is_none() {
+                return Err(ConfigError::CloudInitUnsupported);
+            }
+            let mut seen = HashSet::new();
+            for mapping in &server.ports {
+                for &protocol in mapping.protocol.expand() {
+                    if !seen.insert((mapping.host, protocol)) {
+                        return Err(ConfigError::DuplicatePort(mapping.host, protocol));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the server configuration that manages a running server
+    ///
+    /// If only a single server is configured, it manages all servers
+    pub fn server_config_for(&self, server: &Server) -> Option<&ServerConfig> {
+        match self.servers.as_slice() {
+            [single] => Some(single),
+            servers => servers
+                .iter()
+                .find(|server_config| server_config.label() == server.label),
+        }
+    }
+
+    /// Check if a running server is managed by the server configuration
+    pub fn owns(&self, server_config: &ServerConfig, server: &Server) -> bool {
+        self.server_config_for(server)
+            .is_some_and(|owner| std::ptr::eq(owner, server_config))
+    }
+
+    /// The dyndns configuration only applies to the first configured server
+    ///
+    /// Dyndns is disabled in dry run mode
+    pub fn dyndns_for(&self, server_config: &ServerConfig) -> Option<&DynDnsConfig> {
+        self.dyndns
+            .as_ref()
+            .filter(|_| std::ptr::eq(&self.servers[0], server_config) && !self.dry_run)
+    }
+
+    /// The plan for the mode of the server, if it differs from the default plan
+    pub fn plan_for(&self, server_config: &ServerConfig) -> Option<&str> {
+        self.mode_plans
+            .get(&server_config.config_mode)
+            .map(String::as_str)
+    }
+
+    /// The plan configured for the provider
+    pub fn default_plan(&self) -> Option<&str> {
+        if let Some(vultr) = &self.vultr {
+            Some(&vultr.plan)
+        } else if let Some(digital_ocean) = &self.digital_ocean {
+            Some(&digital_ocean.plan)
+        } else if let Some(linode) = &self.linode {
+            Some(&linode.plan)
+        } else if let Some(ec2) = &self.ec2 {
+            Some(&ec2.instance_type)
+        } else if let Some(ovh) = &self.ovh {
+            Some(&ovh.flavor)
+        } else if let Some(gcp) = &self.gcp {
+            Some(&gcp.machine_type)
+        } else {
+            self.scaleway
+                .as_ref()
+                .map(|scaleway| scaleway.commercial_type.as_str())
+        }
+    }
+
+    /// The configured hourly price for a plan
+    pub fn plan_price(&self, plan: &str) -> Option<f64> {
+        self.plan_prices.get(plan).copied()
+    }
+
+    /// The configured start and stop notification backends, notifications are disabled in dry run mode
+    pub fn notifiers(&self) -> Vec<Box<dyn Notifier>> {
+        let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+        let Some(notifications) = self.notifications.as_ref().filter(|_| !self.dry_run) else {
+            return notifiers;
+        };
+        if let Some(url) = &notifications.discord_webhook_url {
+            notifiers.push(Box::new(DiscordNotifier::new(url.clone())));
+        }
+        if let Some(matrix) = &notifications.matrix {
+            notifiers.push(Box::new(MatrixNotifier::new(matrix)));
+        }
+        notifiers
+    }
+
+    /// Notifications are disabled in dry run mode
+    pub fn ready_webhook_url(&self) -> Option<&str> {
+        self.notifications
+            .as_ref()
+            .and_then(|notifications| notifications.ready_webhook_url.as_deref())
+            .filter(|_| !self.dry_run)
+    }
+
+    /// Human readable description of the configured provider, region and plan
+    pub fn cloud_description(&self) -> String {
+        if let Some(vultr) = &self.vultr {
+            format!(
+                "vultr in {} with plan {}",
+                vultr.region.join(", "),
+                vultr.plan
+            )
+        } else if let Some(digital_ocean) = &self.digital_ocean {
+            format!(
+                "digitalocean in {} with plan {}",
+                digital_ocean.region.join(", "),
+                digital_ocean.plan
+            )
+        } else if let Some(linode) = &self.linode {
+            format!("linode in {} with plan {}", linode.region, linode.plan)
+        } else if let Some(ec2) = &self.ec2 {
+            format!(
+                "ec2 in {} with instance type {}{}",
+                ec2.region,
+                ec2.instance_type,
+                if ec2.spot { " (spot)" } else { "" }
+            )
+        } else if let Some(ovh) = &self.ovh {
+            format!("ovh in {} with flavor {}", ovh.region, ovh.flavor)
+        } else if let Some(gcp) = &self.gcp {
+            format!("gcp in {} with machine type {}", gcp.zone, gcp.machine_type)
+        } else if let Some(scaleway) = &self.scaleway {
+            format!(
+                "scaleway in {} with type {}",
+                scaleway.zone, scaleway.commercial_type
+            )
+        } else {
+            String::from("no provider")
+        }
     }
 
     pub fn cloud(&self) -> Result<Arc<dyn Cloud>, ConfigError> {
-        if self.vultr.is_some() && self.digital_ocean.is_some() {
-            Err(ConfigError::NoProvider)
+        let configured = [
+            self.vultr.is_some(),
+            self.digital_ocean.is_some(),
+            self.linode.is_some(),
+            self.ec2.is_some(),
+            self.ovh.is_some(),
+            self.gcp.is_some(),
+            self.scaleway.is_some(),
+        ];
+        if configured.iter().filter(|configured| **configured).count() > 1 {
+            Err(ConfigError::MultipleProviders)
         } else if let Some(vultr) = &self.vultr {
             Ok(Arc::new(Vultr::new(
                 vultr.api_key.clone(),
                 vultr.region.clone(),
                 vultr.plan.clone(),
+                vultr.managed_tag.clone(),
+                vultr.base_image.clone(),
+                vultr.network_id.clone(),
+                self.retry.cloud_request_attempts,
             )))
         } else if let Some(digital_ocean) = &self.digital_ocean {
             Ok(Arc::new(DigitalOcean::new(
                 digital_ocean.api_key.clone(),
                 digital_ocean.region.clone(),
                 digital_ocean.plan.clone(),
+                digital_ocean.managed_tag.clone(),
+                digital_ocean.base_image.clone(),
+                digital_ocean.vpc_id.clone(),
+                self.retry.cloud_request_attempts,
+            )))
+        } else if let Some(linode) = &self.linode {
+            Ok(Arc::new(Linode::new(
+                linode.api_key.clone(),
+                linode.region.clone(),
+                linode.plan.clone(),
+                linode.managed_tag.clone(),
+                self.retry.cloud_request_attempts,
+            )))
+        } else if let Some(ec2) = &self.ec2 {
+            Ok(Arc::new(Ec2::new(
+                ec2.access_key_id.clone(),
+                ec2.secret_access_key.clone(),
+                Ec2Options {
+                    region: ec2.region.clone(),
+                    instance_type: ec2.instance_type.clone(),
+                    ami: ec2.ami.clone(),
+                    spot: ec2.spot,
+                    spot_max_price: ec2.spot_max_price.clone(),
+                    security_group_ids: ec2.security_group_ids.clone(),
+                    managed_tag: ec2.managed_tag.clone(),
+                },
+                self.retry.cloud_request_attempts,
+            )))
+        } else if let Some(ovh) = &self.ovh {
+            Ok(Arc::new(Ovh::new(
+                OvhCredentials {
+                    endpoint: ovh.endpoint.clone(),
+                    application_key: ovh.application_key.clone(),
+                    application_secret: ovh.application_secret.clone(),
+                    consumer_key: ovh.consumer_key.clone(),
+                },
+                OvhOptions {
+                    project_id: ovh.project_id.clone(),
+                    region: ovh.region.clone(),
+                    flavor: ovh.flavor.clone(),
+                    image: ovh.image.clone(),
+                    managed_tag: ovh.managed_tag.clone(),
+                },
+                self.retry.cloud_request_attempts,
+            )))
+        } else if let Some(gcp) = &self.gcp {
+            let key = read_to_string(&gcp.service_account_key)
+                .map_err(|_| ConfigError::Open(gcp.service_account_key.clone()))?;
+            let key: GcpServiceAccountKey = serde_json::from_str(&key).map_err(|e| {
+                ConfigError::InvalidServiceAccountKey(
+                    gcp.service_account_key.clone(),
+                    e.to_string(),
+                )
+            })?;
+            Ok(Arc::new(Gcp::new(
+                key,
+                GcpOptions {
+                    project: gcp.project.clone(),
+                    zone: gcp.zone.clone(),
+                    machine_type: gcp.machine_type.clone(),
+                    network: gcp.network.clone(),
+                    network_tags: gcp.network_tags.clone(),
+                    managed_tag: gcp.managed_tag.clone(),
+                },
+                self.retry.cloud_request_attempts,
+            )?))
+        } else if let Some(scaleway) = &self.scaleway {
+            Ok(Arc::new(Scaleway::new(
+                scaleway.secret_key.clone(),
+                ScalewayOptions {
+                    project_id: scaleway.project_id.clone(),
+                    zone: scaleway.zone.clone(),
+                    commercial_type: scaleway.commercial_type.clone(),
+                    image: scaleway.image.clone(),
+                    managed_tag: scaleway.managed_tag.clone(),
+                },
+                self.retry.cloud_request_attempts,
             )))
         } else {
             Err(ConfigError::NoProvider)
@@ -69,6 +433,21 @@ impl Config {
     }
 }
 
+/// Environment variable names consist of letters, digits and underscores and don't start with a digit
+fn valid_env_name(name: &str) -> bool {
+    !name.starts_with(|c: char| c.is_ascii_digit())
+        && !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Docker memory limits are a whole number with an optional unit of b, k, m or g
+fn valid_docker_memory(memory: &str) -> bool {
+    let digits = memory.trim_end_matches(|c: char| "bkmgBKMG".contains(c));
+    memory.len() - digits.len() <= 1
+        && !digits.is_empty()
+        && digits.chars().all(|c| c.is_ascii_digit())
+}
+
 fn deserialize_opt_secret<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
 where
     D: Deserializer<'de>,
@@ -85,6 +464,24 @@ where
     load_secret(raw).map_err(D::Error::custom)
 }
 
+fn deserialize_one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
 fn load_secret(raw: String) -> Result<String, SecretError> {
     if raw.starts_with('/') || raw.starts_with('$') {
         load(&raw)
@@ -99,8 +496,9 @@ pub struct ServerConfig {
     pub rcon: String,
     #[serde(deserialize_with = "deserialize_secret")]
     pub password: String,
-    #[serde(default = "server_default_image")]
-    pub image: String,
+    /// Docker image, either a single image or an image per `config_mode`
+    #[serde(default)]
+    pub image: ImageConfig,
     #[serde(deserialize_with = "deserialize_opt_secret")]
     pub demostf_key: Option<String>,
     #[serde(deserialize_with = "deserialize_opt_secret")]
@@ -117,12 +515,215 @@ pub struct ServerConfig {
     pub ssh_keys: Vec<String>,
     #[serde(default)]
     pub manage_existing: bool,
+    /// Extra config for the game server, either a single config or a base config with additions per `config_mode`
+    #[serde(default)]
+    pub extra_cfg: ExtraCfg,
+    #[serde(default = "server_default_ports")]
+    pub ports: Vec<PortMapping>,
+    /// Reserved ip to assign to the server, players can connect to this address instead of the changing server ip
+    ///
+    /// Only supported on digitalocean and vultr
+    #[serde(default)]
+    pub reserved_ip: Option<IpAddr>,
+    /// Use the ipv6 address in the connect command when the server has one
+    #[serde(default)]
+    pub prefer_ipv6: bool,
+    /// Docker network mode for the container, the port mappings are ignored with host networking
+    #[serde(default)]
+    pub network_mode: NetworkMode,
+    #[serde(default)]
+    pub host_key_verification: HostKeyVerification,
+    /// Number of cpus the game server container can use, unlimited if not set
+    #[serde(default)]
+    pub docker_cpus: Option<f32>,
+    /// Memory limit for the game server container like "2g" or "512m", unlimited if not set
+    #[serde(default)]
+    pub docker_memory: Option<String>,
+    /// User to log in to the server as, setup commands are run with sudo for users other than root
+    #[serde(default = "server_default_ssh_user")]
+    pub ssh_user: String,
+    /// Size of the swapfile to create in MB, 0 disables swap
+    #[serde(default = "server_default_swap_mb")]
+    pub swap_mb: u64,
+    /// Stop the server once it has been empty for this many minutes, even outside the stop window
+    #[serde(default)]
+    pub idle_shutdown_minutes: Option<u64>,
+    /// Minimum time in minutes a new server is kept running before it's stopped for being empty
+    #[serde(default = "server_default_min_uptime_minutes")]
+    pub min_uptime_minutes: u64,
+    /// Set up the server trough cloud-init on first boot instead of over ssh, only supported for vultr and digitalocean
+    #[serde(default)]
+    pub cloud_init: bool,
+    /// Generate a random connect password for every new server, instead of using the configured password
+    #[serde(default)]
+    pub rotate_password: bool,
+    /// Count bots as players when checking if the server is empty, SourceTV is never counted
     #[serde(default)]
-    pub extra_cfg: String,
+    pub count_bots: bool,
+    /// Name for the docker container, defaults to the label derived from the server name
+    #[serde(default)]
+    pub container_name: Option<String>,
+    /// Id of a snapshot to boot the server from, skips the setup steps that are part of the snapshot
+    #[serde(default)]
+    pub snapshot: Option<String>,
+    /// Extra environment variables for the docker container
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Private key to set up the server with, instead of generating a new key for every server
+    #[serde(default)]
+    pub deploy_key_path: Option<Utf8PathBuf>,
+    /// Commands to run on the server before pulling the image
+    #[serde(default)]
+    pub pre_setup: Vec<String>,
+    /// Commands to run on the server after the container is started
+    #[serde(default)]
+    pub post_setup: Vec<String>,
 }
 
-fn server_default_image() -> String {
-    String::from("spiretf/docker-spire-server")
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// Run the container on the docker bridge network and publish the configured ports
+    #[default]
+    Bridge,
+    /// Run the container directly on the host network, ports are exposed as bound by the image
+    Host,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyVerification {
+    /// Trust the first host key seen for a server
+    ///
+    /// None of the providers report the host key of a created server, so there is nothing to verify the first key against
+    #[default]
+    #[serde(alias = "provider")]
+    TrustOnFirstUse,
+    /// Accept any host key
+    None,
+}
+
+impl ServerConfig {
+    /// Label for the cloud servers, derived from the server name
+    pub fn label(&self) -> String {
+        let label = self
+            .name
+            .to_ascii_lowercase()
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "-");
+        label
+            .split('-')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Name of the docker container running the server
+    pub fn container_name(&self) -> String {
+        self.container_name.clone().unwrap_or_else(|| self.label())
+    }
+
+    /// The docker image for the configured mode
+    pub fn image(&self) -> &str {
+        match &self.image {
+            ImageConfig::Single(image) => image,
+            ImageConfig::PerMode(images) => images
+                .get(&self.config_mode)
+                .map(String::as_str)
+                .unwrap_or(DEFAULT_IMAGE),
+        }
+    }
+
+    /// The extra config for the configured mode, the base config followed by the config for the mode
+    pub fn extra_cfg(&self) -> String {
+        match &self.extra_cfg {
+            ExtraCfg::Single(cfg) => cfg.clone(),
+            ExtraCfg::Layered { base, modes } => std::iter::once(base)
+                .chain(modes.get(&self.config_mode))
+                .filter(|cfg| !cfg.is_empty())
+                .map(String::as_str)
+                // a newline so a trailing comment in the base config doesn't comment out the mode config
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// The digest the image is pinned to, if the image is specified as `name@sha256:...`
+    pub fn image_digest(&self) -> Option<&str> {
+        self.image().split_once('@').map(|(_, digest)| digest)
+    }
+
+    /// The connect password for a new server
+    pub fn new_password(&self) -> String {
+        if self.rotate_password {
+            thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(ROTATED_PASSWORD_LENGTH)
+                .map(char::from)
+                .collect()
+        } else {
+            self.password.clone()
+        }
+    }
+
+    /// Which players count towards the player count of the server
+    pub fn player_filter(&self) -> PlayerFilter<'_> {
+        PlayerFilter {
+            count_bots: self.count_bots,
+            tv_name: &self.tv_name,
+        }
+    }
+
+    /// Check that the rcon password can be passed to the server unquoted
+    pub fn validate_rcon_password(&self) -> Result<(), &'static str> {
+        if self.rcon.is_empty() {
+            Err("rcon password is empty")
+        } else if self
+            .rcon
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || "\"'`$\\;&|".contains(c))
+        {
+            Err("rcon password contains whitespace or shell special characters")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+const ROTATED_PASSWORD_LENGTH: usize = 12;
+
+const DEFAULT_IMAGE: &str = "spiretf/docker-spire-server";
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ImageConfig {
+    Single(String),
+    /// Image per `config_mode`, modes without an image use the default image
+    PerMode(HashMap<String, String>),
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        ImageConfig::Single(DEFAULT_IMAGE.into())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ExtraCfg {
+    Single(String),
+    /// Base config with additions per `config_mode`, which are appended to the base config
+    Layered {
+        #[serde(default)]
+        base: String,
+        #[serde(default)]
+        modes: HashMap<String, String>,
+    },
+}
+
+impl Default for ExtraCfg {
+    fn default() -> Self {
+        ExtraCfg::Single(String::new())
+    }
 }
 
 fn server_default_name() -> String {
@@ -141,15 +742,126 @@ fn server_default_mode() -> String {
     String::from("6v6")
 }
 
+fn server_default_ssh_user() -> String {
+    String::from("root")
+}
+
+fn server_default_swap_mb() -> u64 {
+    1024
+}
+
+fn server_default_min_uptime_minutes() -> u64 {
+    10
+}
+
+fn server_default_ports() -> Vec<PortMapping> {
+    let mut ports = vec![
+        PortMapping::new(27015, 27015, PortProtocol::Both),
+        PortMapping::new(27021, 27021, PortProtocol::Tcp),
+        PortMapping::new(27020, 27020, PortProtocol::Udp),
+        PortMapping::new(27025, 27025, PortProtocol::Tcp),
+        PortMapping::new(28015, 27015, PortProtocol::Both),
+    ];
+    // extra ports forwarded to the game port, 27115 to 27915
+    ports.extend((1..=9).map(|i| PortMapping::new(27015 + i * 100, 27015, PortProtocol::Both)));
+    ports
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PortProtocol {
+    #[default]
+    Tcp,
+    Udp,
+    Both,
+}
+
+impl PortProtocol {
+    /// Split `Both` into its tcp and udp parts
+    fn expand(self) -> &'static [PortProtocol] {
+        match self {
+            PortProtocol::Tcp => &[PortProtocol::Tcp],
+            PortProtocol::Udp => &[PortProtocol::Udp],
+            PortProtocol::Both => &[PortProtocol::Tcp, PortProtocol::Udp],
+        }
+    }
+}
+
+impl Display for PortProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PortProtocol::Tcp => write!(f, "tcp"),
+            PortProtocol::Udp => write!(f, "udp"),
+            PortProtocol::Both => write!(f, "tcp+udp"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PortMapping {
+    /// Port on the host to expose
+    pub host: u16,
+    /// Port inside the container to forward to
+    pub container: u16,
+    /// Protocol to forward, optional, defaults to tcp
+    #[serde(default)]
+    pub protocol: PortProtocol,
+}
+
+impl PortMapping {
+    fn new(host: u16, container: u16, protocol: PortProtocol) -> Self {
+        PortMapping {
+            host,
+            container,
+            protocol,
+        }
+    }
+
+    /// The docker `-p` arguments for this mapping
+    pub fn docker_args(&self) -> impl Iterator<Item = String> + '_ {
+        self.protocol
+            .expand()
+            .iter()
+            .map(move |protocol| match protocol {
+                PortProtocol::Udp => format!("-p {}:{}/udp", self.host, self.container),
+                _ => format!("-p {}:{}", self.host, self.container),
+            })
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct VultrConfig {
     #[serde(deserialize_with = "deserialize_secret")]
     pub api_key: String,
-    /// See https://api.vultr.com/v2/regions for a list of plans
-    pub region: String,
+    /// Either a single region or a list of regions to try in order when a region is out of capacity
+    ///
+    /// See https://api.vultr.com/v2/regions for a list of regions
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub region: Vec<String>,
     /// See https://api.vultr.com/v2/plans for a list of plans
     #[serde(default = "vultr_default_plan")]
     pub plan: String,
+    /// Tag used to recognize the servers managed by this instance, defaults to "spire"
+    ///
+    /// Use a different tag for each dispenser instance that shares a cloud account
+    #[serde(default = "default_managed_tag")]
+    pub managed_tag: String,
+    /// Short name of the marketplace application to create servers from, needs to come with docker installed
+    ///
+    /// See https://api.vultr.com/v2/applications for a list of applications
+    #[serde(default = "vultr_default_base_image")]
+    pub base_image: String,
+    /// Id of a vpc to attach new servers to, for a private network with other servers
+    #[serde(default)]
+    pub network_id: Option<String>,
+}
+
+fn vultr_default_base_image() -> String {
+    String::from("docker")
+}
+
+fn default_managed_tag() -> String {
+    String::from("spire")
 }
 
 fn vultr_default_plan() -> String {
@@ -160,24 +872,262 @@ fn vultr_default_plan() -> String {
 pub struct DigitalOceanConfig {
     #[serde(deserialize_with = "deserialize_secret")]
     pub api_key: String,
-    /// See https://api.vultr.com/v2/regions for a list of plans
-    pub region: String,
-    /// See https://api.vultr.com/v2/plans for a list of plans
+    /// Either a single region or a list of regions to try in order when a region is out of capacity
+    ///
+    /// See https://api.digitalocean.com/v2/regions for a list of regions
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub region: Vec<String>,
+    /// See https://api.digitalocean.com/v2/sizes for a list of plans
     #[serde(default = "digital_ocean_default_plan")]
     pub plan: String,
+    /// Tag used to recognize the servers managed by this instance, defaults to "spire"
+    ///
+    /// Use a different tag for each dispenser instance that shares a cloud account
+    #[serde(default = "default_managed_tag")]
+    pub managed_tag: String,
+    /// Slug of the image to create droplets from, needs to come with docker installed
+    #[serde(default = "digital_ocean_default_image")]
+    pub base_image: String,
+    /// Id of the vpc to create droplets in, for a private network with other servers
+    ///
+    /// Droplets are created in the default vpc of the region if not set
+    #[serde(default)]
+    pub vpc_id: Option<String>,
+}
+
+fn digital_ocean_default_image() -> String {
+    String::from("docker-20-04")
 }
 
 fn digital_ocean_default_plan() -> String {
     String::from("s-2vcpu-2gb")
 }
 
+#[derive(Deserialize, Debug)]
+pub struct LinodeConfig {
+    #[serde(deserialize_with = "deserialize_secret")]
+    pub api_key: String,
+    /// See https://api.linode.com/v4/regions for a list of regions
+    pub region: String,
+    /// See https://api.linode.com/v4/linode/types for a list of plans
+    #[serde(default = "linode_default_plan")]
+    pub plan: String,
+    /// Tag used to recognize the servers managed by this instance, defaults to "spire"
+    ///
+    /// Use a different tag for each dispenser instance that shares a cloud account
+    #[serde(default = "default_managed_tag")]
+    pub managed_tag: String,
+}
+
+fn linode_default_plan() -> String {
+    String::from("g6-standard-2")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Ec2Config {
+    pub access_key_id: String,
+    #[serde(deserialize_with = "deserialize_secret")]
+    pub secret_access_key: String,
+    /// For example "eu-central-1"
+    pub region: String,
+    #[serde(default = "ec2_default_instance_type")]
+    pub instance_type: String,
+    /// AMI to launch, needs to have docker installed and allow root login over ssh
+    pub ami: String,
+    /// Launch the server as a spot instance
+    #[serde(default)]
+    pub spot: bool,
+    /// Maximum hourly price for the spot instance, defaults to the on-demand price
+    pub spot_max_price: Option<String>,
+    /// Security groups for the instance, needs to allow ssh and the game ports
+    #[serde(default)]
+    pub security_group_ids: Vec<String>,
+    /// Tag used to recognize the servers managed by this instance, defaults to "spire"
+    ///
+    /// Use a different tag for each dispenser instance that shares a cloud account
+    #[serde(default = "default_managed_tag")]
+    pub managed_tag: String,
+}
+
+fn ec2_default_instance_type() -> String {
+    String::from("t3.small")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OvhConfig {
+    /// Api endpoint for the ovh region, for example "https://ca.api.ovh.com/1.0"
+    #[serde(default = "ovh_default_endpoint")]
+    pub endpoint: String,
+    pub application_key: String,
+    #[serde(deserialize_with = "deserialize_secret")]
+    pub application_secret: String,
+    #[serde(deserialize_with = "deserialize_secret")]
+    pub consumer_key: String,
+    /// Id of the public cloud project
+    pub project_id: String,
+    /// For example "GRA11"
+    pub region: String,
+    #[serde(default = "ovh_default_flavor")]
+    pub flavor: String,
+    /// Name of the os image, docker is installed on first boot
+    #[serde(default = "ovh_default_image")]
+    pub image: String,
+    /// Tag used to recognize the servers managed by this instance, defaults to "spire"
+    ///
+    /// Ovh doesn't support tags, so the tag is used as prefix for the instance name
+    ///
+    /// Use a different tag for each dispenser instance that shares a cloud account
+    #[serde(default = "default_managed_tag")]
+    pub managed_tag: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GcpConfig {
+    /// Id of the project to create the instances in
+    pub project: String,
+    /// For example "europe-west4-a"
+    pub zone: String,
+    /// See https://cloud.google.com/compute/docs/machine-resource for a list of machine types
+    #[serde(default = "gcp_default_machine_type")]
+    pub machine_type: String,
+    /// Path to the json key of a service account with access to compute engine
+    pub service_account_key: Utf8PathBuf,
+    /// Vpc network to create the instances in, defaults to "default"
+    #[serde(default = "gcp_default_network")]
+    pub network: String,
+    /// Network tags for the instance, used to apply the firewall rules that allow ssh and the game ports
+    #[serde(default)]
+    pub network_tags: Vec<String>,
+    /// Label used to recognize the servers managed by this instance, defaults to "spire"
+    ///
+    /// Use a different tag for each dispenser instance that shares a cloud account
+    #[serde(default = "default_managed_tag")]
+    pub managed_tag: String,
+}
+
+fn gcp_default_network() -> String {
+    String::from("default")
+}
+
+fn gcp_default_machine_type() -> String {
+    String::from("e2-small")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScalewayConfig {
+    #[serde(deserialize_with = "deserialize_secret")]
+    pub secret_key: String,
+    /// Id of the project to create the servers in
+    pub project_id: String,
+    /// For example "fr-par-1"
+    pub zone: String,
+    /// See https://www.scaleway.com/en/pricing/virtual-instances/ for a list of types
+    #[serde(default = "scaleway_default_commercial_type")]
+    pub commercial_type: String,
+    /// Label of the os image, docker is installed on first boot
+    #[serde(default = "scaleway_default_image")]
+    pub image: String,
+    /// Tag used to recognize the servers managed by this instance, defaults to "spire"
+    ///
+    /// Use a different tag for each dispenser instance that shares a cloud account
+    #[serde(default = "default_managed_tag")]
+    pub managed_tag: String,
+}
+
+fn scaleway_default_commercial_type() -> String {
+    String::from("DEV1-M")
+}
+
+fn scaleway_default_image() -> String {
+    String::from("ubuntu_jammy")
+}
+
+fn ovh_default_endpoint() -> String {
+    String::from("https://eu.api.ovh.com/1.0")
+}
+
+fn ovh_default_flavor() -> String {
+    String::from("b2-7")
+}
+
+fn ovh_default_image() -> String {
+    String::from("Ubuntu 22.04")
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct DynDnsConfig {
     pub update_url: String,
+    /// The hostname to update, or the base domain when using the petname strategy
     pub hostname: String,
-    pub username: String,
+    #[serde(default)]
+    pub hostname_strategy: HostnameStrategy,
+    pub username: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_opt_secret")]
+    pub password: Option<String>,
+    /// Token to send as query parameter instead of a username and password
+    #[serde(default, deserialize_with = "deserialize_opt_secret")]
+    pub token: Option<String>,
+}
+
+impl DynDnsConfig {
+    /// Either a token or a username and password is required
+    pub fn auth(&self) -> Option<DynDnsAuth> {
+        match (&self.username, &self.password, &self.token) {
+            (Some(username), Some(password), None) => Some(DynDnsAuth::Basic {
+                username: username.clone(),
+                password: password.clone(),
+            }),
+            (None, None, Some(token)) => Some(DynDnsAuth::Token(token.clone())),
+            _ => None,
+        }
+    }
+
+    /// The hostname to point to the server
+    ///
+    /// With the petname strategy the name is derived from the server id, so it stays the same for the lifetime of the server
+    pub fn hostname_for(&self, server: &Server) -> String {
+        match self.hostname_strategy {
+            HostnameStrategy::Fixed => self.hostname.clone(),
+            HostnameStrategy::Petname => {
+                let seed = Sha256::digest(server.id.as_bytes());
+                let mut rng = StdRng::from_seed(seed.into());
+                let name = Petnames::default()
+                    .generate(&mut rng, 2, "-")
+                    .expect("default word lists are not empty");
+                format!("{}.{}", name, self.hostname)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HostnameStrategy {
+    /// Always update the configured hostname
+    #[default]
+    Fixed,
+    /// Register a random subdomain like `happy-otter.example.com` under the configured hostname for every server
+    Petname,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NotificationConfig {
+    #[serde(default, deserialize_with = "deserialize_opt_secret")]
+    pub discord_webhook_url: Option<String>,
+    /// Url to post the connect details to once a server is ready
+    #[serde(default, deserialize_with = "deserialize_opt_secret")]
+    pub ready_webhook_url: Option<String>,
+    pub matrix: Option<MatrixConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MatrixConfig {
+    /// Base url of the homeserver, like `https://matrix.org`
+    pub homeserver: String,
+    /// Id of the room to send the notifications to, like `!abcdef:matrix.org`
+    pub room_id: String,
     #[serde(deserialize_with = "deserialize_secret")]
-    pub password: String,
+    pub access_token: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -186,9 +1136,759 @@ pub struct ScheduleConfig {
     pub stop: String,
     #[serde(default = "default_stop_grace_time")]
     pub stop_grace_time: u64,
+    /// Seconds before the end of the grace time to start warning players about the shutdown
+    #[serde(default = "default_stop_warning_time")]
+    pub stop_warning_time: u64,
+    /// Seconds between shutdown warnings
+    #[serde(default = "default_stop_warning_interval")]
+    pub stop_warning_interval: u64,
+    /// Seconds players can extend the grace time with by voting to extend the map, once per shutdown, 0 disables the vote
+    #[serde(default)]
+    pub stop_extension: u64,
+    /// Timezone the start and stop schedules are in, defaults to UTC
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+    /// Seconds between checks of the schedule and the running servers
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval_secs: u64,
+    /// Number of consecutive checks that need to find the server empty before it's stopped
+    #[serde(default = "default_empty_checks")]
+    pub empty_checks: u32,
+}
+
+/// Environment variable to override the start schedule with, for testing
+pub const START_SCHEDULE_ENV: &str = "DISPENSER_START_SCHEDULE";
+/// Environment variable to override the stop schedule with, for testing
+pub const STOP_SCHEDULE_ENV: &str = "DISPENSER_STOP_SCHEDULE";
+
+impl ScheduleConfig {
+    /// Replace the configured schedules, used to test the daemon with a faster schedule
+    pub fn override_schedules(&mut self, start: Option<String>, stop: Option<String>) {
+        if let Some(start) = start.filter(|start| !start.is_empty()) {
+            warn!(
+                configured = %self.start,
+                schedule = %start,
+                "Start schedule is overridden by {}, don't leave this set in production",
+                START_SCHEDULE_ENV
+            );
+            self.start = start;
+        }
+        if let Some(stop) = stop.filter(|stop| !stop.is_empty()) {
+            warn!(
+                configured = %self.stop,
+                schedule = %stop,
+                "Stop schedule is overridden by {}, don't leave this set in production",
+                STOP_SCHEDULE_ENV
+            );
+            self.stop = stop;
+        }
+    }
+
+    /// Parse the start and stop schedules
+    pub fn schedules(&self) -> Result<(Schedule, Schedule), ConfigError> {
+        let start = Schedule::from_str(&self.start)
+            .map_err(|e| ConfigError::InvalidSchedule("start", e))?;
+        let stop =
+            Schedule::from_str(&self.stop).map_err(|e| ConfigError::InvalidSchedule("stop", e))?;
+        Ok((start, stop))
+    }
+
+    /// Next time the schedule triggers after the given time, evaluated in the configured timezone
+    pub fn next_after(&self, schedule: &Schedule, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        schedule
+            .after(&after.with_timezone(&self.timezone))
+            .next()
+            .map(|time| time.with_timezone(&Utc))
+    }
+
+    /// Last time the schedule triggers after `after` and up to and including `until`, evaluated in the configured timezone
+    pub fn last_between(
+        &self,
+        schedule: &Schedule,
+        after: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        schedule
+            .after(&after.with_timezone(&self.timezone))
+            .map(|time| time.with_timezone(&Utc))
+            .take_while(|time| *time <= until)
+            .last()
+    }
+
+    /// Time to wait before the next check, with up to 10% of jitter so multiple deployments don't line up
+    pub fn poll_delay(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs).mul_f64(thread_rng().gen_range(0.9..=1.1))
+    }
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+/// 1m
+fn default_poll_interval() -> u64 {
+    60
+}
+
+fn default_empty_checks() -> u32 {
+    2
 }
 
 /// 1h
 fn default_stop_grace_time() -> u64 {
     3 * 60 * 60
 }
+
+/// 10m
+fn default_stop_warning_time() -> u64 {
+    10 * 60
+}
+
+/// 2m
+fn default_stop_warning_interval() -> u64 {
+    2 * 60
+}
+
+/// Algorithms the ssh client is allowed to negotiate, in order of preference
+///
+/// By default all algorithms supported by the ssh client are allowed
+#[derive(Deserialize, Debug, Default)]
+pub struct SshConfig {
+    pub host_key_algorithms: Option<Vec<String>>,
+    pub kex_algorithms: Option<Vec<String>>,
+    pub ciphers: Option<Vec<String>>,
+}
+
+impl SshConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let defaults = thrussh::Preferred::DEFAULT;
+        select(
+            "host key algorithm",
+            defaults.key,
+            &self.host_key_algorithms,
+        )?;
+        select("key exchange algorithm", defaults.kex, &self.kex_algorithms)?;
+        select("cipher", defaults.cipher, &self.ciphers)?;
+        Ok(())
+    }
+
+    /// The algorithm preferences for the ssh client
+    pub fn preferred(&self) -> Result<thrussh::Preferred, ConfigError> {
+        let defaults = thrussh::Preferred::DEFAULT;
+        Ok(thrussh::Preferred {
+            key: leak_algorithms(select(
+                "host key algorithm",
+                defaults.key,
+                &self.host_key_algorithms,
+            )?),
+            kex: leak_algorithms(select(
+                "key exchange algorithm",
+                defaults.kex,
+                &self.kex_algorithms,
+            )?),
+            cipher: leak_algorithms(select("cipher", defaults.cipher, &self.ciphers)?),
+            ..defaults
+        })
+    }
+}
+
+/// Pick the configured algorithms from the supported ones, keeping the configured order
+fn select<N: AsRef<str> + Copy>(
+    kind: &'static str,
+    supported: &[N],
+    configured: &Option<Vec<String>>,
+) -> Result<Vec<N>, ConfigError> {
+    let Some(configured) = configured else {
+        return Ok(supported.to_vec());
+    };
+    if configured.is_empty() {
+        return Err(ConfigError::NoSshAlgorithms(kind));
+    }
+    configured
+        .iter()
+        .map(|name| {
+            supported
+                .iter()
+                .find(|algorithm| algorithm.as_ref() == name)
+                .copied()
+                .ok_or_else(|| {
+                    let supported = supported
+                        .iter()
+                        .map(|algorithm| algorithm.as_ref())
+                        .collect::<Vec<_>>();
+                    ConfigError::UnsupportedSshAlgorithm(kind, name.clone(), supported.join(", "))
+                })
+        })
+        .collect()
+}
+
+/// The ssh client needs static algorithm lists, this is only done once on startup
+fn leak_algorithms<N>(algorithms: Vec<N>) -> &'static [N] {
+    Box::leak(algorithms.into_boxed_slice())
+}
+
+/// Palantir metrics agent installed on the servers
+#[derive(Deserialize, Debug)]
+pub struct PalantirConfig {
+    /// Whether to install palantir, defaults to true
+    #[serde(default = "default_palantir_enabled")]
+    pub enabled: bool,
+    /// Palantir release to install, defaults to 1.1.0
+    #[serde(default = "default_palantir_version")]
+    pub version: String,
+    /// Port for palantir to serve metrics on, defaults to 5665
+    #[serde(default = "default_palantir_port")]
+    pub port: u16,
+}
+
+impl Default for PalantirConfig {
+    fn default() -> Self {
+        PalantirConfig {
+            enabled: default_palantir_enabled(),
+            version: default_palantir_version(),
+            port: default_palantir_port(),
+        }
+    }
+}
+
+fn default_palantir_port() -> u16 {
+    5665
+}
+
+fn default_palantir_enabled() -> bool {
+    true
+}
+
+fn default_palantir_version() -> String {
+    String::from("1.1.0")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RetryConfig {
+    /// Number of attempts to connect to the server over ssh, defaults to 6
+    #[serde(default = "default_ssh_connect_attempts")]
+    pub ssh_connect_attempts: u32,
+    /// Delay between ssh connection attempts in seconds, defaults to 5
+    #[serde(default = "default_ssh_connect_delay_secs")]
+    pub ssh_connect_delay_secs: u64,
+    /// Number of attempts to pull the docker image, defaults to 6
+    #[serde(default = "default_docker_pull_attempts")]
+    pub docker_pull_attempts: u32,
+    /// Time in seconds to wait for the ssh server to come up after booting, defaults to 10 minutes
+    #[serde(default = "default_boot_timeout_secs")]
+    pub boot_timeout_secs: u64,
+    /// Number of attempts for cloud api requests that fail with a transient error, defaults to 4
+    #[serde(default = "default_cloud_request_attempts")]
+    pub cloud_request_attempts: u32,
+    /// Time in seconds to wait for a newly created server to get an ip address, defaults to 5 minutes
+    #[serde(default = "default_ip_timeout_secs")]
+    pub ip_timeout_secs: u64,
+    /// Time in seconds to wait for an rcon connection to be established, defaults to 5
+    #[serde(default = "default_rcon_timeout_secs")]
+    pub rcon_timeout_secs: u64,
+    /// Time in seconds to wait for the game server to accept rcon connections after setup, defaults to 5 minutes
+    #[serde(default = "default_ready_timeout_secs")]
+    pub ready_timeout_secs: u64,
+    /// Number of times to try creating and setting up a new server, defaults to 1
+    #[serde(default = "default_spawn_attempts")]
+    pub spawn_attempts: u32,
+    /// Delay in seconds before retrying to start a server, doubled for every following attempt, defaults to 30
+    #[serde(default = "default_spawn_retry_delay_secs")]
+    pub spawn_retry_delay_secs: u64,
+}
+
+impl RetryConfig {
+    pub fn rcon_timeout(&self) -> Duration {
+        Duration::from_secs(self.rcon_timeout_secs)
+    }
+
+    /// Backoff before the next start attempt after `attempt` attempts failed
+    pub fn spawn_retry_delay(&self, attempt: u32) -> Duration {
+        Duration::from_secs(
+            self.spawn_retry_delay_secs
+                .saturating_mul(1 << (attempt - 1).min(6)),
+        )
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            ssh_connect_attempts: default_ssh_connect_attempts(),
+            ssh_connect_delay_secs: default_ssh_connect_delay_secs(),
+            docker_pull_attempts: default_docker_pull_attempts(),
+            boot_timeout_secs: default_boot_timeout_secs(),
+            cloud_request_attempts: default_cloud_request_attempts(),
+            ip_timeout_secs: default_ip_timeout_secs(),
+            rcon_timeout_secs: default_rcon_timeout_secs(),
+            ready_timeout_secs: default_ready_timeout_secs(),
+            spawn_attempts: default_spawn_attempts(),
+            spawn_retry_delay_secs: default_spawn_retry_delay_secs(),
+        }
+    }
+}
+
+fn default_spawn_attempts() -> u32 {
+    1
+}
+
+fn default_spawn_retry_delay_secs() -> u64 {
+    30
+}
+
+fn default_ip_timeout_secs() -> u64 {
+    300
+}
+
+fn default_rcon_timeout_secs() -> u64 {
+    5
+}
+
+/// 5m
+fn default_ready_timeout_secs() -> u64 {
+    300
+}
+
+fn default_ssh_connect_attempts() -> u32 {
+    6
+}
+
+fn default_ssh_connect_delay_secs() -> u64 {
+    5
+}
+
+fn default_docker_pull_attempts() -> u32 {
+    6
+}
+
+/// 10m
+fn default_boot_timeout_secs() -> u64 {
+    10 * 60
+}
+
+fn default_cloud_request_attempts() -> u32 {
+    4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(extra: &str, start: &str) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(&format!(
+            r#"
+            {extra}
+
+            [server]
+            rcon = "rcon"
+            password = "password"
+            demostf_key = "demostf"
+            logstf_key = "logstf"
+
+            [schedule]
+            start = "{start}"
+            stop = "0 0 23 * * Sun"
+            "#
+        ))
+        .map_err(TomlError::from)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_docker_limits() {
+        let mut config = parse("", "0 0 17 * * Sun").unwrap();
+        for valid in ["2g", "512m", "1073741824", "256M"] {
+            config.servers[0].docker_memory = Some(valid.into());
+            assert!(config.validate().is_ok(), "{}", valid);
+        }
+        for invalid in ["", "g", "2gb", "1.5g", "-1g"] {
+            config.servers[0].docker_memory = Some(invalid.into());
+            assert!(
+                matches!(config.validate(), Err(ConfigError::InvalidDockerMemory(_))),
+                "{}",
+                invalid
+            );
+        }
+
+        config.servers[0].docker_memory = None;
+        config.servers[0].docker_cpus = Some(1.5);
+        assert!(config.validate().is_ok());
+        config.servers[0].docker_cpus = Some(0.0);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidDockerCpus(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_port() {
+        let mut config = parse("", "0 0 17 * * Sun").unwrap();
+        // the default 27015 mapping already forwards both protocols
+        config.servers[0]
+            .ports
+            .push(PortMapping::new(27015, 27016, PortProtocol::Udp));
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::DuplicatePort(27015, PortProtocol::Udp))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_server() {
+        let config: Config = toml::from_str(
+            r#"
+            [[server]]
+            name = "Spire"
+            rcon = "rcon"
+            password = "password"
+            demostf_key = "demostf"
+            logstf_key = "logstf"
+
+            [[server]]
+            name = "spire!"
+            rcon = "rcon"
+            password = "password"
+            demostf_key = "demostf"
+            logstf_key = "logstf"
+
+            [schedule]
+            start = "0 0 17 * * Sun"
+            stop = "0 0 23 * * Sun"
+            "#,
+        )
+        .unwrap();
+        // both names result in the same server label
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::DuplicateServer(name)) if name == "spire!"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_env_name() {
+        let mut config = parse("", "0 0 17 * * Sun").unwrap();
+        config.servers[0].env.insert("SV_PURE_2".into(), "1".into());
+        assert!(config.validate().is_ok());
+        for invalid in ["", "2FORT", "SV-PURE", "A B"] {
+            config.servers[0].env.clear();
+            config.servers[0].env.insert(invalid.into(), "1".into());
+            assert!(
+                matches!(config.validate(), Err(ConfigError::InvalidEnvName(name)) if name == invalid),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_schedule() {
+        assert!(parse("", "0 0 17 * * Sun").is_ok());
+        assert!(matches!(
+            parse("", "0 0 17 * * Sunday-ish"),
+            Err(ConfigError::InvalidSchedule("start", _))
+        ));
+    }
+
+    #[test]
+    fn test_override_schedules() {
+        let mut config = parse("", "0 0 17 * * Sun").unwrap();
+        config
+            .schedule
+            .override_schedules(Some("0 * * * * *".into()), Some(String::new()));
+        assert_eq!(config.schedule.start, "0 * * * * *");
+        assert_eq!(config.schedule.stop, "0 0 23 * * Sun");
+
+        config
+            .schedule
+            .override_schedules(None, Some("every minute".into()));
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSchedule("stop", _))
+        ));
+    }
+
+    #[test]
+    fn test_ssh_algorithms() {
+        let config = parse(
+            r#"
+            [ssh]
+            host_key_algorithms = ["ssh-ed25519"]
+            "#,
+            "0 0 17 * * Sun",
+        )
+        .unwrap();
+        let preferred = config.ssh.preferred().unwrap();
+        assert_eq!(preferred.key, &[thrussh_keys::key::ED25519]);
+        assert_eq!(preferred.kex, thrussh::Preferred::DEFAULT.kex);
+
+        assert!(matches!(
+            parse(
+                r#"
+                [ssh]
+                ciphers = ["aes128-cbc"]
+                "#,
+                "0 0 17 * * Sun",
+            ),
+            Err(ConfigError::UnsupportedSshAlgorithm("cipher", name, _)) if name == "aes128-cbc"
+        ));
+        assert!(matches!(
+            parse("[ssh]\nkex_algorithms = []", "0 0 17 * * Sun"),
+            Err(ConfigError::NoSshAlgorithms(_))
+        ));
+    }
+
+    #[test]
+    fn test_gcp_read_only_image() {
+        let gcp = r#"
+            [gcp]
+            project = "xxx"
+            zone = "europe-west4-a"
+            service_account_key = "/path/to/key.json"
+            "#;
+        assert!(matches!(
+            parse(gcp, "0 0 17 * * Sun"),
+            Err(ConfigError::ReadOnlyImage("Palantir", _))
+        ));
+
+        assert!(matches!(
+            parse(
+                &format!("{gcp}\n[palantir]\nenabled = false"),
+                "0 0 17 * * Sun"
+            ),
+            Err(ConfigError::ReadOnlyImage("Swap", _))
+        ));
+    }
+
+    #[test]
+    fn test_multiple_providers() {
+        let config = parse(
+            r#"
+            [vultr]
+            api_key = "xxx"
+            region = "ams"
+
+            [digital_ocean]
+            api_key = "xxx"
+            region = "ams3"
+            "#,
+            "0 0 17 * * Sun",
+        )
+        .unwrap();
+        assert!(matches!(
+            config.cloud(),
+            Err(ConfigError::MultipleProviders)
+        ));
+    }
+
+    #[test]
+    fn test_dyndns_auth() {
+        let config = parse(
+            r#"
+            [dyndns]
+            update_url = "https://www.duckdns.org/nic/update"
+            hostname = "example.duckdns.org"
+            token = "xxx"
+            "#,
+            "0 0 17 * * Sun",
+        )
+        .unwrap();
+        assert!(matches!(
+            config.dyndns.unwrap().auth(),
+            Some(DynDnsAuth::Token(token)) if token == "xxx"
+        ));
+
+        assert!(matches!(
+            parse(
+                r#"
+                [dyndns]
+                update_url = "https://update.example.com"
+                hostname = "example.com"
+                username = "user"
+                "#,
+                "0 0 17 * * Sun",
+            ),
+            Err(ConfigError::DynDnsAuth)
+        ));
+    }
+
+    #[test]
+    fn test_image_per_mode() {
+        let server = |extra: &str| {
+            toml::from_str::<ServerConfig>(&format!(
+                r#"
+                rcon = "rcon"
+                password = "password"
+                demostf_key = "demostf"
+                logstf_key = "logstf"
+                {extra}
+                "#
+            ))
+            .unwrap()
+        };
+        assert_eq!(server("").image(), DEFAULT_IMAGE);
+        assert_eq!(server(r#"image = "custom/image""#).image(), "custom/image");
+
+        let images = r#"image = { "6v6" = "custom/sixes", "9v9" = "custom/hl" }"#;
+        assert_eq!(server(images).image(), "custom/sixes");
+        assert_eq!(
+            server(&format!("config_mode = \"9v9\"\n{}", images)).image(),
+            "custom/hl"
+        );
+        assert_eq!(
+            server(&format!("config_mode = \"ultiduo\"\n{}", images)).image(),
+            DEFAULT_IMAGE
+        );
+    }
+
+    #[test]
+    fn test_extra_cfg_per_mode() {
+        let server = |extra: &str| {
+            toml::from_str::<ServerConfig>(&format!(
+                r#"
+                rcon = "rcon"
+                password = "password"
+                demostf_key = "demostf"
+                logstf_key = "logstf"
+                {extra}
+                "#
+            ))
+            .unwrap()
+        };
+        assert_eq!(server("").extra_cfg(), "");
+        assert_eq!(
+            server(r#"extra_cfg = "sv_cheats 0""#).extra_cfg(),
+            "sv_cheats 0"
+        );
+
+        let layered = r#"extra_cfg = { base = "sv_cheats 0 // base", modes = { "9v9" = "mp_tournament_whitelist hl.txt" } }"#;
+        assert_eq!(server(layered).extra_cfg(), "sv_cheats 0 // base");
+        assert_eq!(
+            server(&format!("config_mode = \"9v9\"\n{}", layered)).extra_cfg(),
+            "sv_cheats 0 // base\nmp_tournament_whitelist hl.txt"
+        );
+    }
+
+    #[test]
+    fn test_proxy_url() {
+        assert!(parse(
+            r#"proxy_url = "http://proxy.example.com:3128""#,
+            "0 0 17 * * Sun"
+        )
+        .is_ok());
+        assert!(matches!(
+            parse(r#"proxy_url = "not a url""#, "0 0 17 * * Sun"),
+            Err(ConfigError::Proxy(_))
+        ));
+    }
+
+    #[test]
+    fn test_dyndns_petname_hostname() {
+        let config = parse(
+            r#"
+            [dyndns]
+            update_url = "https://update.example.com"
+            hostname = "pug.example.com"
+            hostname_strategy = "petname"
+            token = "xxx"
+            "#,
+            "0 0 17 * * Sun",
+        )
+        .unwrap();
+        let dyndns = config.dyndns.unwrap();
+        let server = crate::cloud::mock::MockCloud::server("1234");
+        let hostname = dyndns.hostname_for(&server);
+        assert!(hostname.ends_with(".pug.example.com"));
+        assert_eq!(hostname.split('.').next().unwrap().split('-').count(), 2);
+        assert_eq!(hostname, dyndns.hostname_for(&server));
+        assert_ne!(
+            hostname,
+            dyndns.hostname_for(&crate::cloud::mock::MockCloud::server("5678"))
+        );
+    }
+
+    #[test]
+    fn test_region_list() {
+        let config = parse(
+            r#"
+            [vultr]
+            api_key = "xxx"
+            region = ["ams", "fra"]
+            "#,
+            "0 0 17 * * Sun",
+        )
+        .unwrap();
+        assert_eq!(config.vultr.unwrap().region, vec!["ams", "fra"]);
+
+        let config = parse(
+            r#"
+            [digital_ocean]
+            api_key = "xxx"
+            region = "ams3"
+            "#,
+            "0 0 17 * * Sun",
+        )
+        .unwrap();
+        assert_eq!(config.digital_ocean.unwrap().region, vec!["ams3"]);
+    }
+
+    #[test]
+    fn test_mode_plans() {
+        let config: Config = toml::from_str(
+            r#"
+            mode_plans = { highlander = "vc2-2c-4gb" }
+
+            [[server]]
+            name = "Sixes"
+            rcon = "rcon"
+            password = "password"
+            demostf_key = "demostf"
+            logstf_key = "logstf"
+
+            [[server]]
+            name = "Highlander"
+            rcon = "rcon"
+            password = "password"
+            demostf_key = "demostf"
+            logstf_key = "logstf"
+            config_mode = "highlander"
+
+            [schedule]
+            start = "0 0 17 * * Sun"
+            stop = "0 0 23 * * Sun"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.plan_for(&config.servers[0]), None);
+        assert_eq!(config.plan_for(&config.servers[1]), Some("vc2-2c-4gb"));
+    }
+
+    #[test]
+    fn test_no_provider() {
+        let config = parse("", "0 0 17 * * Sun").unwrap();
+        assert!(matches!(config.cloud(), Err(ConfigError::NoProvider)));
+    }
+
+    #[test]
+    fn test_schedule_timezone() {
+        let config: ScheduleConfig = toml::from_str(
+            r#"
+            start = "0 0 17 * * *"
+            stop = "0 0 23 * * *"
+            timezone = "Europe/Amsterdam"
+            "#,
+        )
+        .unwrap();
+        let schedule = Schedule::from_str(&config.start).unwrap();
+        let winter = "2024-01-15T00:00:00Z".parse().unwrap();
+        let summer = "2024-07-15T00:00:00Z".parse().unwrap();
+        assert_eq!(
+            config.next_after(&schedule, winter).unwrap().to_rfc3339(),
+            "2024-01-15T16:00:00+00:00"
+        );
+        assert_eq!(
+            config.next_after(&schedule, summer).unwrap().to_rfc3339(),
+            "2024-07-15T15:00:00+00:00"
+        );
+    }
+}
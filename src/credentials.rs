@@ -0,0 +1,201 @@
+use crate::cloud::{CreatedAuth, Server};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, remove_file, rename, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CredentialsError {
+    #[error("Failed to access credentials file {0}: {1}")]
+    Io(Utf8PathBuf, std::io::Error),
+    #[error("Invalid credentials file {0}: {1}")]
+    Json(Utf8PathBuf, serde_json::Error),
+    #[error("Failed to encode or decode private key: {0}")]
+    Key(#[from] thrussh_keys::Error),
+}
+
+/// Credentials for the running servers, keyed by the server id
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    #[serde(default)]
+    pub servers: BTreeMap<String, ServerCredentials>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerCredentials {
+    pub name: String,
+    pub ip: IpAddr,
+    pub password: Option<String>,
+    /// Private key in pkcs8 pem format
+    pub private_key: Option<String>,
+    /// Sha256 fingerprint of the host key seen while setting up the server
+    #[serde(default)]
+    pub host_key: Option<String>,
+}
+
+impl ServerCredentials {
+    pub fn new(name: &str, server: &Server, auth: &CreatedAuth) -> Result<Self, CredentialsError> {
+        let (password, key) = match auth {
+            CreatedAuth::Password(password) => (Some(password.clone()), None),
+            CreatedAuth::Ssh(key) => (None, Some(key)),
+            CreatedAuth::PasswordOrKey(password, key) => (Some(password.clone()), Some(key)),
+        };
+        let private_key = key
+            .map(|key| {
+                let mut pem = Vec::new();
+                thrussh_keys::encode_pkcs8_pem(key, &mut pem)?;
+                Ok::<_, CredentialsError>(String::from_utf8_lossy(&pem).into_owned())
+            })
+            .transpose()?;
+        Ok(ServerCredentials {
+            name: name.into(),
+            ip: server.ip,
+            password,
+            private_key,
+            host_key: None,
+        })
+    }
+
+    /// Authentication to log in to the server with, using the private key if one was saved
+    pub fn auth(&self) -> Result<Option<CreatedAuth>, CredentialsError> {
+        let key = self
+            .private_key
+            .as_deref()
+            .map(|pem| thrussh_keys::decode_secret_key(pem, None).map(Arc::new))
+            .transpose()?;
+        Ok(match (self.password.clone(), key) {
+            (Some(password), Some(key)) => Some(CreatedAuth::PasswordOrKey(password, key)),
+            (None, Some(key)) => Some(CreatedAuth::Ssh(key)),
+            (Some(password), None) => Some(CreatedAuth::Password(password)),
+            (None, None) => None,
+        })
+    }
+}
+
+impl Credentials {
+    /// Add the credentials for a newly created server to the file
+    pub fn add(
+        path: &Utf8Path,
+        id: &str,
+        credentials: ServerCredentials,
+    ) -> Result<(), CredentialsError> {
+        let mut stored = Credentials::load(path)?;
+        stored.servers.insert(id.into(), credentials);
+        stored.save(path)
+    }
+
+    /// Remember the host key of a server, so later connections can verify it
+    pub fn set_host_key(
+        path: &Utf8Path,
+        id: &str,
+        host_key: String,
+    ) -> Result<(), CredentialsError> {
+        let mut stored = Credentials::load(path)?;
+        match stored.servers.get_mut(id) {
+            Some(credentials) => credentials.host_key = Some(host_key),
+            None => return Ok(()),
+        }
+        stored.save(path)
+    }
+
+    /// Get the saved credentials for a server
+    pub fn get(path: &Utf8Path, id: &str) -> Result<Option<ServerCredentials>, CredentialsError> {
+        Ok(Credentials::load(path)?.servers.remove(id))
+    }
+
+    /// Remove the credentials for a destroyed server, the file is removed once no servers are left
+    pub fn remove(path: &Utf8Path, id: &str) -> Result<(), CredentialsError> {
+        let mut stored = Credentials::load(path)?;
+        if stored.servers.remove(id).is_none() {
+            return Ok(());
+        }
+        if stored.servers.is_empty() {
+            remove_file(path).map_err(|e| CredentialsError::Io(path.into(), e))
+        } else {
+            stored.save(path)
+        }
+    }
+
+    fn load(path: &Utf8Path) -> Result<Self, CredentialsError> {
+        match read_to_string(path) {
+            Ok(content) => {
+                serde_json::from_str(&content).map_err(|e| CredentialsError::Json(path.into(), e))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Credentials::default()),
+            Err(e) => Err(CredentialsError::Io(path.into(), e)),
+        }
+    }
+
+    /// The file is only readable by the owner and replaced atomically
+    fn save(&self, path: &Utf8Path) -> Result<(), CredentialsError> {
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| CredentialsError::Json(path.into(), e))?;
+        let tmp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .map_err(|e| CredentialsError::Io(tmp_path.clone(), e))?;
+        file.write_all(content.as_bytes())
+            .map_err(|e| CredentialsError::Io(tmp_path.clone(), e))?;
+        rename(&tmp_path, path).map_err(|e| CredentialsError::Io(path.into(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_add_remove() {
+        let path = Utf8PathBuf::from_path_buf(temp_dir())
+            .unwrap()
+            .join(format!("dispenser-credentials-{}.json", std::process::id()));
+        let server = crate::cloud::mock::MockCloud::server("1234");
+
+        Credentials::add(
+            &path,
+            &server.id,
+            ServerCredentials::new("Spire", &server, &CreatedAuth::Password("pass".into()))
+                .unwrap(),
+        )
+        .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let stored = Credentials::load(&path).unwrap();
+        assert_eq!(stored.servers["1234"].password.as_deref(), Some("pass"));
+
+        Credentials::set_host_key(&path, &server.id, "abcd".into()).unwrap();
+        let stored = Credentials::get(&path, &server.id).unwrap().unwrap();
+        assert_eq!(stored.host_key.as_deref(), Some("abcd"));
+
+        Credentials::remove(&path, &server.id).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_key_roundtrip() {
+        let server = crate::cloud::mock::MockCloud::server("1234");
+        let key = Arc::new(thrussh_keys::key::KeyPair::generate_ed25519().unwrap());
+        let credentials =
+            ServerCredentials::new("Spire", &server, &CreatedAuth::Ssh(key.clone())).unwrap();
+        match credentials.auth().unwrap() {
+            Some(CreatedAuth::Ssh(decoded)) => {
+                assert_eq!(
+                    decoded.clone_public_key().fingerprint(),
+                    key.clone_public_key().fingerprint()
+                );
+            }
+            auth => panic!("unexpected auth {:?}", auth),
+        }
+    }
+}
@@ -1,7 +1,9 @@
+use crate::http;
 use reqwest::{Client, StatusCode};
 use serde::Serialize;
 use std::net::IpAddr;
 use thiserror::Error;
+use tokio::net::lookup_host;
 
 pub type Result<T, E = DynDnsError> = std::result::Result<T, E>;
 
@@ -19,6 +21,8 @@ pub enum DynDnsError {
     InvalidHostname,
     #[error("Rate limited")]
     Abuse,
+    #[error("Failed to resolve hostname: {0}")]
+    Resolve(std::io::Error),
 }
 
 impl DynDnsError {
@@ -32,35 +36,47 @@ impl DynDnsError {
 
 /// Intentionally opaque error
 #[derive(Debug, Error)]
-#[error("{0}")]
+#[error("{}", http::describe_error(.0))]
 pub struct NetworkError(reqwest::Error);
 
+/// How to authenticate with the dyndns provider
+#[derive(Clone, Debug)]
+pub enum DynDnsAuth {
+    /// Http basic auth, used by most dyndns2 providers
+    Basic { username: String, password: String },
+    /// Token send as `token` query parameter, used by providers like duckdns
+    Token(String),
+}
+
+/// Cheap to clone, clones share the underlying connection pool
+#[derive(Clone)]
 pub struct DynDnsClient {
     client: Client,
     update_url: String,
-    username: String,
-    password: String,
+    auth: DynDnsAuth,
 }
 
 impl DynDnsClient {
-    pub fn new(update_url: String, username: String, password: String) -> Self {
+    pub fn new(update_url: String, auth: DynDnsAuth) -> Self {
         DynDnsClient {
-            client: Client::new(),
+            client: http::client(),
             update_url,
-            username,
-            password,
+            auth,
         }
     }
 
     pub async fn update(&self, hostname: &str, ip: IpAddr) -> Result<()> {
-        let response = self
+        let request = self
             .client
             .get(&self.update_url)
-            .basic_auth(&self.username, Some(&self.password))
-            .query(&DynDnsParams { hostname, ip })
-            .send()
-            .await
-            .map_err(NetworkError)?;
+            .query(&DynDnsParams { hostname, ip });
+        let request = match &self.auth {
+            DynDnsAuth::Basic { username, password } => {
+                request.basic_auth(username, Some(password))
+            }
+            DynDnsAuth::Token(token) => request.query(&[("token", token)]),
+        };
+        let response = request.send().await.map_err(NetworkError)?;
 
         let status = response.status();
         DynDnsError::from_status_code(status)?;
@@ -75,6 +91,17 @@ impl DynDnsClient {
             _ => Err(DynDnsError::InvalidResponse(text)),
         }
     }
+
+    /// Check the credentials by re-submitting the address the hostname currently resolves to
+    pub async fn check(&self, hostname: &str) -> Result<()> {
+        let ip = lookup_host((hostname, 0))
+            .await
+            .map_err(DynDnsError::Resolve)?
+            .next()
+            .ok_or(DynDnsError::InvalidHostname)?
+            .ip();
+        self.update(hostname, ip).await
+    }
 }
 
 #[derive(Serialize)]
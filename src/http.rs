@@ -0,0 +1,121 @@
+use reqwest::{Client, Proxy};
+use std::error::Error as _;
+use std::sync::OnceLock;
+use thiserror::Error;
+
+/// Intentionally opaque error
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct ProxyError(reqwest::Error);
+
+/// Proxy for all outbound requests, set once on startup
+static PROXY: OnceLock<Proxy> = OnceLock::new();
+
+pub fn parse_proxy(url: &str) -> Result<Proxy, ProxyError> {
+    Proxy::all(url).map_err(ProxyError)
+}
+
+/// Route all outbound requests trough a proxy, needs to be set before any client is created
+pub fn set_proxy(proxy: Proxy) {
+    if PROXY.set(proxy).is_err() {
+        panic!("proxy can only be set once");
+    }
+}
+
+/// Build a http client for outbound requests, using the configured proxy
+///
+/// Without a configured proxy, the `HTTPS_PROXY`, `HTTP_PROXY` and `ALL_PROXY` environment variables are used
+pub fn client() -> Client {
+    let mut builder = Client::builder();
+    if let Some(proxy) = PROXY.get() {
+        builder = builder.proxy(proxy.clone());
+    }
+    builder.build().expect("failed to initialize tls backend")
+}
+
+/// Describe a failed request, distinguishing timeouts, dns and connection failures
+///
+/// Only the host is included, since the full url can contain credentials in the query
+pub fn describe_error(e: &reqwest::Error) -> String {
+    let host = e
+        .url()
+        .and_then(|url| url.host_str())
+        .unwrap_or("unknown host");
+    let cause = root_cause(e);
+    if e.is_timeout() {
+        format!("request to {} timed out", host)
+    } else if e.is_connect() && is_dns_error(e) {
+        format!("failed to resolve {}: {}", host, cause)
+    } else if e.is_connect() {
+        format!("failed to connect to {}: {}", host, cause)
+    } else if e.is_decode() {
+        format!("invalid response body from {}: {}", host, cause)
+    } else {
+        format!("request to {} failed: {}", host, cause)
+    }
+}
+
+/// The innermost error, which usually has the most specific message
+fn root_cause(e: &reqwest::Error) -> String {
+    let mut cause: &dyn std::error::Error = e;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+/// Whether resolving the host failed, reqwest doesn't expose this so the error chain is checked for the resolver error
+fn is_dns_error(e: &reqwest::Error) -> bool {
+    let mut source = e.source();
+    while let Some(error) = source {
+        if error.to_string().starts_with("dns error") {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_describe_connect_error() {
+        // bind and drop a listener to get a port nothing is listening on
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let error = Client::new()
+            .get(format!("http://127.0.0.1:{}/?token=secret", port))
+            .send()
+            .await
+            .unwrap_err();
+        let description = describe_error(&error);
+        assert!(
+            description.starts_with("failed to connect to 127.0.0.1: "),
+            "{}",
+            description
+        );
+        assert!(!description.contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_describe_timeout() {
+        // accepts connections but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let error = Client::new()
+            .get(format!("http://127.0.0.1:{}/", port))
+            .timeout(Duration::from_millis(100))
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(describe_error(&error), "request to 127.0.0.1 timed out");
+    }
+}
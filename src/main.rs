@@ -1,30 +1,57 @@
 extern crate core;
 
-use crate::cloud::{Cloud, CloudError, CreatedAuth, Server};
-use crate::config::{Config, ConfigError, DynDnsConfig, ServerConfig};
+use crate::cloud::dry_run::DryRunCloud;
+use crate::cloud::{Cloud, CloudError, Created, CreatedAuth, Server, SpawnOptions};
+use crate::config::{
+    Config, ConfigError, HostKeyVerification, HostnameStrategy, NetworkMode, PalantirConfig,
+    PortMapping, RetryConfig, ServerConfig,
+};
+use crate::credentials::{Credentials, CredentialsError, ServerCredentials};
 use crate::dns::{DynDnsClient, DynDnsError};
-use crate::rcon::Rcon;
-use crate::ssh::SshError;
-use chrono::Utc;
-use clap::{Parser, Subcommand};
+use crate::metrics::METRICS;
+use crate::progress::ProgressEvent;
+use crate::rcon::{find_player, Rcon};
+use crate::schedule::{ScheduleTracker, Window};
+use crate::ssh::{shell_quote, HostKeyCheck, SshError};
+use crate::state::{DaemonState, ServerState};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
 use cron::Schedule;
+use futures_util::future::join_all;
+use futures_util::StreamExt;
 use main_error::MainResult;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 use ssh::SshSession;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{stdout, Write};
 use std::net::IpAddr;
-use std::str::FromStr;
+use std::pin::pin;
+use std::process::exit;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::signal::ctrl_c;
+use tokio::sync::mpsc::Sender;
 use tokio::time::sleep;
 use tokio::{select, spawn};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, instrument, warn};
 
 mod cloud;
 mod config;
+mod credentials;
 mod dns;
+mod http;
+mod metrics;
+mod notify;
+mod output;
+mod progress;
 mod rcon;
+mod schedule;
 mod ssh;
+mod state;
 
 /// Manage ephemeral tf2 servers
 #[derive(Parser)]
@@ -33,19 +60,141 @@ struct Args {
     #[clap(subcommand)]
     command: Option<Commands>,
     config: String,
+    /// Log the actions that would be taken without creating or destroying servers
+    #[clap(long, global = true)]
+    dry_run: bool,
+    /// Format of the log output
+    #[clap(long, global = true, value_enum, default_value_t)]
+    log_format: LogFormat,
+    /// Print machine readable json for the `list`, `start` and `ensure` commands
+    #[clap(long, global = true)]
+    json: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default)]
+enum LogFormat {
+    #[default]
+    Text,
+    Json,
 }
 
 #[derive(Subcommand, Default)]
 enum Commands {
-    /// Start a new server if none is running
+    /// Start the configured servers that aren't running yet
     Start,
-    /// Start the server if one is running
+    /// Make sure the configured servers are running, starting the servers that aren't
+    ///
+    /// Unlike `start`, this exits with an error if a server failed to start
+    Ensure {
+        /// Point the dyndns to already running servers and save them in the state file, so the daemon manages them
+        #[clap(long)]
+        adopt: bool,
+    },
+    /// Stop all running servers
     Stop,
     /// List running servers
     List,
+    /// Stop every server with the managed tag, including servers that don't belong to the configured servers
+    Prune,
+    /// Check the configuration and credentials without starting a server
+    Validate,
+    /// Show the logs of the tf2 server container
+    Logs {
+        /// Private ssh key to connect to the server with, defaults to the key saved in the credentials file
+        #[clap(short, long)]
+        identity: Option<String>,
+        /// Number of lines to show from the end of the logs
+        #[clap(long, default_value_t = 200)]
+        tail: usize,
+        /// Keep streaming new log output
+        #[clap(short, long)]
+        follow: bool,
+        /// Name of the server to show the logs for, defaults to the first running server
+        #[clap(long)]
+        server: Option<String>,
+    },
+    /// Restart the game server of a running server with the current configuration, without recreating the server
+    Reconfigure {
+        /// Private ssh key to connect to the server with, defaults to the key saved in the credentials file
+        #[clap(short, long)]
+        identity: Option<String>,
+        /// Name of the server to reconfigure, defaults to the first running server
+        #[clap(long)]
+        server: Option<String>,
+    },
+    /// Change the plan of a running server, this restarts the server
+    Resize {
+        /// Plan to switch to
+        plan: String,
+        /// Name of the server to resize, defaults to the first running server
+        #[clap(long)]
+        server: Option<String>,
+    },
+    /// Create a snapshot of a running server, which can be used as `snapshot` in the server config
+    Snapshot {
+        /// Name for the snapshot
+        name: String,
+        /// Name of the server to snapshot, defaults to the first running server
+        #[clap(long)]
+        server: Option<String>,
+    },
+    /// Change the map on a running server
+    Map {
+        /// Name of the map to change to
+        name: String,
+        /// Name of the server to change the map on, defaults to the first running server
+        #[clap(long)]
+        server: Option<String>,
+    },
+    /// Lock a running server for new players and stop it once all players have left
+    Drain {
+        /// Name of the server to drain, defaults to the first running server
+        #[clap(long)]
+        server: Option<String>,
+        /// Maximum time in minutes to wait for players to leave, defaults to the `stop_grace_time` from the schedule
+        #[clap(long)]
+        timeout: Option<u64>,
+    },
+    /// Run a command on a running server trough rcon
+    Rcon {
+        /// Command to run
+        command: String,
+        /// Name of the server to run the command on, defaults to the first running server
+        #[clap(long)]
+        server: Option<String>,
+    },
+    /// Kick a player from a running server
+    Kick {
+        /// Userid or name of the player to kick
+        player: String,
+        /// Reason shown to the kicked player
+        #[clap(long, default_value = "Kicked by admin")]
+        reason: String,
+        /// Name of the server to kick the player from, defaults to the first running server
+        #[clap(long)]
+        server: Option<String>,
+    },
+    /// Ban a player from a running server
+    Ban {
+        /// Userid or name of the player to ban
+        player: String,
+        /// Duration of the ban in minutes, 0 bans the player permanently
+        #[clap(long, default_value_t = 0)]
+        minutes: u32,
+        /// Reason shown to the banned player
+        #[clap(long, default_value = "Banned by admin")]
+        reason: String,
+        /// Name of the server to ban the player from, defaults to the first running server
+        #[clap(long)]
+        server: Option<String>,
+    },
     /// Run the management daemon
     #[default]
     Daemon,
+    /// Run a single iteration of the management daemon and exit, for running the daemon from an external scheduler
+    ///
+    /// Use together with `state_file` so the stop timer is remembered between runs
+    Tick,
 }
 
 #[derive(Debug, Error)]
@@ -56,347 +205,2299 @@ pub enum Error {
     Config(#[from] ConfigError),
     #[error("Error while trying to connect trough ssh: {0}")]
     Ssh(#[from] SshError),
-    #[error("Setup command returned an error: {0}")]
-    SetupError(String),
+    #[error("Setup failed at step {step}: {output}")]
+    SetupError { step: SetupStep, output: String },
     #[error("Error while updating dyndns: {0}")]
     DynDns(#[from] DynDnsError),
     #[error("Already running")]
-    AlreadyRunning(Server),
+    AlreadyRunning(Box<Server>),
     #[error("{0}")]
     Schedule(#[from] cron::error::Error),
     #[error("{0}")]
     Rcon(#[from] ::rcon::Error),
+    #[error("Timeout while connecting to rcon")]
+    RconTimeout,
+    #[error("Invalid map name \"{0}\"")]
+    InvalidMap(String),
+    #[error("No player found matching \"{0}\"")]
+    PlayerNotFound(String),
+    #[error("Multiple players found matching \"{0}\", use the userid instead")]
+    AmbiguousPlayer(String),
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("Error while reading saved credentials: {0}")]
+    Credentials(#[from] CredentialsError),
+    #[error("No identity given and no credentials saved for server {0}")]
+    NoCredentials(String),
+}
+
+/// Step of the server setup, to show where the setup failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupStep {
+    PreSetup,
+    DockerPull,
+    DockerRun,
+    VerifyDigest,
+    PostSetup,
+    Swap,
+    Palantir,
+}
+
+impl Display for SetupStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SetupStep::PreSetup => "pre_setup",
+            SetupStep::DockerPull => "docker_pull",
+            SetupStep::DockerRun => "docker_run",
+            SetupStep::VerifyDigest => "verify_digest",
+            SetupStep::PostSetup => "post_setup",
+            SetupStep::Swap => "swap",
+            SetupStep::Palantir => "palantir",
+        };
+        write!(f, "{}", name)
+    }
 }
 
-#[instrument(skip(config))]
+impl Error {
+    fn setup(step: SetupStep, output: impl Into<String>) -> Self {
+        Error::SetupError {
+            step,
+            output: output.into(),
+        }
+    }
+}
+
+#[instrument(skip(config, password, palantir, retry, progress))]
 async fn setup(
     ssh: &mut SshSession,
     config: &ServerConfig,
+    password: &str,
+    palantir: &PalantirConfig,
+    retry: &RetryConfig,
     hostname: Option<&str>,
+    progress: Option<&Sender<ProgressEvent>>,
 ) -> Result<(), Error> {
     sleep(Duration::from_secs(10)).await;
 
+    // providers that install docker trough cloud-init might not be done yet
+    ssh.exec("cloud-init status --wait").await?;
+
+    progress::report(progress, ProgressEvent::Setup(SetupStep::PreSetup));
+    run_hooks(ssh, SetupStep::PreSetup, &config.pre_setup).await?;
+
+    // servers booted from a snapshot already have the image, swap and palantir set up
+    let from_snapshot = config.snapshot.is_some();
+
+    if from_snapshot {
+        info!("booted from snapshot, skipping image pull");
+    } else {
+        progress::report(progress, ProgressEvent::Setup(SetupStep::DockerPull));
+        pull_image(ssh, config, retry).await?;
+    }
+
+    progress::report(progress, ProgressEvent::Setup(SetupStep::DockerRun));
+    start_container(ssh, config, password).await?;
+
+    progress::report(progress, ProgressEvent::Setup(SetupStep::PostSetup));
+    run_hooks(ssh, SetupStep::PostSetup, &config.post_setup).await?;
+
+    if from_snapshot {
+        debug!("booted from snapshot, skipping swap setup");
+    } else if config.swap_mb > 0 {
+        progress::report(progress, ProgressEvent::Setup(SetupStep::Swap));
+        info!(size_mb = config.swap_mb, "setting up swap");
+        for command in swap_commands(config.swap_mb) {
+            exec_optional(ssh, SetupStep::Swap, command).await?;
+        }
+    } else {
+        info!("swap disabled");
+    }
+
+    if palantir.enabled && !from_snapshot {
+        progress::report(progress, ProgressEvent::Setup(SetupStep::Palantir));
+        info!(version = display(&palantir.version), "setting up palantir");
+        for command in palantir_install_commands(palantir) {
+            exec_optional(ssh, SetupStep::Palantir, command).await?;
+        }
+    }
+    if let Some(hostname) = hostname {
+        ssh.exec(format!("hostname {}", hostname)).await?;
+    }
+    if palantir.enabled {
+        exec_optional(ssh, SetupStep::Palantir, "systemctl enable --now palantir").await?;
+    }
+
+    Ok(())
+}
+
+/// Run a setup command that isn't required for the game server, a failure is logged but doesn't fail the setup
+async fn exec_optional(
+    ssh: &mut SshSession,
+    step: SetupStep,
+    command: impl Into<String> + Debug,
+) -> Result<(), Error> {
+    let result = ssh.exec(command).await?;
+    if !result.success() {
+        warn!(
+            step = %step,
+            code = debug(result.code),
+            stderr = display(result.stderr()),
+            "Optional setup step failed"
+        );
+    }
+    Ok(())
+}
+
+/// Pull the game server image, retrying failed pulls
+async fn pull_image(
+    ssh: &mut SshSession,
+    config: &ServerConfig,
+    retry: &RetryConfig,
+) -> Result<(), Error> {
     let mut tries = 0;
 
-    debug!(image = display(&config.image), "pulling image");
+    debug!(image = display(config.image()), "pulling image");
     loop {
         tries += 1;
         sleep(Duration::from_secs(2)).await;
-        let result = ssh.exec(format!("docker pull {}", config.image)).await?;
+        let result = ssh.exec(format!("docker pull {}", config.image())).await?;
         if result.success() {
-            break;
-        } else if tries > 5 {
+            return Ok(());
+        } else if tries >= retry.docker_pull_attempts {
             error!(
+                step = %SetupStep::DockerPull,
                 tries = tries,
-                output = display(result.output()),
+                code = debug(result.code),
+                stderr = display(result.stderr()),
                 "Failed to pull docker image to many times, giving up"
             );
-            return Err(Error::SetupError(result.output()));
+            return Err(Error::setup(SetupStep::DockerPull, result.output()));
         } else {
             error!(
                 tries = tries,
-                output = display(result.output()),
+                code = debug(result.code),
+                stderr = display(result.stderr()),
                 "Failed to pull docker image, retrying"
             );
         }
     }
+}
+
+/// (Re-)create the game server container
+async fn start_container(
+    ssh: &mut SshSession,
+    config: &ServerConfig,
+    password: &str,
+) -> Result<(), Error> {
+    let container = config.container_name();
+    info!(container = display(&container), "starting container");
 
-    info!("starting container");
+    // clear out any container left behind by an earlier setup
+    ssh.exec(format!("docker rm -f {}", container)).await?;
 
-    let cmnd = format!(
-        "docker run --name spire -d \
-            -e NAME={name} -e TV_NAME={tv_name} -e PASSWORD={password} -e RCON_PASSWORD={rcon} \
-            -e DEMOSTF_APIKEY={demostf} -e LOGSTF_APIKEY={logstf} \
-            -e CONFIG_LEAGUE={league} -e CONFIG_MODE={mode} -e 'EXTRA_CFG={extra_cfg}' \
-            -p 27015:27015 -p 27021:27021 -p 27015:27015/udp -p 27020:27020/udp -p 27025:27025 \
-            -p 28015:27015 -p 28015:27015/udp -p 27115:27015 -p 27115:27015/udp -p 27215:27015 \
-            -p 27215:27015/udp -p 27315:27015 -p 27315:27015/udp -p 27415:27015 -p 27415:27015/udp \
-            -p 27515:27015 -p 27515:27015/udp -p 27615:27015 -p 27615:27015/udp -p 27715:27015 \
-            -p 27715:27015/udp -p 27815:27015 -p 27815:27015/udp -p 27915:27015 -p 27915:27015/udp \
-            {image}
-            ",
-        name = config.name,
-        tv_name = config.tv_name,
-        password = config.password,
-        rcon = config.rcon,
-        demostf = config.demostf_key.as_deref().unwrap_or_default(),
-        logstf = config.logstf_key.as_deref().unwrap_or_default(),
-        league = config.config_league,
-        mode = config.config_mode,
-        image = config.image,
-        extra_cfg = config.extra_cfg,
-    );
+    let cmnd = docker_run_command(config, password);
 
     debug!("running {cmnd}");
 
     let result = ssh.exec(cmnd).await?;
 
     if !result.success() {
-        return Err(Error::SetupError(result.output()));
+        error!(
+            step = %SetupStep::DockerRun,
+            code = debug(result.code),
+            stderr = display(result.stderr()),
+            "Failed to start container"
+        );
+        return Err(Error::setup(SetupStep::DockerRun, result.output()));
     }
 
-    info!("setting up swap");
-    ssh.exec("dd if=/dev/zero of=/swapfile bs=1M count=1024")
-        .await?;
-    ssh.exec("chmod 600 /swapfile && mkswap /swapfile && swapon /swapfile")
-        .await?;
+    if let Some(digest) = config.image_digest() {
+        verify_image_digest(ssh, &container, digest).await?;
+    }
+    Ok(())
+}
+
+/// Restart the game server container of a running server with the current configuration
+///
+/// The host setup like swap and palantir is left as is
+#[instrument(skip(ssh, config, password, retry))]
+async fn reconfigure(
+    ssh: &mut SshSession,
+    config: &ServerConfig,
+    password: &str,
+    retry: &RetryConfig,
+) -> Result<(), Error> {
+    // pull before removing the old container, so a failed pull leaves the server running
+    pull_image(ssh, config, retry).await?;
+    start_container(ssh, config, password).await
+}
 
-    info!("setting up prometheus");
-    ssh.exec("wget https://github.com/icewind1991/palantir/raw/main/palantir.service -O /etc/systemd/system/palantir.service").await?;
-    ssh.exec("wget https://github.com/icewind1991/palantir/releases/download/v1.1.0/palantir-x86_64-unknown-linux-musl -O /usr/local/bin/palantir").await?;
-    ssh.exec("chmod +x /usr/local/bin/palantir").await?;
-    ssh.exec(
-        r#"sed -i -e "s|User=palantir|DynamicUser=true|" /etc/systemd/system/palantir.service"#,
+/// Command to start the game server container
+fn docker_run_command(config: &ServerConfig, password: &str) -> String {
+    let container = config.container_name();
+    format!(
+        "docker run --name {container} -d --restart unless-stopped \
+            -e {name} -e {tv_name} -e {password} -e {rcon} \
+            -e {demostf} -e {logstf} \
+            -e {league} -e {mode} -e {extra_cfg} \
+            {env} {limits} {network} {image}
+            ",
+        name = shell_quote(&format!("NAME={}", config.name)),
+        tv_name = shell_quote(&format!("TV_NAME={}", config.tv_name)),
+        password = shell_quote(&format!("PASSWORD={}", password)),
+        rcon = shell_quote(&format!("RCON_PASSWORD={}", config.rcon)),
+        demostf = shell_quote(&format!(
+            "DEMOSTF_APIKEY={}",
+            config.demostf_key.as_deref().unwrap_or_default()
+        )),
+        logstf = shell_quote(&format!(
+            "LOGSTF_APIKEY={}",
+            config.logstf_key.as_deref().unwrap_or_default()
+        )),
+        league = shell_quote(&format!("CONFIG_LEAGUE={}", config.config_league)),
+        mode = shell_quote(&format!("CONFIG_MODE={}", config.config_mode)),
+        image = config.image(),
+        extra_cfg = shell_quote(&format!("EXTRA_CFG={}", config.extra_cfg())),
+        env = config
+            .env
+            .iter()
+            .map(|(name, value)| format!("-e {}", shell_quote(&format!("{}={}", name, value))))
+            .collect::<Vec<_>>()
+            .join(" "),
+        limits = config
+            .docker_cpus
+            .map(|cpus| format!("--cpus {}", cpus))
+            .into_iter()
+            .chain(
+                config
+                    .docker_memory
+                    .as_ref()
+                    .map(|memory| format!("--memory {}", memory)),
+            )
+            .collect::<Vec<_>>()
+            .join(" "),
+        network = match config.network_mode {
+            NetworkMode::Host => String::from("--network host"),
+            NetworkMode::Bridge => config
+                .ports
+                .iter()
+                .flat_map(PortMapping::docker_args)
+                .collect::<Vec<_>>()
+                .join(" "),
+        },
     )
-    .await?;
-    ssh.exec("iptables -I INPUT -p tcp --dport 5665 -j ACCEPT")
-        .await?;
-    if let Some(hostname) = hostname {
-        ssh.exec(&format!(
-            "hostname {} && systemctl start palantir",
-            hostname
+}
+
+/// Commands to set up a swapfile, added to fstab so the swap survives a restart, for example after resizing
+fn swap_commands(swap_mb: u64) -> [String; 2] {
+    [
+        format!("dd if=/dev/zero of=/swapfile bs=1M count={}", swap_mb),
+        String::from("chmod 600 /swapfile && mkswap /swapfile && swapon /swapfile && echo '/swapfile none swap sw 0 0' >> /etc/fstab"),
+    ]
+}
+
+/// Commands to install palantir and allow access to its port
+fn palantir_install_commands(palantir: &PalantirConfig) -> Vec<String> {
+    vec![
+        format!("wget https://github.com/icewind1991/palantir/raw/v{}/palantir.service -O /etc/systemd/system/palantir.service", palantir.version),
+        format!("wget https://github.com/icewind1991/palantir/releases/download/v{}/palantir-x86_64-unknown-linux-musl -O /usr/local/bin/palantir", palantir.version),
+        String::from("chmod +x /usr/local/bin/palantir"),
+        String::from(r#"sed -i -e "s|User=palantir|DynamicUser=true|" /etc/systemd/system/palantir.service"#),
+        format!(
+            "mkdir -p /etc/systemd/system/palantir.service.d && printf '[Service]\\nEnvironment=PORT={}\\n' > /etc/systemd/system/palantir.service.d/port.conf",
+            palantir.port
+        ),
+        // only add the rule if it doesn't exist yet, so re-provisioning a server doesn't add duplicate rules
+        format!(
+            "iptables -C INPUT -p tcp --dport {port} -j ACCEPT || iptables -I INPUT -p tcp --dport {port} -j ACCEPT",
+            port = palantir.port
+        ),
+    ]
+}
+
+/// Script that sets up the server on first boot trough cloud-init, instead of over ssh
+///
+/// Runs the same steps as `setup`, except for verifying the image digest
+fn cloud_init_script(
+    config: &ServerConfig,
+    password: &str,
+    palantir: &PalantirConfig,
+    retry: &RetryConfig,
+) -> String {
+    let from_snapshot = config.snapshot.is_some();
+    let mut commands = vec![String::from("set -e")];
+    commands.extend(config.pre_setup.iter().cloned());
+    if !from_snapshot {
+        commands.push(format!(
+            "for try in $(seq {attempts}); do docker pull {image} && break; [ $try -lt {attempts} ] || exit 1; sleep 2; done",
+            attempts = retry.docker_pull_attempts,
+            image = config.image()
+        ));
+    }
+    commands.push(format!("docker rm -f {} || true", config.container_name()));
+    commands.push(docker_run_command(config, password).trim().into());
+    commands.extend(config.post_setup.iter().cloned());
+    // swap and palantir are optional, like in the ssh setup a failure there shouldn't stop the remaining steps
+    commands.push(String::from("set +e"));
+    if !from_snapshot && config.swap_mb > 0 {
+        commands.extend(swap_commands(config.swap_mb));
+    }
+    if palantir.enabled {
+        if !from_snapshot {
+            commands.extend(palantir_install_commands(palantir));
+        }
+        commands.push(String::from("systemctl enable --now palantir"));
+    }
+    format!("#!/bin/bash\n{}\n", commands.join("\n"))
+}
+
+/// Run the configured setup hooks, failing the setup if any of them fails
+async fn run_hooks(
+    ssh: &mut SshSession,
+    step: SetupStep,
+    commands: &[String],
+) -> Result<(), Error> {
+    for command in commands {
+        debug!(step = %step, command = command.as_str(), "running setup hook");
+        let result = ssh.exec(command).await?;
+        debug!(
+            step = %step,
+            code = debug(result.code),
+            output = display(result.output()),
+            "setup hook finished"
+        );
+        if !result.success() {
+            error!(
+                step = %step,
+                command = command.as_str(),
+                code = debug(result.code),
+                stderr = display(result.stderr()),
+                "Setup hook failed"
+            );
+            return Err(Error::setup(step, result.output()));
+        }
+    }
+    Ok(())
+}
+
+/// Verify that the running container uses the image with the pinned digest
+async fn verify_image_digest(
+    ssh: &mut SshSession,
+    container: &str,
+    digest: &str,
+) -> Result<(), Error> {
+    let result = ssh
+        .exec(format!(
+            r#"docker image inspect --format '{{{{join .RepoDigests "\n"}}}}' "$(docker inspect --format '{{{{.Image}}}}' {})""#,
+            container
         ))
         .await?;
+    if !result.success() {
+        return Err(Error::setup(SetupStep::VerifyDigest, result.output()));
+    }
+    let output = result.stdout();
+    let running = output
+        .lines()
+        .filter_map(|line| line.trim().rsplit_once('@'))
+        .map(|(_, digest)| digest)
+        .collect::<Vec<_>>();
+    if running.contains(&digest) {
+        debug!(digest = digest, "verified image digest");
+        Ok(())
     } else {
-        ssh.exec("systemctl start palantir").await?;
+        Err(Error::setup(
+            SetupStep::VerifyDigest,
+            format!(
+                "Running image digest ({}) doesn't match the configured digest {}",
+                running.join(", "),
+                digest
+            ),
+        ))
     }
-
-    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> MainResult {
-    tracing_subscriber::fmt::init();
-
     let cli = Args::parse();
 
-    let config = Config::from_file(&cli.config)?;
-    let cloud = config.cloud()?;
+    match cli.log_format {
+        LogFormat::Text => tracing_subscriber::fmt().init(),
+        LogFormat::Json => tracing_subscriber::fmt().json().init(),
+    }
+
+    let mut config = Config::from_file(&cli.config)?;
+    config.dry_run = cli.dry_run;
+    config.json_output = cli.json;
+    if let Some(proxy_url) = &config.proxy_url {
+        http::set_proxy(http::parse_proxy(proxy_url).map_err(ConfigError::from)?);
+    }
+    ssh::set_preferred(config.ssh.preferred()?);
+    let cloud = if config.dry_run {
+        Arc::new(DryRunCloud::new(
+            config.cloud()?,
+            config.cloud_description(),
+        ))
+    } else {
+        config.cloud()?
+    };
 
     match cli.command.unwrap_or_default() {
         Commands::Daemon => {
-            let start_schedule = Schedule::from_str(&config.schedule.start)?;
-            let stop_schedule = Schedule::from_str(&config.schedule.stop)?;
+            let (start_schedule, stop_schedule) = config.schedule.schedules()?;
+
+            if let Some(addr) = config.metrics_listen {
+                spawn(metrics::serve(addr));
+            }
 
+            let dns = dyndns_client(&config);
+            let shutdown = CancellationToken::new();
+            let mut daemon = pin!(run_loop(
+                cloud,
+                config,
+                dns,
+                start_schedule,
+                stop_schedule,
+                shutdown.clone(),
+                false
+            ));
             select! {
-                _ = run_loop(cloud, config, start_schedule, stop_schedule) => {},
-                _ = ctrl_c() => {},
+                result = &mut daemon => result?,
+                _ = ctrl_c() => {
+                    info!("Shutting down, waiting for in-progress operations to finish");
+                    shutdown.cancel();
+                    daemon.await?;
+                },
             }
         }
+        Commands::Tick => {
+            let (start_schedule, stop_schedule) = config.schedule.schedules()?;
+            let dns = dyndns_client(&config);
+            let shutdown = CancellationToken::new();
+            spawn(cancel_on_ctrl_c(shutdown.clone()));
+            run_loop(
+                cloud,
+                config,
+                dns,
+                start_schedule,
+                stop_schedule,
+                shutdown,
+                true,
+            )
+            .await?;
+        }
         Commands::List => {
             let servers = cloud.list().await?;
-            if servers.is_empty() {
-                println!("No running server");
-            } else {
-                for server in servers {
-                    let player_count =
-                        match Rcon::new((server.ip, 27015), &config.server.rcon).await {
-                            Ok(mut rcon) => rcon.player_count().await,
-                            Err(e) => Err(e),
-                        };
-
-                    if let Ok(player_count) = player_count {
-                        println!("{}: {} with {} players", server.id, server.ip, player_count);
-                    } else {
-                        println!("{}: {}", server.id, server.ip);
+            let state = load_state(&config);
+            let mut listed = Vec::with_capacity(servers.len());
+            let mut prices = HashMap::new();
+            for server in &servers {
+                let server_config = config.server_config_for(server);
+                let player_count = match server_config {
+                    Some(server_config) => {
+                        match Rcon::new(
+                            (server.reachable_ip(), 27015),
+                            &server_config.rcon,
+                            config.retry.rcon_timeout(),
+                        )
+                        .await
+                        {
+                            Ok(mut rcon) => {
+                                rcon.player_count(server_config.player_filter()).await.ok()
+                            }
+                            Err(_) => None,
+                        }
                     }
+                    None => None,
+                };
+                let connect = server_config.and_then(|server_config| {
+                    let password = server_password(server_config, server, &state)?;
+                    Some(connect_string(&config, server_config, server, password))
+                });
+                let plan = server_config
+                    .and_then(|server_config| config.plan_for(server_config))
+                    .or_else(|| config.default_plan());
+                let price = match plan {
+                    Some(plan) => match prices.get(plan) {
+                        Some(price) => *price,
+                        None => {
+                            let price = hourly_price(cloud.as_ref(), &config, plan).await;
+                            prices.insert(plan, price);
+                            price
+                        }
+                    },
+                    None => None,
+                };
+                let mut output = output::ServerOutput::new(server, player_count, connect);
+                output.estimated_cost = price.map(|price| server.accrued_cost(price));
+                listed.push(output);
+            }
+
+            let (start_schedule, stop_schedule) = config.schedule.schedules()?;
+            let now = Utc::now();
+            let next_start = config.schedule.next_after(&start_schedule, now);
+            let next_stop = config.schedule.next_after(&stop_schedule, now);
+
+            if config.json_output {
+                output::print_json(&output::ListOutput {
+                    servers: listed,
+                    next_start,
+                    next_stop,
+                });
+                return Ok(());
+            }
+
+            if listed.is_empty() {
+                println!("No running server");
+            }
+            for server in listed {
+                let mut line = format!("{} ({}): {}", server.id, server.label, server.ip);
+                if let Some(private_ip) = server.private_ip {
+                    line.push_str(&format!(" (private {})", private_ip));
+                }
+                if !server.region.is_empty() {
+                    line.push_str(&format!(" in {}", server.region));
+                }
+                if !server.plan.is_empty() {
+                    line.push_str(&format!(" on {}", server.plan));
+                }
+                if let Some(player_count) = server.players {
+                    line.push_str(&format!(" with {} players", player_count));
+                }
+                line.push_str(&format!(
+                    ", up {}",
+                    format_duration(chrono::Duration::minutes(server.uptime_minutes))
+                ));
+                if let Some(cost) = server.estimated_cost {
+                    line.push_str(&format!(", ~{:.2} so far", cost));
+                }
+                println!("{}", line);
+            }
+
+            if let (Some(next_start), Some(next_stop)) = (next_start, next_stop) {
+                println!(
+                    "Next start in {} / next stop in {}",
+                    format_duration(next_start - now),
+                    format_duration(next_stop - now)
+                );
+            }
+        }
+        Commands::Validate => {
+            println!("[x] configuration");
+            let mut valid = check("cloud provider", cloud.validate().await);
+            for server_config in &config.servers {
+                valid &= check(
+                    &format!("rcon password for {}", server_config.name),
+                    server_config.validate_rcon_password(),
+                );
+            }
+            if let (Some(dns), Some(dns_config)) = (dyndns_client(&config), &config.dyndns) {
+                // petname hostnames only exist once a server is started
+                if dns_config.hostname_strategy == HostnameStrategy::Fixed {
+                    valid &= check("dyndns", dns.check(&dns_config.hostname).await);
                 }
             }
+            if config.min_balance.is_some() {
+                valid &= check(
+                    "account balance",
+                    check_balance(cloud.as_ref(), &config).await,
+                );
+            }
+            if !valid {
+                exit(1);
+            }
         }
         Commands::Start => {
-            match start(cloud.as_ref(), &config).await {
-                Ok(_) => {}
-                Err(Error::AlreadyRunning(_)) => {
-                    println!("Server already running");
-                }
-                Err(e) => eprintln!("{:#}", e),
-            };
+            let started = start_servers(cloud.as_ref(), &config, false).await;
+            if config.json_output {
+                output::print_json(&started);
+            }
         }
-        Commands::Stop => match cloud.list().await?.first() {
-            Some(server) => match cloud.kill(&server.id).await {
-                Ok(_) => {
-                    println!("Server stopped");
+        Commands::Ensure { adopt } => {
+            let started = start_servers(cloud.as_ref(), &config, adopt).await;
+            if config.json_output {
+                output::print_json(&started);
+            }
+            let failed = started.iter().any(|started| {
+                matches!(
+                    started.status,
+                    output::StartStatus::Failed | output::StartStatus::Cancelled
+                )
+            });
+            if failed {
+                exit(1);
+            }
+        }
+        Commands::Logs {
+            identity,
+            tail,
+            follow,
+            server,
+        } => {
+            let servers = cloud.list().await?;
+            match find_server(&config, &servers, server.as_deref()) {
+                Some(server) => {
+                    let server_config = config.server_config_for(server).unwrap();
+                    let auth = ssh_auth(&config, server, identity.as_deref())?;
+                    let host_key = known_host_key(&config, server_config, server)?;
+                    let mut ssh = SshSession::open(
+                        server.reachable_ip(),
+                        &server_config.ssh_user,
+                        &auth,
+                        &host_key,
+                        Duration::from_secs(config.retry.boot_timeout_secs),
+                    )
+                    .await?;
+                    let follow = if follow { " -f" } else { "" };
+                    let mut output = pin!(
+                        ssh.exec_stream(format!(
+                            "docker logs --tail {tail}{follow} {}",
+                            server_config.container_name()
+                        ))
+                        .await?
+                    );
+                    let mut stdout = stdout();
+                    while let Some(chunk) = output.next().await {
+                        stdout.write_all(&chunk)?;
+                        stdout.flush()?;
+                    }
+                    ssh.close().await?;
+                }
+                None => {
+                    eprintln!("No server running");
                 }
-                Err(e) => eprintln!("{:#}", e),
-            },
-            None => {
-                eprintln!("No server running");
             }
-        },
-    }
-
-    Ok(())
-}
-
-async fn run_loop(
-    cloud: Arc<dyn Cloud>,
-    config: Config,
-    start_schedule: Schedule,
-    stop_schedule: Schedule,
-) {
-    let mut active_server = if config.server.manage_existing {
-        cloud.list().await.into_iter().flatten().next()
-    } else {
-        None
-    };
-
-    if let Some(server) = active_server.as_ref() {
-        info!(
-            server = debug(server),
-            "Taking ownership of existing server"
-        );
-    }
-
-    let mut start_of_stop_time = None;
-    let stop_grace_time = Duration::from_secs(config.schedule.stop_grace_time);
+        }
+        Commands::Reconfigure { identity, server } => {
+            let servers = cloud.list().await?;
+            match find_server(&config, &servers, server.as_deref()) {
+                Some(server) => {
+                    let server_config = config.server_config_for(server).unwrap();
+                    match connect_rcon(&config, server).await {
+                        Ok(mut rcon) => {
+                            match rcon.player_count(server_config.player_filter()).await {
+                                Ok(0) => {}
+                                Ok(count) => eprintln!(
+                                "Warning: {} players are connected to {} and will be disconnected",
+                                count, server_config.name
+                            ),
+                                Err(e) => warn!(error = %e, "Failed to get the player count"),
+                            }
+                        }
+                        Err(e) => warn!(error = %e, "Failed to connect to rcon"),
+                    }
 
-    loop {
-        let next_start = start_schedule.upcoming(Utc).next().unwrap();
-        let next_stop = stop_schedule.upcoming(Utc).next().unwrap();
+                    let state = load_state(&config);
+                    let password = match server_password(server_config, server, &state) {
+                        Some(password) => password.to_string(),
+                        None => {
+                            println!("The password of the running server is unknown, using a new password");
+                            server_config.new_password()
+                        }
+                    };
 
-        // we're between start time and stop time
-        if active_server.is_none() && next_start > next_stop {
-            start_of_stop_time = None;
-            println!("Starting server");
-            match start(cloud.as_ref(), &config).await {
-                Ok(server) => active_server = Some(server),
-                Err(Error::AlreadyRunning(server)) if config.server.manage_existing => {
-                    info!(
-                        server = debug(&server),
-                        "Taking ownership of existing server"
+                    let auth = ssh_auth(&config, server, identity.as_deref())?;
+                    let host_key = known_host_key(&config, server_config, server)?;
+                    let mut ssh = SshSession::open(
+                        server.reachable_ip(),
+                        &server_config.ssh_user,
+                        &auth,
+                        &host_key,
+                        Duration::from_secs(config.retry.boot_timeout_secs),
+                    )
+                    .await?;
+                    println!("Restarting the game server on {}", server.id);
+                    reconfigure(&mut ssh, server_config, &password, &config.retry).await?;
+                    ssh.close().await?;
+                    println!("Connect using");
+                    println!(
+                        "  {}",
+                        connect_string(&config, server_config, server, &password)
                     );
-                    if let Some(dns_config) = config.dyndns.as_ref() {
-                        spawn(set_dyndns(dns_config.clone(), server.ip));
+                }
+                None => {
+                    eprintln!("No server running");
+                }
+            }
+        }
+        Commands::Resize { plan, server } => {
+            let servers = cloud.list().await?;
+            match find_server(&config, &servers, server.as_deref()) {
+                Some(server) => {
+                    println!("Resizing server {} to {}", server.id, plan);
+                    cloud.resize(&server.id, &plan).await?;
+                    println!(
+                        "Server {} resized, it will be back once it's restarted",
+                        server.id
+                    );
+                }
+                None => {
+                    eprintln!("No server running");
+                }
+            }
+        }
+        Commands::Snapshot { name, server } => {
+            let servers = cloud.list().await?;
+            match find_server(&config, &servers, server.as_deref()) {
+                Some(server) => {
+                    println!("Creating snapshot {} of server {}", name, server.id);
+                    let snapshot = cloud.snapshot(&server.id, &name).await?;
+                    println!("Snapshot created with id {}", snapshot);
+                }
+                None => {
+                    eprintln!("No server running");
+                }
+            }
+        }
+        Commands::Map { name, server } => {
+            let servers = cloud.list().await?;
+            match find_server(&config, &servers, server.as_deref()) {
+                Some(server) => {
+                    let mut rcon = connect_rcon(&config, server).await?;
+                    let response = rcon.changelevel(&name).await?;
+                    println!("Changing map to {}", name);
+                    if !response.is_empty() {
+                        println!("{}", response);
                     }
-                    active_server = Some(server);
                 }
-                Err(e) => eprintln!("{:#}", e),
-            };
+                None => {
+                    eprintln!("No server running");
+                }
+            }
         }
-
-        // we're between stop time and start time
-        if active_server.is_some() && next_stop > next_start {
-            let stop_elapsed = start_of_stop_time
-                .get_or_insert_with(Instant::now)
-                .elapsed();
-
-            let stop = if stop_elapsed > stop_grace_time {
-                warn!("Server took longer than the grace time of {} seconds to empty, shutting down with players left", stop_grace_time.as_secs());
-                true
-            } else {
-                let active_players_res = match Rcon::new(
-                    (active_server.as_ref().unwrap().ip, 27015),
-                    &config.server.rcon,
-                )
-                .await
-                {
-                    Ok(mut rcon) => rcon.player_count().await,
-                    Err(e) => Err(e),
-                };
-                match active_players_res {
-                    Ok(0) => true,
-                    Ok(count) => {
-                        info!(
-                            "Want to stop server, but there are still {} active players",
-                            count
+        Commands::Drain { server, timeout } => {
+            let servers = cloud.list().await?;
+            match find_server(&config, &servers, server.as_deref()) {
+                Some(server) => {
+                    let timeout = timeout
+                        .map(|minutes| Duration::from_secs(minutes * 60))
+                        .unwrap_or(Duration::from_secs(config.schedule.stop_grace_time));
+                    drain(cloud.as_ref(), &config, server, timeout).await?;
+                }
+                None => {
+                    eprintln!("No server running");
+                }
+            }
+        }
+        Commands::Rcon { command, server } => {
+            let servers = cloud.list().await?;
+            match find_server(&config, &servers, server.as_deref()) {
+                Some(server) => {
+                    let mut rcon = connect_rcon(&config, server).await?;
+                    println!("{}", rcon.command(&command).await?.trim_end());
+                }
+                None => {
+                    eprintln!("No server running");
+                }
+            }
+        }
+        Commands::Kick {
+            player,
+            reason,
+            server,
+        } => {
+            let servers = cloud.list().await?;
+            match find_server(&config, &servers, server.as_deref()) {
+                Some(server) => {
+                    let mut rcon = connect_rcon(&config, server).await?;
+                    let players = rcon.players().await?;
+                    let player = find_player(&players, &player)?;
+                    let response = rcon.kick(player.userid, &reason).await?;
+                    println!("Kicked {} ({})", player.name, player.userid);
+                    if !response.is_empty() {
+                        println!("{}", response);
+                    }
+                }
+                None => {
+                    eprintln!("No server running");
+                }
+            }
+        }
+        Commands::Ban {
+            player,
+            minutes,
+            reason,
+            server,
+        } => {
+            let servers = cloud.list().await?;
+            match find_server(&config, &servers, server.as_deref()) {
+                Some(server) => {
+                    let mut rcon = connect_rcon(&config, server).await?;
+                    let players = rcon.players().await?;
+                    let player = find_player(&players, &player)?;
+                    let response = rcon.ban(player.userid, minutes, &reason).await?;
+                    if minutes == 0 {
+                        println!("Banned {} ({}) permanently", player.name, player.userid);
+                    } else {
+                        println!(
+                            "Banned {} ({}) for {} minutes",
+                            player.name, player.userid, minutes
                         );
-                        false
                     }
-                    Err(e) => {
-                        error!("Error while trying get player count: {}", e);
-                        false
+                    if !response.is_empty() {
+                        println!("{}", response);
                     }
                 }
-            };
-            if stop {
-                let id = &active_server.as_ref().unwrap().id;
-                println!("Stopping server {}", id);
-                match cloud.kill(id).await {
+                None => {
+                    eprintln!("No server running");
+                }
+            }
+        }
+        Commands::Stop => {
+            let servers: Vec<Server> = cloud
+                .list()
+                .await?
+                .into_iter()
+                .filter(|server| config.server_config_for(server).is_some())
+                .collect();
+            if servers.is_empty() {
+                eprintln!("No server running");
+            }
+            for server in servers {
+                match destroy(cloud.as_ref(), &config, &server).await {
                     Ok(_) => {
-                        active_server = None;
+                        println!("Server {} stopped", server.id);
                     }
                     Err(e) => eprintln!("{:#}", e),
                 }
             }
         }
-
-        sleep(Duration::from_secs(60)).await;
+        Commands::Prune => {
+            let servers = cloud.list().await?;
+            if servers.is_empty() {
+                println!("No managed servers running");
+            }
+            let mut failed = 0;
+            for server in &servers {
+                match destroy(cloud.as_ref(), &config, server).await {
+                    Ok(_) => {
+                        println!("Server {} ({}) stopped", server.id, server.label);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to stop server {}: {:#}", server.id, e);
+                        failed += 1;
+                    }
+                }
+            }
+            if !servers.is_empty() {
+                println!(
+                    "Stopped {} of {} servers",
+                    servers.len() - failed,
+                    servers.len()
+                );
+            }
+            if failed > 0 {
+                exit(1);
+            }
+        }
     }
+
+    Ok(())
 }
 
-#[instrument(skip(cloud, config))]
-async fn start(cloud: &dyn Cloud, config: &Config) -> Result<Server, Error> {
-    let list = cloud.list().await?;
-    let count = list.len();
-    let first = list.into_iter().next();
-    if let Some(first) = first {
-        warn!(
+/// Find a running server belonging to the config, optionally by the name of the server
+fn find_server<'a>(
+    config: &Config,
+    servers: &'a [Server],
+    name: Option<&str>,
+) -> Option<&'a Server> {
+    servers
+        .iter()
+        .find(|running| match (config.server_config_for(running), name) {
+            (Some(_), None) => true,
+            (Some(server_config), Some(name)) => server_config.name == name,
+            (None, _) => false,
+        })
+}
+
+/// Start all configured servers that aren't running yet
+///
+/// With `adopt`, servers that are already running are taken over by pointing the dyndns to them
+/// and saving them in the state file, so the daemon manages them
+async fn start_servers(
+    cloud: &dyn Cloud,
+    config: &Config,
+    adopt: bool,
+) -> Vec<output::StartOutput> {
+    let dns = dyndns_client(config);
+    let shutdown = CancellationToken::new();
+    spawn(cancel_on_ctrl_c(shutdown.clone()));
+    let mut started = Vec::with_capacity(config.servers.len());
+    for server_config in &config.servers {
+        let password = server_config.new_password();
+        let result = start(
+            cloud,
+            config,
+            dns.as_ref(),
+            server_config,
+            &password,
+            &shutdown,
+            None,
+        )
+        .await;
+        let (status, server, error) = match result {
+            Ok(server) => {
+                let connect = connect_string(config, server_config, &server, &password);
+                (
+                    output::StartStatus::Started,
+                    Some(output::ServerOutput::new(&server, None, Some(connect))),
+                    None,
+                )
+            }
+            Err(Error::AlreadyRunning(server)) => {
+                if !config.json_output {
+                    println!("Server {} already running", server_config.name);
+                }
+                if adopt {
+                    adopt_server(config, dns.as_ref(), server_config, &server).await;
+                }
+                (
+                    output::StartStatus::AlreadyRunning,
+                    Some(output::ServerOutput::new(&server, None, None)),
+                    None,
+                )
+            }
+            Err(Error::Cancelled) => {
+                eprintln!("Cancelled, the partially created server has been destroyed");
+                (output::StartStatus::Cancelled, None, None)
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                (output::StartStatus::Failed, None, Some(e.to_string()))
+            }
+        };
+        started.push(output::StartOutput {
+            name: server_config.name.clone(),
+            status,
+            server,
+            error,
+        });
+        if matches!(status, output::StartStatus::Cancelled) {
+            break;
+        }
+    }
+    started
+}
+
+/// Take over an already running server, pointing the dyndns to it and saving it in the state file
+async fn adopt_server(
+    config: &Config,
+    dns: Option<&DynDnsClient>,
+    server_config: &ServerConfig,
+    server: &Server,
+) {
+    if let (Some(dns), Some(dns_config)) = (dns, config.dyndns_for(server_config)) {
+        set_dyndns(
+            dns.clone(),
+            dns_config.hostname_for(server),
+            server.ip,
+            server.ip_v6,
+        )
+        .await;
+    }
+    let Some(state_file) = config.state_file.as_deref() else {
+        return;
+    };
+    let mut state = load_state(config);
+    let known = state
+        .servers
+        .get(&server_config.name)
+        .is_some_and(|persisted| persisted.server_id == server.id);
+    if !known {
+        state.servers.insert(
+            server_config.name.clone(),
+            ServerState {
+                server_id: server.id.clone(),
+                spawned: server.created,
+                stop_started: None,
+                empty_checks: 0,
+                password: None,
+                stop_extended: false,
+            },
+        );
+        match state.save(state_file) {
+            Ok(()) => info!(id = %server.id, "Saved existing server in the daemon state"),
+            Err(e) => error!(error = %e, "Failed to save daemon state"),
+        }
+    }
+}
+
+/// Open an rcon connection to a running server
+async fn connect_rcon(config: &Config, server: &Server) -> Result<Rcon, Error> {
+    let server_config = config
+        .server_config_for(server)
+        .ok_or_else(|| Error::Cloud(CloudError::ServerNotFound))?;
+    Rcon::new(
+        (server.reachable_ip(), 27015),
+        &server_config.rcon,
+        config.retry.rcon_timeout(),
+    )
+    .await
+}
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Set a random server password so no new players can join, wait for the server to empty and stop it
+///
+/// The server is stopped with players left if they don't leave before the timeout
+async fn drain(
+    cloud: &dyn Cloud,
+    config: &Config,
+    server: &Server,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let filter = config
+        .server_config_for(server)
+        .ok_or_else(|| Error::Cloud(CloudError::ServerNotFound))?
+        .player_filter();
+    let password: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    connect_rcon(config, server)
+        .await?
+        .command(&format!("sv_password {}", password))
+        .await?;
+    println!("Server {} locked for new players", server.id);
+
+    let deadline = Instant::now() + timeout;
+    let mut rcon: Option<Rcon> = None;
+    let mut last_count = None;
+    loop {
+        let count = match rcon.as_mut() {
+            Some(rcon) => rcon.player_count(filter).await,
+            None => match connect_rcon(config, server).await {
+                Ok(connected) => rcon.insert(connected).player_count(filter).await,
+                Err(e) => Err(e),
+            },
+        };
+        match count {
+            Ok(0) => {
+                println!("Server is empty");
+                break;
+            }
+            Ok(count) => {
+                if last_count != Some(count) {
+                    println!("Waiting for {} players to leave", count);
+                }
+                last_count = Some(count);
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to get player count");
+                rcon = None;
+            }
+        }
+        if Instant::now() >= deadline {
+            println!("Players didn't leave in time, stopping the server with players left");
+            break;
+        }
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+
+    destroy(cloud, config, server).await?;
+    println!("Server {} stopped", server.id);
+    Ok(())
+}
+
+/// Daemon state for a single configured server
+struct ManagedServer<'a> {
+    config: &'a ServerConfig,
+    active: Option<Server>,
+    start_of_stop_time: Option<DateTime<Utc>>,
+    last_stop_warning: Option<Instant>,
+    /// Since when the server has been without players
+    empty_since: Option<Instant>,
+    /// Whether the server was stopped for being idle during the current start window
+    idle_stopped: bool,
+    /// Number of consecutive player count checks where the rcon password was rejected
+    rcon_auth_failures: u32,
+    /// Number of consecutive player count checks that found the server empty
+    empty_checks: u32,
+    /// Rcon connection to the active server, kept open between checks
+    rcon: Option<Rcon>,
+    /// Connect password of the active server, if it was rotated
+    password: Option<String>,
+    /// Whether the players voted to extend the current shutdown
+    stop_extended: bool,
+    /// The map time limit when the extend vote was enabled, a higher time limit means the vote passed
+    vote_timelimit: Option<f32>,
+}
+
+/// Number of consecutive rejected rcon passwords after which a server is stopped without knowing the player count
+const RCON_AUTH_FAILURE_LIMIT: u32 = 5;
+
+async fn run_loop(
+    cloud: Arc<dyn Cloud>,
+    config: Config,
+    dns: Option<DynDnsClient>,
+    start_schedule: Schedule,
+    stop_schedule: Schedule,
+    shutdown: CancellationToken,
+    once: bool,
+) -> Result<(), CloudError> {
+    let state_file = config.state_file.as_deref();
+    let mut state = match state_file.map(DaemonState::load).transpose() {
+        Ok(state) => state.unwrap_or_default(),
+        Err(e) => {
+            error!(error = %e, "Failed to load daemon state");
+            DaemonState::default()
+        }
+    };
+
+    let existing = if config.servers.iter().any(|server| server.manage_existing) {
+        cloud.list().await?
+    } else {
+        Vec::new()
+    };
+
+    let mut managed: Vec<ManagedServer> = Vec::with_capacity(config.servers.len());
+    for server_config in &config.servers {
+        let persisted = state.servers.get(&server_config.name);
+        if let Some(persisted) = persisted {
+            // a failed lookup isn't proof the server is gone, forgetting it would leave it running unmanaged
+            if let Some(server) = cloud.get(&persisted.server_id).await? {
+                info!(
+                    server = debug(&server),
+                    name = display(&server_config.name),
+                    "Restoring server from saved state"
+                );
+                managed.push(ManagedServer {
+                    config: server_config,
+                    active: Some(server),
+                    start_of_stop_time: persisted.stop_started,
+                    last_stop_warning: None,
+                    empty_since: None,
+                    idle_stopped: false,
+                    rcon_auth_failures: 0,
+                    empty_checks: persisted.empty_checks,
+                    rcon: None,
+                    password: persisted.password.clone(),
+                    stop_extended: persisted.stop_extended,
+                    vote_timelimit: None,
+                });
+                continue;
+            }
+            warn!(
+                id = display(&persisted.server_id),
+                name = display(&server_config.name),
+                "Server from saved state no longer exists"
+            );
+        }
+        let active = server_config
+            .manage_existing
+            .then(|| {
+                existing
+                    .iter()
+                    .find(|server| config.owns(server_config, server))
+                    .cloned()
+            })
+            .flatten();
+        if let Some(server) = active.as_ref() {
+            info!(
+                server = debug(server),
+                name = display(&server_config.name),
+                "Taking ownership of existing server"
+            );
+        }
+        managed.push(ManagedServer {
+            config: server_config,
+            active,
+            start_of_stop_time: None,
+            last_stop_warning: None,
+            empty_since: None,
+            idle_stopped: false,
+            rcon_auth_failures: 0,
+            empty_checks: 0,
+            rcon: None,
+            password: None,
+            stop_extended: false,
+            vote_timelimit: None,
+        });
+    }
+
+    let mut schedule = ScheduleTracker::new(start_schedule, stop_schedule);
+    loop {
+        let window = schedule.window(&config.schedule, Utc::now());
+
+        join_all(
+            managed.iter_mut().map(|server| {
+                server.tick(cloud.as_ref(), &config, dns.as_ref(), &shutdown, window)
+            }),
+        )
+        .await;
+
+        if let Some(state_file) = state_file {
+            let new_state = DaemonState {
+                servers: managed
+                    .iter()
+                    .filter_map(|server| Some((server.config.name.clone(), server.state()?)))
+                    .collect(),
+            };
+            if new_state != state {
+                if let Err(e) = new_state.save(state_file) {
+                    error!(error = %e, "Failed to save daemon state");
+                }
+                state = new_state;
+            }
+        }
+
+        if once {
+            return Ok(());
+        }
+
+        select! {
+            _ = sleep(config.schedule.poll_delay()) => {},
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+impl ManagedServer<'_> {
+    /// The state to persist for this server, if it's running
+    fn state(&self) -> Option<ServerState> {
+        let active = self.active.as_ref()?;
+        Some(ServerState {
+            server_id: active.id.clone(),
+            spawned: active.created,
+            stop_started: self.start_of_stop_time,
+            empty_checks: self.empty_checks,
+            password: self.password.clone(),
+            stop_extended: self.stop_extended,
+        })
+    }
+
+    #[instrument(skip_all, fields(name = %self.config.name))]
+    async fn tick(
+        &mut self,
+        cloud: &dyn Cloud,
+        config: &Config,
+        dns: Option<&DynDnsClient>,
+        shutdown: &CancellationToken,
+        window: Window,
+    ) {
+        let stop_extension = Duration::from_secs(config.schedule.stop_extension);
+        let stop_grace_time = Duration::from_secs(config.schedule.stop_grace_time)
+            + if self.stop_extended {
+                stop_extension
+            } else {
+                Duration::ZERO
+            };
+        let stop_warning_time = Duration::from_secs(config.schedule.stop_warning_time);
+        let stop_warning_interval = Duration::from_secs(config.schedule.stop_warning_interval);
+
+        self.check_active(cloud, config).await;
+
+        // an idle shutdown keeps the server down until the next scheduled start
+        if window == Window::Stop {
+            self.idle_stopped = false;
+        }
+
+        // we're between start time and stop time
+        if self.active.is_none() && !self.idle_stopped && window == Window::Start {
+            self.start_of_stop_time = None;
+            self.last_stop_warning = None;
+            self.stop_extended = false;
+            self.vote_timelimit = None;
+            println!("Starting server {}", self.config.name);
+            let password = self.config.new_password();
+            match start(cloud, config, dns, self.config, &password, shutdown, None).await {
+                Ok(server) => {
+                    let notifiers = config.notifiers();
+                    if !notifiers.is_empty() {
+                        let connect = connect_string(config, self.config, &server, &password);
+                        notify::notify_start(&notifiers, &server, &connect).await;
+                    }
+                    self.active = Some(server);
+                    self.password = self.config.rotate_password.then_some(password);
+                }
+                Err(Error::AlreadyRunning(server)) if self.config.manage_existing => {
+                    info!(
+                        server = debug(&server),
+                        "Taking ownership of existing server"
+                    );
+                    update_dyndns(dns, config, self.config, &server);
+                    self.active = Some(*server);
+                }
+                Err(e) => {
+                    METRICS.spawn_failed();
+                    eprintln!("{:#}", e);
+                }
+            };
+        }
+
+        let players = match self.active.as_ref().map(Server::reachable_ip) {
+            Some(ip) => Some(self.player_count(ip, config.retry.rcon_timeout()).await),
+            None => None,
+        };
+        match &players {
+            Some(Err(Error::Rcon(::rcon::Error::Auth))) => self.rcon_auth_failures += 1,
+            _ => self.rcon_auth_failures = 0,
+        }
+        // the player count can briefly drop to zero during a map change, only trust repeated empty checks
+        match &players {
+            Some(Ok(0)) => self.empty_checks += 1,
+            _ => self.empty_checks = 0,
+        }
+        // give players some time to join a freshly started server before considering it empty
+        let warmed_up = self.active.as_ref().is_some_and(|server| {
+            (Utc::now() - server.created).num_minutes() >= self.config.min_uptime_minutes as i64
+        });
+        let empty = self.empty_checks >= config.schedule.empty_checks && warmed_up;
+
+        // stop servers that have been empty for too long, regardless of the schedule
+        if let (Some(players), Some(idle_shutdown_minutes), true) = (
+            players.as_ref(),
+            self.config.idle_shutdown_minutes,
+            window == Window::Start,
+        ) {
+            match players {
+                Ok(0) if empty => {
+                    let idle = self.empty_since.get_or_insert_with(Instant::now).elapsed();
+                    if idle > Duration::from_secs(idle_shutdown_minutes * 60) {
+                        info!(
+                            "Server has been empty for more than {} minutes, shutting down",
+                            idle_shutdown_minutes
+                        );
+                        self.idle_stopped = self.stop(cloud, config).await;
+                    }
+                }
+                Ok(0) => {}
+                Ok(_) => {
+                    self.empty_since = None;
+                }
+                Err(e) => {
+                    debug!(error = %e, "Failed to get player count for idle check");
+                }
+            }
+        }
+
+        // we're between stop time and start time
+        if let (Some(ip), Some(players), true) = (
+            self.active.as_ref().map(Server::reachable_ip),
+            players.as_ref(),
+            window == Window::Stop,
+        ) {
+            let stop_elapsed = (Utc::now() - *self.start_of_stop_time.get_or_insert_with(Utc::now))
+                .to_std()
+                .unwrap_or_default();
+
+            let stop = if stop_elapsed >= stop_grace_time {
+                warn!("Server took longer than the grace time of {} seconds to empty, shutting down with players left", stop_grace_time.as_secs());
+                true
+            } else {
+                match players {
+                    Ok(0) if empty => true,
+                    Ok(0) if !warmed_up => {
+                        info!(
+                            "Server is empty, but was only started recently, waiting for players to join"
+                        );
+                        false
+                    }
+                    Ok(0) => {
+                        info!(
+                            checks = self.empty_checks,
+                            "Server is empty, waiting for the next check to confirm before stopping"
+                        );
+                        false
+                    }
+                    Ok(count) => {
+                        info!(
+                            "Want to stop server, but there are still {} active players",
+                            count
+                        );
+                        let remaining = stop_grace_time - stop_elapsed;
+                        let can_extend = !stop_extension.is_zero() && !self.stop_extended;
+                        let mut extended = false;
+                        if remaining <= stop_warning_time && can_extend {
+                            match self
+                                .check_extend_vote(ip, config.retry.rcon_timeout())
+                                .await
+                            {
+                                Ok(true) => {
+                                    info!(
+                                        "Players voted to extend, delaying the shutdown by {} seconds",
+                                        stop_extension.as_secs()
+                                    );
+                                    self.stop_extended = true;
+                                    self.last_stop_warning = None;
+                                    extended = true;
+                                    let message = format!(
+                                        "Shutdown extended by {} minutes",
+                                        stop_extension.as_secs().div_ceil(60)
+                                    );
+                                    if let Err(e) =
+                                        self.say(ip, config.retry.rcon_timeout(), &message).await
+                                    {
+                                        warn!(error = %e, "Failed to announce the extension");
+                                    }
+                                }
+                                Ok(false) => {}
+                                Err(e) => warn!(error = %e, "Failed to check the extend vote"),
+                            }
+                        }
+                        let warning_due = self
+                            .last_stop_warning
+                            .is_none_or(|last| last.elapsed() >= stop_warning_interval);
+                        if remaining <= stop_warning_time && warning_due && !extended {
+                            self.last_stop_warning = Some(Instant::now());
+                            let extension = can_extend.then_some(stop_extension);
+                            if let Err(e) = self
+                                .send_stop_warning(
+                                    ip,
+                                    config.retry.rcon_timeout(),
+                                    remaining,
+                                    extension,
+                                )
+                                .await
+                            {
+                                warn!(error = %e, "Failed to send shutdown warning");
+                            }
+                        }
+                        false
+                    }
+                    Err(Error::Rcon(::rcon::Error::Auth))
+                        if self.rcon_auth_failures >= RCON_AUTH_FAILURE_LIMIT =>
+                    {
+                        error!(
+                            failures = self.rcon_auth_failures,
+                            "Rcon password keeps getting rejected, shutting down without knowing the player count"
+                        );
+                        true
+                    }
+                    Err(Error::Rcon(::rcon::Error::Auth)) => {
+                        warn!(
+                            failures = self.rcon_auth_failures,
+                            "Rcon password rejected while trying to get the player count"
+                        );
+                        false
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "Failed to connect to rcon, the server might still be booting");
+                        false
+                    }
+                }
+            };
+            if stop {
+                self.stop(cloud, config).await;
+            }
+        }
+
+        METRICS.set_server(
+            &self.config.name,
+            self.active.is_some(),
+            players
+                .and_then(Result::ok)
+                .filter(|_| self.active.is_some()),
+        );
+    }
+
+    /// Check that the active server still exists, forgetting it if it was destroyed outside of dispenser
+    async fn check_active(&mut self, cloud: &dyn Cloud, config: &Config) {
+        let Some(active_server) = self.active.as_ref() else {
+            return;
+        };
+        match cloud.get(&active_server.id).await {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                warn!(
+                    id = display(&active_server.id),
+                    "Active server disappeared, it was probably destroyed outside of dispenser"
+                );
+                forget_credentials(config, &active_server.id);
+                self.active = None;
+                self.start_of_stop_time = None;
+                self.empty_since = None;
+                self.rcon = None;
+                self.password = None;
+                self.stop_extended = false;
+                self.vote_timelimit = None;
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to check if the active server still exists");
+            }
+        }
+    }
+
+    /// Stop the active server, returns whether the server was stopped
+    async fn stop(&mut self, cloud: &dyn Cloud, config: &Config) -> bool {
+        let Some(active_server) = self.active.as_ref() else {
+            return false;
+        };
+        let id = &active_server.id;
+        println!("Stopping server {}", id);
+        match destroy(cloud, config, active_server).await {
+            Ok(_) => {
+                METRICS.server_killed();
+                notify::notify_stop(&config.notifiers(), id).await;
+                self.active = None;
+                self.empty_since = None;
+                self.rcon = None;
+                self.password = None;
+                self.stop_extended = false;
+                self.vote_timelimit = None;
+                true
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                false
+            }
+        }
+    }
+
+    /// The open rcon connection to the active server, connecting if there is none
+    async fn rcon(&mut self, ip: IpAddr, rcon_timeout: Duration) -> Result<&mut Rcon, Error> {
+        if self.rcon.is_none() {
+            self.rcon = Some(Rcon::new((ip, 27015), &self.config.rcon, rcon_timeout).await?);
+        }
+        Ok(self.rcon.as_mut().unwrap())
+    }
+
+    /// Check if the players voted to extend the map, enabling the extend vote on the first check
+    ///
+    /// Rcon can't read the chat, so a passed vote is detected by the increased map time limit
+    async fn check_extend_vote(
+        &mut self,
+        ip: IpAddr,
+        rcon_timeout: Duration,
+    ) -> Result<bool, Error> {
+        let previous = self.vote_timelimit;
+        let result = async {
+            let rcon = self.rcon(ip, rcon_timeout).await?;
+            if previous.is_none() {
+                rcon.command("sv_vote_issue_extendlevel_allowed 1").await?;
+            }
+            rcon.cvar("mp_timelimit").await
+        }
+        .await;
+        let timelimit = match result {
+            Ok(value) => value.and_then(|value| value.parse::<f32>().ok()),
+            Err(e) => {
+                self.rcon = None;
+                return Err(e);
+            }
+        };
+        match (previous, timelimit) {
+            (Some(previous), Some(timelimit)) => Ok(timelimit > previous),
+            (None, timelimit) => {
+                self.vote_timelimit = timelimit;
+                Ok(false)
+            }
+            (Some(_), None) => Ok(false),
+        }
+    }
+
+    /// Get the player count, reusing the rcon connection from earlier checks
+    ///
+    /// If the kept connection stopped working, for example because the server restarted, a new connection is made
+    async fn player_count(&mut self, ip: IpAddr, rcon_timeout: Duration) -> Result<usize, Error> {
+        let filter = self.config.player_filter();
+        if let Some(rcon) = self.rcon.as_mut() {
+            match rcon.player_count_timeout(filter, rcon_timeout).await {
+                Ok(count) => return Ok(count),
+                Err(e) => {
+                    debug!(error = %e, "Kept rcon connection failed, reconnecting");
+                    self.rcon = None;
+                }
+            }
+        }
+        let result = self
+            .rcon(ip, rcon_timeout)
+            .await?
+            .player_count_timeout(filter, rcon_timeout)
+            .await;
+        if result.is_err() {
+            self.rcon = None;
+        }
+        result
+    }
+
+    /// Warn the players about the shutdown, mentioning the extend vote if the shutdown can still be extended
+    async fn send_stop_warning(
+        &mut self,
+        ip: IpAddr,
+        rcon_timeout: Duration,
+        remaining: Duration,
+        extension: Option<Duration>,
+    ) -> Result<(), Error> {
+        let minutes = remaining.as_secs().div_ceil(60);
+        let mut message = format!(
+            "Server is shutting down in {} minute{}",
+            minutes,
+            if minutes == 1 { "" } else { "s" }
+        );
+        if let Some(extension) = extension {
+            message.push_str(&format!(
+                ", call a vote to extend the map to keep it running for {} more minutes",
+                extension.as_secs().div_ceil(60)
+            ));
+        }
+        self.say(ip, rcon_timeout, &message).await
+    }
+
+    /// Broadcast a chat message on the active server
+    async fn say(
+        &mut self,
+        ip: IpAddr,
+        rcon_timeout: Duration,
+        message: &str,
+    ) -> Result<(), Error> {
+        let result = self.rcon(ip, rcon_timeout).await?.say(message).await;
+        if result.is_err() {
+            self.rcon = None;
+        }
+        result
+    }
+}
+
+#[instrument(skip_all, fields(name = %server_config.name))]
+async fn start(
+    cloud: &dyn Cloud,
+    config: &Config,
+    dns: Option<&DynDnsClient>,
+    server_config: &ServerConfig,
+    password: &str,
+    shutdown: &CancellationToken,
+    progress: Option<&Sender<ProgressEvent>>,
+) -> Result<Server, Error> {
+    let list: Vec<Server> = cloud
+        .list()
+        .await?
+        .into_iter()
+        .filter(|server| config.owns(server_config, server))
+        .collect();
+    let count = list.len();
+    let first = list.into_iter().next();
+    if let Some(first) = first {
+        warn!(
             "Non empty server list while starting: {:?}, and {} more",
             first,
             count - 1
         );
-        return Err(Error::AlreadyRunning(first));
+        return Err(Error::AlreadyRunning(Box::new(first)));
+    }
+
+    if let Err(e) = check_balance(cloud, config).await {
+        warn!(error = %e, "Account balance check failed, spawning the server might fail");
+    }
+
+    let attempts = config.retry.spawn_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        info!(attempt, attempts, server = %server_config.name, "Starting server");
+        // a failed server needs to be gone before the next attempt, or the next attempt would see it as already running
+        let cleanup = config.cleanup_on_failure || attempt < attempts;
+        progress::report(progress, ProgressEvent::Spawning { attempt });
+        match spawn_and_provision(
+            cloud,
+            config,
+            dns,
+            server_config,
+            password,
+            shutdown,
+            cleanup,
+            progress,
+        )
+        .await
+        {
+            Err(e) if attempt < attempts && !matches!(e, Error::Cancelled) => {
+                let delay = config.retry.spawn_retry_delay(attempt);
+                warn!(attempt, attempts, error = %e, "Starting the server failed, retrying in {}s", delay.as_secs());
+                progress::report(progress, ProgressEvent::Retrying { attempt, delay });
+                select! {
+                    _ = shutdown.cancelled() => {
+                        progress::report_outcome(
+                            progress,
+                            ProgressEvent::Failed {
+                                error: Error::Cancelled.to_string(),
+                            },
+                        )
+                        .await;
+                        return Err(Error::Cancelled);
+                    }
+                    _ = sleep(delay) => {},
+                }
+                attempt += 1;
+            }
+            Err(e) => {
+                progress::report_outcome(
+                    progress,
+                    ProgressEvent::Failed {
+                        error: e.to_string(),
+                    },
+                )
+                .await;
+                return Err(e);
+            }
+            Ok(server) => return Ok(server),
+        }
+    }
+}
+
+/// Create a new server and set it up, destroying it again if the setup is interrupted
+#[allow(clippy::too_many_arguments)]
+async fn spawn_and_provision(
+    cloud: &dyn Cloud,
+    config: &Config,
+    dns: Option<&DynDnsClient>,
+    server_config: &ServerConfig,
+    password: &str,
+    shutdown: &CancellationToken,
+    cleanup: bool,
+    progress: Option<&Sender<ProgressEvent>>,
+) -> Result<Server, Error> {
+    let deploy_key = server_config
+        .deploy_key_path
+        .as_ref()
+        .map(ssh::load_secret_key)
+        .transpose()?;
+    let user_data = server_config
+        .cloud_init
+        .then(|| cloud_init_script(server_config, password, &config.palantir, &config.retry));
+    let created = cloud
+        .spawn(&SpawnOptions {
+            label: &server_config.label(),
+            ssh_keys: &server_config.ssh_keys,
+            snapshot: server_config.snapshot.as_deref(),
+            deploy_key: deploy_key.as_ref(),
+            plan: config.plan_for(server_config),
+            user_data: user_data.as_deref(),
+        })
+        .await?;
+    METRICS.server_spawned();
+    progress::report(
+        progress,
+        ProgressEvent::WaitingForIp {
+            id: created.id.clone(),
+        },
+    );
+
+    let provisioned = async {
+        let server = match cloud
+            .wait_for_ip(
+                &created.id,
+                Duration::from_secs(config.retry.ip_timeout_secs),
+            )
+            .await
+        {
+            Ok(server) => server,
+            Err(e) => {
+                // without an ip the server can't be set up, don't leave it running and billing
+                warn!(id = %created.id, error = %e, "Server didn't get an ip, destroying it");
+                if let Err(kill_error) = discard(cloud, config, server_config, &created.id).await {
+                    error!(id = %created.id, error = %kill_error, "Failed to destroy the server");
+                }
+                return Err(e.into());
+            }
+        };
+        let result = provision(
+            cloud,
+            config,
+            dns,
+            server_config,
+            password,
+            &created,
+            server,
+            progress,
+        )
+        .await;
+        if let Err(e) = &result {
+            if cleanup {
+                warn!(id = %created.id, error = %e, "Setup failed, destroying the partially provisioned server");
+                if let Err(kill_error) = discard(cloud, config, server_config, &created.id).await {
+                    error!(id = %created.id, error = %kill_error, "Failed to destroy the server");
+                }
+            }
+        }
+        result
+    };
+
+    // don't leave a half provisioned server running when shutting down during the setup
+    select! {
+        biased;
+        _ = shutdown.cancelled() => {
+            warn!(id = %created.id, "Setup cancelled, destroying the partially created server");
+            discard(cloud, config, server_config, &created.id).await?;
+            Err(Error::Cancelled)
+        }
+        result = provisioned => result,
     }
+}
 
-    let created = cloud.spawn(&config.server.ssh_keys).await?;
-    let server = cloud.wait_for_ip(&created.id).await?;
+/// Destroy a server that wasn't fully provisioned
+async fn discard(
+    cloud: &dyn Cloud,
+    config: &Config,
+    server_config: &ServerConfig,
+    id: &str,
+) -> Result<(), CloudError> {
+    if let Some(ip) = server_config.reserved_ip {
+        // the ip might not have been attached yet
+        if let Err(e) = cloud.detach_ip(ip).await {
+            debug!(error = %e, ip = %ip, "Failed to detach reserved ip");
+        }
+    }
+    cloud.kill(id).await?;
+    forget_credentials(config, id);
+    Ok(())
+}
 
-    println!("Server is booting");
-    println!("  IP: {}", server.ip);
-    println!("  Root Password: {}", created.auth);
+/// Set up a newly created server once it has an ip
+#[allow(clippy::too_many_arguments)]
+async fn provision(
+    cloud: &dyn Cloud,
+    config: &Config,
+    dns: Option<&DynDnsClient>,
+    server_config: &ServerConfig,
+    password: &str,
+    created: &Created,
+    server: Server,
+    progress: Option<&Sender<ProgressEvent>>,
+) -> Result<Server, Error> {
+    progress::report(
+        progress,
+        ProgressEvent::Booting {
+            id: server.id.clone(),
+            ip: server.reachable_ip(),
+        },
+    );
+    if !config.json_output {
+        println!("Server is booting");
+        println!("  IP: {}", server.reachable_ip());
+        println!("  Root Password: {}", created.auth);
+    }
+
+    if let Some(ip) = server_config.reserved_ip {
+        cloud.attach_ip(&server.id, ip).await?;
+        if !config.json_output {
+            println!("  Reserved IP: {}", ip);
+        }
+    }
 
-    let connect_host = if let Some(dns_config) = config.dyndns.as_ref() {
-        spawn(set_dyndns(dns_config.clone(), server.ip));
-        dns_config.hostname.to_string()
+    if config.dry_run {
+        info!("dry run: would set up the server over ssh");
+        return Ok(server);
+    }
+
+    if let Some(path) = &config.credentials_file {
+        let result = ServerCredentials::new(&server_config.name, &server, &created.auth)
+            .and_then(|credentials| Credentials::add(path, &created.id, credentials));
+        if let Err(e) = result {
+            error!(error = %e, "Failed to save server credentials");
+        }
+    }
+
+    let hostname = config
+        .dyndns_for(server_config)
+        .map(|dns| dns.hostname_for(&server));
+    update_dyndns(dns, config, server_config, &server);
+
+    let ready_timeout = if server_config.cloud_init {
+        // the setup is still running on the server, which includes booting and pulling the image
+        Duration::from_secs(config.retry.boot_timeout_secs + config.retry.ready_timeout_secs)
     } else {
-        format!("{}", server.ip)
+        let host_key = HostKeyCheck::new(server_config.host_key_verification, None);
+        progress::report(progress, ProgressEvent::ConnectingSsh);
+        let mut ssh = connect_ssh_or_reboot(
+            cloud,
+            &created.id,
+            server.reachable_ip(),
+            &server_config.ssh_user,
+            &created.auth,
+            &host_key,
+            &config.retry,
+        )
+        .await?;
+        if let (Some(path), Some(fingerprint)) = (&config.credentials_file, host_key.fingerprint())
+        {
+            if let Err(e) = Credentials::set_host_key(path, &created.id, fingerprint) {
+                error!(error = %e, "Failed to save the server host key");
+            }
+        }
+        setup(
+            &mut ssh,
+            server_config,
+            password,
+            &config.palantir,
+            &config.retry,
+            hostname.as_deref(),
+            progress,
+        )
+        .await?;
+        ssh.close().await?;
+        Duration::from_secs(config.retry.ready_timeout_secs)
     };
 
-    let mut ssh = connect_ssh(server.ip, &created.auth).await?;
-    setup(
-        &mut ssh,
-        &config.server,
-        config.dyndns.as_ref().map(|dns| dns.hostname.as_str()),
+    if server_config.cloud_init {
+        progress::report(progress, ProgressEvent::CloudInit);
+    }
+    if !config.json_output {
+        if server_config.cloud_init {
+            println!("Server is being setup trough cloud-init");
+        } else {
+            println!("Server has been setup and is starting");
+        }
+    }
+    progress::report(progress, ProgressEvent::WaitingForGameServer);
+    if wait_for_game_server(&server, server_config, &config.retry, ready_timeout).await {
+        if !config.json_output {
+            println!("Server is ready");
+        }
+    } else {
+        warn!("game server didn't accept rcon connections in time, it might still be loading");
+    }
+
+    let connect = connect_string(config, server_config, &server, password);
+    if !config.json_output {
+        println!("Connect using");
+        println!("  {}", connect);
+    }
+    progress::report_outcome(
+        progress,
+        ProgressEvent::Ready {
+            id: server.id.clone(),
+            connect: connect.clone(),
+        },
     )
-    .await?;
-    ssh.close().await?;
-
-    println!("Server has been setup and is starting");
-    println!("Connect using");
-    println!(
-        "  connect {}; password {}",
-        connect_host, config.server.password
-    );
+    .await;
+
+    if let Some(url) = config.ready_webhook_url() {
+        let payload = notify::ReadyPayload {
+            ip: server.reachable_ip(),
+            ip_v6: server.ip_v6,
+            hostname: hostname.as_deref(),
+            connect: &connect,
+            password,
+        };
+        if let Err(e) = notify::notify_ready(url, &payload).await {
+            warn!(error = %e, "failed to send ready webhook");
+        }
+    }
     Ok(server)
 }
 
-async fn set_dyndns(dns_config: DynDnsConfig, ip: IpAddr) {
-    let dns = DynDnsClient::new(
-        dns_config.update_url,
-        dns_config.username,
-        dns_config.password,
-    );
-    println!(
-        "Updating DynDNS entry for {} to {}",
-        dns_config.hostname, ip
-    );
-    if let Err(e) = dns.update(&dns_config.hostname, ip).await {
-        eprintln!("Error while updating DynDNS: {}", e);
+/// Wait until the game server inside the container accepts rcon connections
+///
+/// Returns false if the server didn't respond before the timeout
+async fn wait_for_game_server(
+    server: &Server,
+    server_config: &ServerConfig,
+    retry: &RetryConfig,
+    timeout: Duration,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match Rcon::new(
+            (server.reachable_ip(), 27015),
+            &server_config.rcon,
+            retry.rcon_timeout(),
+        )
+        .await
+        {
+            // a rejected password still means the server is up and listening
+            Ok(_) | Err(Error::Rcon(::rcon::Error::Auth)) => return true,
+            Err(e) => info!(error = %e, "game server not ready yet"),
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Cancel the token once ctrl-c is received
+async fn cancel_on_ctrl_c(token: CancellationToken) {
+    if ctrl_c().await.is_ok() {
+        token.cancel();
+    }
+}
+
+fn connect_string(
+    config: &Config,
+    server_config: &ServerConfig,
+    server: &Server,
+    password: &str,
+) -> String {
+    let connect_host = if let Some(dns_config) = config.dyndns_for(server_config) {
+        dns_config.hostname_for(server)
+    } else if let Some(ip) = server_config.reserved_ip {
+        format!("{}", ip)
+    } else {
+        let ip = match server.ip_v6 {
+            Some(ip_v6) if server_config.prefer_ipv6 => ip_v6,
+            _ => server.reachable_ip(),
+        };
+        match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        }
+    };
+    format!("connect {}; password {}", connect_host, password)
+}
+
+/// Load the daemon state, rotated passwords are only known from the daemon state
+fn load_state(config: &Config) -> DaemonState {
+    match config.state_file.as_deref().map(DaemonState::load) {
+        Some(Ok(state)) => state,
+        Some(Err(e)) => {
+            warn!(error = %e, "Failed to load daemon state");
+            DaemonState::default()
+        }
+        None => DaemonState::default(),
+    }
+}
+
+/// The connect password of a running server
+///
+/// Rotated passwords are only known if the server is in the daemon state
+fn server_password<'a>(
+    server_config: &'a ServerConfig,
+    server: &Server,
+    state: &'a DaemonState,
+) -> Option<&'a str> {
+    let persisted = state
+        .servers
+        .get(&server_config.name)
+        .filter(|persisted| persisted.server_id == server.id)
+        .and_then(|persisted| persisted.password.as_deref());
+    match persisted {
+        Some(password) => Some(password),
+        None if server_config.rotate_password => None,
+        None => Some(&server_config.password),
+    }
+}
+
+/// Format a duration as a short human readable string, like "3h12m"
+fn format_duration(duration: chrono::Duration) -> String {
+    let minutes = duration.num_minutes().max(0);
+    let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+    let mut formatted = String::new();
+    if days > 0 {
+        formatted.push_str(&format!("{}d", days));
+    }
+    if hours > 0 {
+        formatted.push_str(&format!("{}h", hours));
+    }
+    if minutes > 0 || formatted.is_empty() {
+        formatted.push_str(&format!("{}m", minutes));
+    }
+    formatted
+}
+
+/// The hourly price of a plan, from the config or the provider
+async fn hourly_price(cloud: &dyn Cloud, config: &Config, plan: &str) -> Option<f64> {
+    if let Some(price) = config.plan_price(plan) {
+        return Some(price);
+    }
+    match cloud.hourly_price(plan).await {
+        Ok(price) => price,
+        Err(e) => {
+            warn!(plan = plan, error = %e, "Failed to get the price of the plan");
+            None
+        }
+    }
+}
+
+/// Print the result of a validation step, returns whether the step passed
+fn check<E: Display>(name: &str, result: Result<(), E>) -> bool {
+    match result {
+        Ok(()) => {
+            println!("[x] {}", name);
+            true
+        }
+        Err(e) => {
+            println!("[ ] {}: {}", name, e);
+            false
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+enum BalanceError {
+    #[error("Balance of {0:.2} is below the configured minimum of {1:.2}")]
+    Low(f64, f64),
+    #[error("Failed to get account balance: {0}")]
+    Cloud(#[from] CloudError),
+}
+
+/// Check the account balance against the configured minimum, if the provider reports the balance
+async fn check_balance(cloud: &dyn Cloud, config: &Config) -> Result<(), BalanceError> {
+    let Some(min_balance) = config.min_balance else {
+        return Ok(());
+    };
+    match cloud.account_balance().await? {
+        Some(balance) if balance < min_balance => Err(BalanceError::Low(balance, min_balance)),
+        _ => Ok(()),
+    }
+}
+
+/// Destroy a server, detaching its reserved ip first and removing it from the credentials file
+async fn destroy(cloud: &dyn Cloud, config: &Config, server: &Server) -> Result<(), CloudError> {
+    if let Some(ip) = config
+        .server_config_for(server)
+        .and_then(|server_config| server_config.reserved_ip)
+    {
+        if let Err(e) = cloud.detach_ip(ip).await {
+            warn!(error = %e, ip = %ip, "Failed to detach reserved ip");
+        }
+    }
+    cloud.kill(&server.id).await?;
+    forget_credentials(config, &server.id);
+    Ok(())
+}
+
+/// Authentication for manually connecting to a server, from the given private key or the credentials file
+fn ssh_auth(
+    config: &Config,
+    server: &Server,
+    identity: Option<&str>,
+) -> Result<CreatedAuth, Error> {
+    if let Some(identity) = identity {
+        return Ok(ssh::load_key(identity)?);
+    }
+    let saved = match &config.credentials_file {
+        Some(path) => Credentials::get(path, &server.id)?,
+        None => None,
+    };
+    match saved {
+        Some(credentials) => credentials
+            .auth()?
+            .ok_or_else(|| Error::NoCredentials(server.id.clone())),
+        None => Err(Error::NoCredentials(server.id.clone())),
     }
 }
 
-async fn connect_ssh(ip: IpAddr, auth: &CreatedAuth) -> Result<SshSession, Error> {
+/// Host key check for manually connecting to a server, using the host key saved while setting up the server
+fn known_host_key(
+    config: &Config,
+    server_config: &ServerConfig,
+    server: &Server,
+) -> Result<HostKeyCheck, Error> {
+    let saved = match &config.credentials_file {
+        Some(path) => Credentials::get(path, &server.id)?.and_then(|saved| saved.host_key),
+        None => None,
+    };
+    if saved.is_none()
+        && !matches!(
+            server_config.host_key_verification,
+            HostKeyVerification::None
+        )
+    {
+        warn!(
+            id = display(&server.id),
+            "No saved host key for the server, accepting any host key"
+        );
+    }
+    Ok(HostKeyCheck::new(
+        server_config.host_key_verification,
+        saved,
+    ))
+}
+
+/// Remove a destroyed server from the credentials file, if configured
+fn forget_credentials(config: &Config, id: &str) {
+    if let Some(path) = &config.credentials_file {
+        if let Err(e) = Credentials::remove(path, id) {
+            error!(error = %e, "Failed to remove server credentials");
+        }
+    }
+}
+
+/// Create the dyndns client once, so the connection pool is reused between updates
+fn dyndns_client(config: &Config) -> Option<DynDnsClient> {
+    config.dyndns.as_ref().map(|dns_config| {
+        DynDnsClient::new(
+            dns_config.update_url.clone(),
+            dns_config
+                .auth()
+                .expect("dyndns auth is checked when loading the config"),
+        )
+    })
+}
+
+/// Point the dyndns hostname to the server in the background, if dyndns is configured for it
+fn update_dyndns(
+    dns: Option<&DynDnsClient>,
+    config: &Config,
+    server_config: &ServerConfig,
+    server: &Server,
+) {
+    if let (Some(dns), Some(dns_config)) = (dns, config.dyndns_for(server_config)) {
+        spawn(set_dyndns(
+            dns.clone(),
+            dns_config.hostname_for(server),
+            server.ip,
+            server.ip_v6,
+        ));
+    }
+}
+
+async fn set_dyndns(dns: DynDnsClient, hostname: String, ip: IpAddr, ip_v6: Option<IpAddr>) {
+    // update the A and AAAA records independently, a failure for one shouldn't block the other
+    // ipv6-only servers don't have an ipv4 address to point the A record to
+    let ip = Some(ip).filter(|ip| !ip.is_unspecified());
+    for ip in ip.into_iter().chain(ip_v6) {
+        println!("Updating DynDNS entry for {} to {}", hostname, ip);
+        if let Err(e) = dns.update(&hostname, ip).await {
+            eprintln!("Error while updating DynDNS: {}", e);
+        }
+    }
+}
+
+/// Connect to a newly created server, rebooting it once if ssh doesn't come up
+///
+/// Fresh servers occasionally boot into a broken state where ssh never starts
+async fn connect_ssh_or_reboot(
+    cloud: &dyn Cloud,
+    id: &str,
+    ip: IpAddr,
+    user: &str,
+    auth: &CreatedAuth,
+    host_key: &HostKeyCheck,
+    retry: &RetryConfig,
+) -> Result<SshSession, Error> {
+    match connect_ssh(ip, user, auth, host_key, retry).await {
+        Ok(ssh) => Ok(ssh),
+        Err(e) => {
+            match cloud.reboot(id).await {
+                Ok(()) => {}
+                Err(CloudError::Unsupported(_)) => return Err(e),
+                Err(reboot_error) => {
+                    error!(error = %reboot_error, "Failed to reboot server");
+                    return Err(e);
+                }
+            }
+            warn!(error = %e, "Rebooted server after failing to connect to ssh");
+            connect_ssh(ip, user, auth, host_key, retry).await
+        }
+    }
+}
+
+async fn connect_ssh(
+    ip: IpAddr,
+    user: &str,
+    auth: &CreatedAuth,
+    host_key: &HostKeyCheck,
+    retry: &RetryConfig,
+) -> Result<SshSession, Error> {
     let mut tries = 0;
 
     loop {
         tries += 1;
-        sleep(Duration::from_secs(5)).await;
+        sleep(Duration::from_secs(retry.ssh_connect_delay_secs)).await;
 
-        match SshSession::open(ip, auth).await {
+        match SshSession::open(
+            ip,
+            user,
+            auth,
+            host_key,
+            Duration::from_secs(retry.boot_timeout_secs),
+        )
+        .await
+        {
             Ok(ssh) => {
                 return Ok(ssh);
             }
-            Err(e) if tries > 5 => {
+            Err(e) if tries >= retry.ssh_connect_attempts => {
                 error!(
                     tries = tries,
                     error = %e,
@@ -410,3 +2511,506 @@ async fn connect_ssh(ip: IpAddr, auth: &CreatedAuth) -> Result<SshSession, Error
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud::mock::MockCloud;
+    use tokio::time::timeout;
+
+    fn config(manage_existing: bool, start: &str, stop: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            [server]
+            rcon = "rcon"
+            password = "password"
+            demostf_key = "demostf"
+            logstf_key = "logstf"
+            manage_existing = {manage_existing}
+
+            [schedule]
+            start = "{start}"
+            stop = "{stop}"
+            stop_grace_time = 0
+            "#
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_start_already_running() {
+        let cloud = MockCloud::with_servers(vec![MockCloud::server("existing")]);
+        let config = config(false, "0 0 0 * * * 2091", "0 0 0 * * * 2090");
+
+        match start(
+            &cloud,
+            &config,
+            None,
+            &config.servers[0],
+            &config.servers[0].password,
+            &CancellationToken::new(),
+            None,
+        )
+        .await
+        {
+            Err(Error::AlreadyRunning(server)) => assert_eq!(server.id, "existing"),
+            res => panic!(
+                "expected AlreadyRunning, got {:?}",
+                res.map(|server| server.id)
+            ),
+        }
+        assert_eq!(cloud.spawn_count(), 0);
+        assert_eq!(cloud.servers().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_start_multiple_servers() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [[server]]
+            name = "First"
+            rcon = "rcon"
+            password = "password"
+            demostf_key = "demostf"
+            logstf_key = "logstf"
+
+            [[server]]
+            name = "Second Server"
+            rcon = "rcon"
+            password = "password"
+            demostf_key = "demostf"
+            logstf_key = "logstf"
+
+            [schedule]
+            start = "0 0 0 * * * 2090"
+            stop = "0 0 0 * * * 2091"
+            "#,
+        )
+        .unwrap();
+        let cloud = MockCloud::with_servers(vec![MockCloud::server("first")]);
+
+        // the running server belongs to the first config, so it only blocks starting the first config
+        assert!(matches!(
+            start(
+                &cloud,
+                &config,
+                None,
+                &config.servers[0],
+                &config.servers[0].password,
+                &CancellationToken::new(),
+                None
+            )
+            .await,
+            Err(Error::AlreadyRunning(_))
+        ));
+        // skip the ssh setup, the second server only needs to get spawned
+        config.dry_run = true;
+        let started = start(
+            &cloud,
+            &config,
+            None,
+            &config.servers[1],
+            &config.servers[1].password,
+            &CancellationToken::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(cloud.spawn_count(), 1);
+        assert_eq!(cloud.servers().len(), 2);
+        assert_eq!(started.label, "second-server");
+
+        let mut other = MockCloud::server("other");
+        other.label = "second-server".into();
+        let cloud = MockCloud::with_servers(vec![other]);
+        assert!(matches!(
+            start(
+                &cloud,
+                &config,
+                None,
+                &config.servers[1],
+                &config.servers[1].password,
+                &CancellationToken::new(),
+                None
+            )
+            .await,
+            Err(Error::AlreadyRunning(_))
+        ));
+        assert_eq!(
+            config
+                .server_config_for(&MockCloud::server("first"))
+                .unwrap()
+                .name,
+            "First"
+        );
+        assert!(config
+            .server_config_for(&MockCloud::server("unknown"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(chrono::Duration::minutes(192)), "3h12m");
+        assert_eq!(format_duration(chrono::Duration::hours(6)), "6h");
+        assert_eq!(format_duration(chrono::Duration::seconds(30)), "0m");
+        assert_eq!(format_duration(chrono::Duration::hours(50)), "2d2h");
+    }
+
+    #[test]
+    fn test_connect_string_ipv6_only() {
+        let config = config(false, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        let mut server = MockCloud::server("v6");
+        server.ip = IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
+        server.ip_v6 = Some("2001:db8::1".parse().unwrap());
+        assert_eq!(
+            connect_string(
+                &config,
+                &config.servers[0],
+                &server,
+                &config.servers[0].password
+            ),
+            format!(
+                "connect [2001:db8::1]; password {}",
+                config.servers[0].password
+            )
+        );
+    }
+
+    #[test]
+    fn test_rotated_password() {
+        let mut config = config(false, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        let server = MockCloud::server("1234");
+        let mut state = DaemonState::default();
+        assert_eq!(
+            server_password(&config.servers[0], &server, &state),
+            Some(config.servers[0].password.as_str())
+        );
+
+        config.servers[0].rotate_password = true;
+        let password = config.servers[0].new_password();
+        assert_ne!(password, config.servers[0].password);
+        assert_eq!(server_password(&config.servers[0], &server, &state), None);
+
+        state.servers.insert(
+            config.servers[0].name.clone(),
+            ServerState {
+                server_id: server.id.clone(),
+                spawned: server.created,
+                stop_started: None,
+                empty_checks: 0,
+                password: Some(password.clone()),
+                stop_extended: false,
+            },
+        );
+        assert_eq!(
+            server_password(&config.servers[0], &server, &state),
+            Some(password.as_str())
+        );
+    }
+
+    #[test]
+    fn test_cloud_init_script() {
+        let config = config(false, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        let script = cloud_init_script(
+            &config.servers[0],
+            &config.servers[0].password,
+            &config.palantir,
+            &config.retry,
+        );
+        let lines: Vec<&str> = script.lines().collect();
+        assert_eq!(lines[..2], ["#!/bin/bash", "set -e"]);
+        assert!(lines[2].contains("docker pull spiretf/docker-spire-server"));
+        assert!(lines[4].starts_with("docker run --name spire -d"));
+        assert!(!lines[4].contains('\n'));
+        assert_eq!(lines[5], "set +e");
+        assert_eq!(lines.last(), Some(&"systemctl enable --now palantir"));
+    }
+
+    #[test]
+    fn test_docker_run_command_quoting() {
+        let config = config(false, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        let command = docker_run_command(&config.servers[0], "it's; rm -rf /");
+        assert!(command.contains(r"-e 'PASSWORD=it'\''s; rm -rf /'"));
+        assert!(command.contains("-e 'NAME="));
+    }
+
+    #[tokio::test]
+    async fn test_start_cancelled() {
+        let cloud = MockCloud::never_ready();
+        let config = config(false, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+
+        assert!(matches!(
+            start(
+                &cloud,
+                &config,
+                None,
+                &config.servers[0],
+                &config.servers[0].password,
+                &shutdown,
+                None
+            )
+            .await,
+            Err(Error::Cancelled)
+        ));
+        assert_eq!(cloud.spawn_count(), 1);
+        assert!(cloud.servers().is_empty());
+    }
+
+    #[test]
+    fn test_setup_error_step() {
+        assert_eq!(
+            Error::setup(SetupStep::DockerRun, "port is already allocated").to_string(),
+            "Setup failed at step docker_run: port is already allocated"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_retries_failed_spawn() {
+        let cloud = MockCloud::never_ready();
+        let mut config = config(false, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        config.retry.ip_timeout_secs = 1;
+        config.retry.spawn_attempts = 3;
+        config.retry.spawn_retry_delay_secs = 0;
+
+        assert!(matches!(
+            start(
+                &cloud,
+                &config,
+                None,
+                &config.servers[0],
+                &config.servers[0].password,
+                &CancellationToken::new(),
+                None
+            )
+            .await,
+            Err(Error::Cloud(CloudError::StartTimeout))
+        ));
+        assert_eq!(cloud.spawn_count(), 3);
+        assert!(cloud.servers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_start_reports_progress() {
+        let cloud = MockCloud::default();
+        let mut config = config(false, "0 0 0 * * * 2091", "0 0 0 * * * 2090");
+        config.dry_run = true;
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+
+        start(
+            &cloud,
+            &config,
+            None,
+            &config.servers[0],
+            &config.servers[0].password,
+            &CancellationToken::new(),
+            Some(&sender),
+        )
+        .await
+        .unwrap();
+        drop(sender);
+
+        let mut events = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                ProgressEvent::Spawning { attempt: 1 },
+                ProgressEvent::WaitingForIp {
+                    id: "mock-1".into()
+                },
+                ProgressEvent::Booting {
+                    id: "mock-1".into(),
+                    ip: "127.0.0.1".parse().unwrap()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_ip_timeout_destroys_server() {
+        let cloud = MockCloud::never_ready();
+        let mut config = config(false, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        config.retry.ip_timeout_secs = 1;
+
+        assert!(matches!(
+            start(
+                &cloud,
+                &config,
+                None,
+                &config.servers[0],
+                &config.servers[0].password,
+                &CancellationToken::new(),
+                None
+            )
+            .await,
+            Err(Error::Cloud(CloudError::StartTimeout))
+        ));
+        assert_eq!(cloud.spawn_count(), 1);
+        assert!(cloud.servers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_start_reports_failure() {
+        let cloud = MockCloud::never_ready();
+        let mut config = config(false, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        config.retry.ip_timeout_secs = 1;
+        // a small buffer can drop intermediate events, but not the outcome
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let collector = spawn(async move {
+            let mut events = Vec::new();
+            while let Some(event) = receiver.recv().await {
+                events.push(event);
+            }
+            events
+        });
+
+        let result = start(
+            &cloud,
+            &config,
+            None,
+            &config.servers[0],
+            &config.servers[0].password,
+            &CancellationToken::new(),
+            Some(&sender),
+        )
+        .await;
+        drop(sender);
+        assert!(result.is_err());
+
+        let events = collector.await.unwrap();
+        assert_eq!(
+            events.last(),
+            Some(&ProgressEvent::Failed {
+                error: Error::Cloud(CloudError::StartTimeout).to_string()
+            })
+        );
+    }
+
+    async fn run_loop_in_stop_window(manage_existing: bool) -> Arc<MockCloud> {
+        let cloud = Arc::new(MockCloud::with_servers(vec![MockCloud::server("existing")]));
+        // the next stop is after the next start, so the server should be stopped
+        let config = config(manage_existing, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        let (start_schedule, stop_schedule) = config.schedule.schedules().unwrap();
+
+        let _ = timeout(
+            Duration::from_secs(1),
+            run_loop(
+                cloud.clone(),
+                config,
+                None,
+                start_schedule,
+                stop_schedule,
+                CancellationToken::new(),
+                false,
+            ),
+        )
+        .await;
+        cloud
+    }
+
+    #[tokio::test]
+    async fn test_run_loop_takes_ownership() {
+        let cloud = run_loop_in_stop_window(true).await;
+        assert!(cloud.servers().is_empty());
+        assert_eq!(cloud.spawn_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_run_loop_ignores_unmanaged() {
+        let cloud = run_loop_in_stop_window(false).await;
+        assert_eq!(cloud.servers().len(), 1);
+        assert_eq!(cloud.spawn_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tick_forgets_vanished_server() {
+        let cloud = MockCloud::with_servers(Vec::new());
+        let config = config(false, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        let mut managed = ManagedServer {
+            config: &config.servers[0],
+            active: Some(MockCloud::server("vanished")),
+            start_of_stop_time: Some(Utc::now()),
+            last_stop_warning: None,
+            empty_since: None,
+            idle_stopped: false,
+            rcon_auth_failures: 0,
+            empty_checks: 0,
+            rcon: None,
+            password: None,
+            stop_extended: false,
+            vote_timelimit: None,
+        };
+        managed
+            .tick(
+                &cloud,
+                &config,
+                None,
+                &CancellationToken::new(),
+                Window::Stop,
+            )
+            .await;
+        assert!(managed.active.is_none());
+        assert!(managed.start_of_stop_time.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_run_loop_replaces_deleted_server() {
+        let cloud = Arc::new(MockCloud::with_servers(vec![MockCloud::server("existing")]));
+        // the next start is after the next stop, so the server should be running
+        let mut config = config(true, "0 0 0 * * * 2091", "0 0 0 * * * 2090");
+        config.schedule.poll_interval_secs = 1;
+        config.dry_run = true;
+        let (start_schedule, stop_schedule) = config.schedule.schedules().unwrap();
+
+        let shutdown = CancellationToken::new();
+        let daemon = tokio::spawn(run_loop(
+            cloud.clone(),
+            config,
+            None,
+            start_schedule,
+            stop_schedule,
+            shutdown.clone(),
+            false,
+        ));
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(cloud.spawn_count(), 0);
+
+        // delete the server behind the daemon's back
+        cloud.kill("existing").await.unwrap();
+        sleep(Duration::from_secs(2)).await;
+        shutdown.cancel();
+        daemon.await.unwrap().unwrap();
+
+        assert_eq!(cloud.spawn_count(), 1);
+        let servers = cloud.servers();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0].id, "mock-1");
+    }
+
+    #[tokio::test]
+    async fn test_run_loop_once() {
+        let cloud = Arc::new(MockCloud::with_servers(vec![MockCloud::server("existing")]));
+        let config = config(true, "0 0 0 * * * 2090", "0 0 0 * * * 2091");
+        let (start_schedule, stop_schedule) = config.schedule.schedules().unwrap();
+
+        let finished = timeout(
+            Duration::from_secs(5),
+            run_loop(
+                cloud.clone(),
+                config,
+                None,
+                start_schedule,
+                stop_schedule,
+                CancellationToken::new(),
+                true,
+            ),
+        )
+        .await;
+        assert!(matches!(finished, Ok(Ok(()))));
+        assert!(cloud.servers().is_empty());
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::spawn;
+use tracing::{error, info, warn};
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// Metrics about the state of the daemon
+pub struct Metrics {
+    servers_spawned: AtomicU64,
+    servers_killed: AtomicU64,
+    spawn_failures: AtomicU64,
+    servers: Mutex<BTreeMap<String, ServerMetrics>>,
+}
+
+#[derive(Default, Clone, Copy)]
+struct ServerMetrics {
+    running: bool,
+    players: Option<usize>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            servers_spawned: AtomicU64::new(0),
+            servers_killed: AtomicU64::new(0),
+            spawn_failures: AtomicU64::new(0),
+            servers: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn server_spawned(&self) {
+        self.servers_spawned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn server_killed(&self) {
+        self.servers_killed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn spawn_failed(&self) {
+        self.spawn_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Update the state of a managed server
+    pub fn set_server(&self, name: &str, running: bool, players: Option<usize>) {
+        self.servers
+            .lock()
+            .unwrap()
+            .insert(name.into(), ServerMetrics { running, players });
+    }
+
+    /// Render the metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let servers = self.servers.lock().unwrap().clone();
+
+        out.push_str("# HELP dispenser_server_running Whether the server is running\n");
+        out.push_str("# TYPE dispenser_server_running gauge\n");
+        for (name, server) in &servers {
+            let _ = writeln!(
+                out,
+                "dispenser_server_running{{server=\"{}\"}} {}",
+                escape_label(name),
+                u8::from(server.running)
+            );
+        }
+
+        out.push_str("# HELP dispenser_current_players Number of players on the server\n");
+        out.push_str("# TYPE dispenser_current_players gauge\n");
+        for (name, server) in &servers {
+            if let Some(players) = server.players {
+                let _ = writeln!(
+                    out,
+                    "dispenser_current_players{{server=\"{}\"}} {}",
+                    escape_label(name),
+                    players
+                );
+            }
+        }
+
+        for (name, help, counter) in [
+            (
+                "dispenser_servers_spawned_total",
+                "Number of servers spawned",
+                &self.servers_spawned,
+            ),
+            (
+                "dispenser_servers_killed_total",
+                "Number of servers killed",
+                &self.servers_killed,
+            ),
+            (
+                "dispenser_spawn_failures_total",
+                "Number of failed attempts to start a server",
+                &self.spawn_failures,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Serve the metrics over http
+pub async fn serve(addr: SocketAddr) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(error = %e, addr = %addr, "Failed to bind metrics listener");
+            return;
+        }
+    };
+    info!(addr = %addr, "serving metrics");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                spawn(async move {
+                    if let Err(e) = handle(stream).await {
+                        warn!(error = %e, "Error while serving metrics");
+                    }
+                });
+            }
+            Err(e) => warn!(error = %e, "Failed to accept metrics connection"),
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut buffer = [0; 1024];
+    let read = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let path = request.split(' ').nth(1).unwrap_or_default();
+
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", METRICS.render())
+    } else {
+        ("404 Not Found", String::from("Not Found\n"))
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.server_spawned();
+        metrics.set_server("Spire \"1\"", true, Some(12));
+        metrics.set_server("Other", false, None);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("dispenser_server_running{server=\"Spire \\\"1\\\"\"} 1\n"));
+        assert!(rendered.contains("dispenser_server_running{server=\"Other\"} 0\n"));
+        assert!(rendered.contains("dispenser_current_players{server=\"Spire \\\"1\\\"\"} 12\n"));
+        assert!(!rendered.contains("dispenser_current_players{server=\"Other\"}"));
+        assert!(rendered.contains("dispenser_servers_spawned_total 1\n"));
+        assert!(rendered.contains("dispenser_spawn_failures_total 0\n"));
+    }
+}
@@ -0,0 +1,297 @@
+use crate::cloud::Server;
+use crate::config::MatrixConfig;
+use crate::http;
+use async_trait::async_trait;
+use rand::{thread_rng, Rng};
+use reqwest::{Client, RequestBuilder, Url};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::sleep;
+use tracing::warn;
+
+pub type Result<T, E = NotifyError> = std::result::Result<T, E>;
+
+#[derive(Debug, Error)]
+pub enum NotifyError {
+    #[error("Network error: {0}")]
+    Network(#[from] NetworkError),
+    #[error("Webhook rejected the notification: {0}")]
+    Rejected(String),
+    #[error("Invalid url \"{0}\"")]
+    InvalidUrl(String),
+}
+
+/// Intentionally opaque error
+#[derive(Debug, Error)]
+#[error("{}", http::describe_error(.0))]
+pub struct NetworkError(reqwest::Error);
+
+const COLOR_STARTED: u32 = 0x2ecc71;
+const COLOR_STOPPED: u32 = 0xe74c3c;
+
+const READY_WEBHOOK_ATTEMPTS: u32 = 3;
+const READY_WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Http client shared between all notifications, so the connection pool is reused
+fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(http::client)
+}
+
+/// Connect details that are posted to the ready webhook
+#[derive(Debug, Serialize)]
+pub struct ReadyPayload<'a> {
+    pub ip: IpAddr,
+    pub ip_v6: Option<IpAddr>,
+    pub hostname: Option<&'a str>,
+    pub connect: &'a str,
+    pub password: &'a str,
+}
+
+/// Post the connect details of a server that finished setup, retrying a couple of times on failure
+pub async fn notify_ready(url: &str, payload: &ReadyPayload<'_>) -> Result<()> {
+    let mut tries = 0;
+    loop {
+        tries += 1;
+        match post_json(url, payload).await {
+            Err(e) if tries < READY_WEBHOOK_ATTEMPTS => {
+                warn!(tries = tries, error = %e, "failed to send ready webhook, retrying");
+                sleep(READY_WEBHOOK_RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A backend to send start and stop notifications to
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// Name of the backend, for logging
+    fn name(&self) -> &'static str;
+    async fn notify_start(&self, server: &Server, connect_string: &str) -> Result<()>;
+    async fn notify_stop(&self, id: &str) -> Result<()>;
+}
+
+/// Notify all backends that a server has been started, failures are logged
+pub async fn notify_start(notifiers: &[Box<dyn Notifier>], server: &Server, connect_string: &str) {
+    for notifier in notifiers {
+        if let Err(e) = notifier.notify_start(server, connect_string).await {
+            warn!(backend = notifier.name(), error = %e, "Failed to send start notification");
+        }
+    }
+}
+
+/// Notify all backends that a server has been stopped, failures are logged
+pub async fn notify_stop(notifiers: &[Box<dyn Notifier>], id: &str) {
+    for notifier in notifiers {
+        if let Err(e) = notifier.notify_stop(id).await {
+            warn!(backend = notifier.name(), error = %e, "Failed to send stop notification");
+        }
+    }
+}
+
+/// Sends notifications as embeds to a discord webhook
+pub struct DiscordNotifier {
+    url: String,
+}
+
+impl DiscordNotifier {
+    pub fn new(url: String) -> Self {
+        DiscordNotifier { url }
+    }
+}
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    async fn notify_start(&self, server: &Server, connect_string: &str) -> Result<()> {
+        discord_start(&self.url, server, connect_string).await
+    }
+
+    async fn notify_stop(&self, id: &str) -> Result<()> {
+        discord_stop(&self.url, id).await
+    }
+}
+
+/// Notify a discord webhook that a server has been started
+async fn discord_start(url: &str, server: &Server, connect_string: &str) -> Result<()> {
+    let mut fields = vec![DiscordField {
+        name: "IP",
+        value: server.reachable_ip().to_string(),
+    }];
+    if let Some(ip_v6) = server.ip_v6 {
+        fields.push(DiscordField {
+            name: "IPv6",
+            value: ip_v6.to_string(),
+        });
+    }
+
+    send(
+        url,
+        DiscordEmbed {
+            title: "Server started",
+            description: format!("`{}`", connect_string),
+            color: COLOR_STARTED,
+            fields,
+        },
+    )
+    .await
+}
+
+/// Notify a discord webhook that a server has been stopped
+async fn discord_stop(url: &str, id: &str) -> Result<()> {
+    send(
+        url,
+        DiscordEmbed {
+            title: "Server stopped",
+            description: format!("Server {} has been shut down", id),
+            color: COLOR_STOPPED,
+            fields: Vec::new(),
+        },
+    )
+    .await
+}
+
+async fn send(url: &str, embed: DiscordEmbed<'_>) -> Result<()> {
+    post_json(
+        url,
+        &DiscordMessage {
+            embeds: vec![embed],
+        },
+    )
+    .await
+}
+
+/// Sends notifications as text messages to a matrix room
+pub struct MatrixNotifier {
+    homeserver: String,
+    room_id: String,
+    access_token: String,
+}
+
+impl MatrixNotifier {
+    pub fn new(config: &MatrixConfig) -> Self {
+        MatrixNotifier {
+            homeserver: config.homeserver.clone(),
+            room_id: config.room_id.clone(),
+            access_token: config.access_token.clone(),
+        }
+    }
+
+    /// The url to send a message event to, every message needs a unique transaction id
+    fn send_url(&self) -> Result<Url> {
+        let transaction_id = format!("dispenser-{:016x}", thread_rng().gen::<u64>());
+        let mut url = Url::parse(&self.homeserver)
+            .map_err(|_| NotifyError::InvalidUrl(self.homeserver.clone()))?;
+        url.path_segments_mut()
+            .map_err(|_| NotifyError::InvalidUrl(self.homeserver.clone()))?
+            .pop_if_empty()
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                &self.room_id,
+                "send",
+                "m.room.message",
+                &transaction_id,
+            ]);
+        Ok(url)
+    }
+
+    async fn send(&self, body: String) -> Result<()> {
+        let request = client()
+            .put(self.send_url()?)
+            .bearer_auth(&self.access_token)
+            .json(&MatrixMessage {
+                msgtype: "m.text",
+                body,
+            });
+        send_request(request).await
+    }
+}
+
+#[async_trait]
+impl Notifier for MatrixNotifier {
+    fn name(&self) -> &'static str {
+        "matrix"
+    }
+
+    async fn notify_start(&self, server: &Server, connect_string: &str) -> Result<()> {
+        self.send(format!(
+            "Server started on {}: {}",
+            server.reachable_ip(),
+            connect_string
+        ))
+        .await
+    }
+
+    async fn notify_stop(&self, id: &str) -> Result<()> {
+        self.send(format!("Server {} has been shut down", id)).await
+    }
+}
+
+async fn post_json<T: Serialize>(url: &str, body: &T) -> Result<()> {
+    send_request(client().post(url).json(body)).await
+}
+
+async fn send_request(request: RequestBuilder) -> Result<()> {
+    let response = request.send().await.map_err(NetworkError)?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(NotifyError::Rejected(
+            response.text().await.map_err(NetworkError)?,
+        ))
+    }
+}
+
+#[derive(Serialize)]
+struct MatrixMessage {
+    msgtype: &'static str,
+    body: String,
+}
+
+#[derive(Serialize)]
+struct DiscordMessage<'a> {
+    embeds: Vec<DiscordEmbed<'a>>,
+}
+
+#[derive(Serialize)]
+struct DiscordEmbed<'a> {
+    title: &'a str,
+    description: String,
+    color: u32,
+    fields: Vec<DiscordField<'a>>,
+}
+
+#[derive(Serialize)]
+struct DiscordField<'a> {
+    name: &'a str,
+    value: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix_send_url() {
+        let notifier = MatrixNotifier {
+            homeserver: "https://matrix.example.com/".into(),
+            room_id: "!room:example.com".into(),
+            access_token: "token".into(),
+        };
+        let url = notifier.send_url().unwrap().to_string();
+        assert!(url.starts_with(
+            "https://matrix.example.com/_matrix/client/v3/rooms/!room:example.com/send/m.room.message/dispenser-"
+        ));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::cloud::Server;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// Output of the `list` command when using `--json`
+#[derive(Debug, Serialize)]
+pub struct ListOutput {
+    pub servers: Vec<ServerOutput>,
+    pub next_start: Option<DateTime<Utc>>,
+    pub next_stop: Option<DateTime<Utc>>,
+}
+
+/// A single server in the `--json` output
+#[derive(Debug, Serialize)]
+pub struct ServerOutput {
+    pub id: String,
+    pub label: String,
+    pub ip: IpAddr,
+    pub ip_v6: Option<IpAddr>,
+    pub private_ip: Option<IpAddr>,
+    pub region: String,
+    pub plan: String,
+    /// Number of players, if the server could be reached over rcon
+    pub players: Option<usize>,
+    /// Connect string for the server, if it belongs to a configured server
+    pub connect: Option<String>,
+    pub uptime_minutes: i64,
+    /// Estimated cost since the server was created, if the price of the plan is known
+    pub estimated_cost: Option<f64>,
+}
+
+impl ServerOutput {
+    pub fn new(server: &Server, players: Option<usize>, connect: Option<String>) -> Self {
+        ServerOutput {
+            id: server.id.clone(),
+            label: server.label.clone(),
+            ip: server.ip,
+            ip_v6: server.ip_v6,
+            private_ip: server.private_ip,
+            region: server.region.clone(),
+            plan: server.plan.clone(),
+            players,
+            connect,
+            uptime_minutes: server.lifetime().num_minutes(),
+            estimated_cost: None,
+        }
+    }
+}
+
+/// Result of starting a single configured server, as part of the `start` command `--json` output
+#[derive(Debug, Serialize)]
+pub struct StartOutput {
+    pub name: String,
+    pub status: StartStatus,
+    pub server: Option<ServerOutput>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum StartStatus {
+    Started,
+    AlreadyRunning,
+    Cancelled,
+    Failed,
+}
+
+/// Print a value as json on stdout
+pub fn print_json<T: Serialize>(value: &T) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).expect("output is always serializable")
+    );
+}
@@ -0,0 +1,54 @@
+use crate::SetupStep;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tracing::debug;
+
+/// Phase of starting a server, for following the progress of a start from a ui
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    /// Creating the server at the cloud provider, the first attempt is 1
+    Spawning { attempt: u32 },
+    /// The server is created and waiting to get an ip
+    WaitingForIp { id: String },
+    /// The server has an ip and is booting
+    Booting { id: String, ip: IpAddr },
+    /// Connecting to the server over ssh
+    ConnectingSsh,
+    /// Running a step of the setup over ssh
+    Setup(SetupStep),
+    /// The setup runs trough cloud-init on the server
+    CloudInit,
+    /// Waiting for the game server to accept rcon connections
+    WaitingForGameServer,
+    /// The server is ready for players
+    Ready { id: String, connect: String },
+    /// Starting the server failed, the next attempt starts after the delay
+    Retrying { attempt: u32, delay: Duration },
+    /// Starting the server failed and won't be retried
+    Failed { error: String },
+}
+
+/// Send an intermediate progress event if anyone is listening
+///
+/// Events are dropped when the receiver can't keep up, so a slow listener doesn't hold up the setup
+pub fn report(progress: Option<&Sender<ProgressEvent>>, event: ProgressEvent) {
+    let Some(progress) = progress else {
+        return;
+    };
+    match progress.try_send(event) {
+        Ok(()) | Err(TrySendError::Closed(_)) => {}
+        Err(TrySendError::Full(event)) => {
+            debug!(event = ?event, "Progress receiver is full, dropping event");
+        }
+    }
+}
+
+/// Send the final `Ready` or `Failed` event, waiting for room in the channel so the outcome isn't lost
+pub async fn report_outcome(progress: Option<&Sender<ProgressEvent>>, event: ProgressEvent) {
+    if let Some(progress) = progress {
+        // a closed channel means nobody is listening anymore
+        let _ = progress.send(event).await;
+    }
+}
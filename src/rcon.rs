@@ -1,25 +1,298 @@
 use crate::Error;
 use rcon::Connection;
 use std::fmt::Debug;
+use std::time::Duration;
 use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::timeout;
 use tracing::instrument;
 
 pub struct Rcon(Connection<TcpStream>);
 
+/// A player connected to the server, as listed by the `status` command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Player {
+    pub userid: u32,
+    pub name: String,
+    pub bot: bool,
+}
+
+/// Which players from the `status` output count towards the player count
+#[derive(Debug, Clone, Copy)]
+pub struct PlayerFilter<'a> {
+    /// Count bots as players, for game modes where bots are the players
+    pub count_bots: bool,
+    /// Name of the SourceTV bot, which is never counted
+    pub tv_name: &'a str,
+}
+
 impl Rcon {
+    /// Connect to the server, giving up if the connection isn't established within the timeout
     #[instrument(skip(password))]
-    pub async fn new<A: ToSocketAddrs + Debug>(host: A, password: &str) -> Result<Self, Error> {
-        Ok(Rcon(Connection::builder().connect(host, password).await?))
+    pub async fn new<A: ToSocketAddrs + Debug>(
+        host: A,
+        password: &str,
+        connect_timeout: Duration,
+    ) -> Result<Self, Error> {
+        let connection = timeout(
+            connect_timeout,
+            Connection::builder().connect(host, password),
+        )
+        .await
+        .map_err(|_| Error::RconTimeout)??;
+        Ok(Rcon(connection))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn player_count(&mut self, filter: PlayerFilter<'_>) -> Result<usize, Error> {
+        let status = self.0.cmd("status").await?;
+        Ok(count_players(&status, filter))
     }
 
+    /// List the connected players, including bots
     #[instrument(skip(self))]
-    pub async fn player_count(&mut self) -> Result<usize, Error> {
+    pub async fn players(&mut self) -> Result<Vec<Player>, Error> {
         let status = self.0.cmd("status").await?;
-        let player_lines = status
-            .lines()
-            .filter(|line| line.starts_with('#'))
-            .filter(|line| !line.contains("# userid"))
-            .filter(|line| !line.contains(" BOT "));
-        Ok(player_lines.count())
+        Ok(parse_players(&status))
+    }
+
+    /// Kick a player from the server
+    #[instrument(skip(self))]
+    pub async fn kick(&mut self, userid: u32, reason: &str) -> Result<String, Error> {
+        let response = self
+            .0
+            .cmd(&format!(
+                "kickid {} \"{}\"",
+                userid,
+                sanitize_reason(reason)
+            ))
+            .await?;
+        Ok(response.trim().into())
+    }
+
+    /// Ban a player for a number of minutes, 0 bans the player permanently
+    ///
+    /// The player is kicked with the reason after being banned
+    #[instrument(skip(self))]
+    pub async fn ban(&mut self, userid: u32, minutes: u32, reason: &str) -> Result<String, Error> {
+        let response = self.0.cmd(&format!("banid {} {}", minutes, userid)).await?;
+        let kick_response = self.kick(userid, reason).await?;
+        Ok(format!("{}\n{}", response.trim(), kick_response)
+            .trim()
+            .into())
+    }
+
+    /// Get the player count, failing if the server doesn't respond in time
+    ///
+    /// Used for connections that are kept open, where the server might have gone away without closing the connection
+    pub async fn player_count_timeout(
+        &mut self,
+        filter: PlayerFilter<'_>,
+        response_timeout: Duration,
+    ) -> Result<usize, Error> {
+        timeout(response_timeout, self.player_count(filter))
+            .await
+            .map_err(|_| Error::RconTimeout)?
+    }
+
+    /// Run an arbitrary command, returns the response from the server
+    #[instrument(skip(self))]
+    pub async fn command(&mut self, command: &str) -> Result<String, Error> {
+        Ok(self.0.cmd(command).await?)
+    }
+
+    /// Change the map, returns the response from the server
+    #[instrument(skip(self))]
+    pub async fn changelevel(&mut self, map: &str) -> Result<String, Error> {
+        // map names are passed as-is in the rcon command, don't allow anything that could add extra commands
+        let valid = !map.is_empty()
+            && map
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if !valid {
+            return Err(Error::InvalidMap(map.into()));
+        }
+        let response = self.0.cmd(&format!("changelevel {}", map)).await?;
+        Ok(response.trim().into())
+    }
+
+    /// Get the value of a console variable, `None` if the variable doesn't exist
+    #[instrument(skip(self))]
+    pub async fn cvar(&mut self, name: &str) -> Result<Option<String>, Error> {
+        let response = self.0.cmd(name).await?;
+        Ok(parse_cvar(&response, name).map(String::from))
+    }
+
+    /// Broadcast a chat message to all players
+    #[instrument(skip(self))]
+    pub async fn say(&mut self, message: &str) -> Result<(), Error> {
+        self.0.cmd(&format!("say {}", message)).await?;
+        Ok(())
+    }
+}
+
+/// Count the players in the output of the `status` command
+fn count_players(status: &str, filter: PlayerFilter<'_>) -> usize {
+    parse_players(status)
+        .iter()
+        .filter(|player| filter.count_bots || !player.bot)
+        .filter(|player| !player.is_source_tv(filter.tv_name))
+        .count()
+}
+
+/// Parse the player lines from the output of the `status` command
+///
+/// Player lines look like `#      3 "Player One"        [U:1:12345678]      12:34       40    0 active 198.51.100.1:27005`
+fn parse_players(status: &str) -> Vec<Player> {
+    status
+        .lines()
+        .filter_map(|line| {
+            let userid = line
+                .strip_prefix('#')?
+                .split_whitespace()
+                .next()?
+                .parse()
+                .ok()?;
+            let start = line.find('"')?;
+            let end = line.rfind('"').filter(|&end| end > start)?;
+            Some(Player {
+                userid,
+                name: line[start + 1..end].into(),
+                bot: line[end..].contains(" BOT "),
+            })
+        })
+        .collect()
+}
+
+impl Player {
+    /// SourceTV shows up as a bot with the configured tv name
+    fn is_source_tv(&self, tv_name: &str) -> bool {
+        self.bot && (self.name == tv_name || self.name == "SourceTV")
+    }
+}
+
+/// Find a player by userid or name
+///
+/// Names are matched case-insensitively, a partial name is accepted if it only matches a single player
+pub fn find_player<'a>(players: &'a [Player], query: &str) -> Result<&'a Player, Error> {
+    if let Ok(userid) = query.parse::<u32>() {
+        if let Some(player) = players.iter().find(|player| player.userid == userid) {
+            return Ok(player);
+        }
+    }
+    let query_lower = query.to_lowercase();
+    if let Some(player) = players
+        .iter()
+        .find(|player| player.name.to_lowercase() == query_lower)
+    {
+        return Ok(player);
+    }
+    let mut matches = players
+        .iter()
+        .filter(|player| player.name.to_lowercase().contains(&query_lower));
+    match (matches.next(), matches.next()) {
+        (Some(player), None) => Ok(player),
+        (Some(_), Some(_)) => Err(Error::AmbiguousPlayer(query.into())),
+        (None, _) => Err(Error::PlayerNotFound(query.into())),
+    }
+}
+
+/// Parse the value from the response to querying a console variable
+///
+/// The response looks like `"mp_timelimit" = "30" ( def. "0" ) min. 0.000000 notify`
+fn parse_cvar<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    let rest = response
+        .trim_start()
+        .strip_prefix(&format!("\"{}\" = \"", name))?;
+    rest.split_once('"').map(|(value, _)| value)
+}
+
+/// Remove characters from a kick reason that would end the quoted argument or start a new command
+fn sanitize_reason(reason: &str) -> String {
+    reason
+        .chars()
+        .filter(|c| !matches!(c, '"' | ';') && !c.is_control())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = r#"hostname: Spire
+version : 8622567/24 8622567 secure
+udp/ip  : 0.0.0.0:27015  (public ip: 203.0.113.10)
+map     : cp_process_f12 at: 0 x, 0 y, 0 z
+tags    : cp
+players : 2 humans, 2 bots (25 max)
+edicts  : 571 used of 2048 max
+# userid name                uniqueid            connected ping loss state  adr
+#      2 "SpireTV"           BOT                                     active
+#      3 "Player One"        [U:1:12345678]      12:34       40    0 active 198.51.100.1:27005
+#      4 "Player "Two""    [U:1:23456789]      05:01       60    0 active 198.51.100.2:27005
+#      5 "Heavy"             BOT                                     active
+"#;
+
+    fn filter(count_bots: bool) -> PlayerFilter<'static> {
+        PlayerFilter {
+            count_bots,
+            tv_name: "SpireTV",
+        }
+    }
+
+    #[test]
+    fn test_count_players() {
+        assert_eq!(count_players(STATUS, filter(false)), 2);
+        assert_eq!(count_players(STATUS, filter(true)), 3);
+    }
+
+    #[test]
+    fn test_find_player() {
+        let players = parse_players(STATUS);
+        assert_eq!(players.len(), 4);
+        assert_eq!(
+            players[2],
+            Player {
+                userid: 4,
+                name: "Player \"Two\"".into(),
+                bot: false,
+            }
+        );
+        assert_eq!(find_player(&players, "3").unwrap().name, "Player One");
+        assert_eq!(find_player(&players, "heavy").unwrap().userid, 5);
+        assert_eq!(find_player(&players, "two").unwrap().userid, 4);
+        assert!(matches!(
+            find_player(&players, "player"),
+            Err(Error::AmbiguousPlayer(_))
+        ));
+        assert!(matches!(
+            find_player(&players, "nobody"),
+            Err(Error::PlayerNotFound(_))
+        ));
+        assert_eq!(sanitize_reason("bye\"; quit"), "bye quit");
+    }
+
+    #[test]
+    fn test_parse_cvar() {
+        assert_eq!(
+            parse_cvar(
+                "\"mp_timelimit\" = \"30\" ( def. \"0\" ) min. 0.000000 notify\n - game time per map in minutes\n",
+                "mp_timelimit"
+            ),
+            Some("30")
+        );
+        assert_eq!(
+            parse_cvar("Unknown command \"mp_timelimt\"\n", "mp_timelimt"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_count_players_only_stv() {
+        let status =
+            "# userid name                uniqueid            connected ping loss state  adr
+#      2 \"SourceTV\"          BOT                                     active
+";
+        assert_eq!(count_players(status, filter(false)), 0);
+        assert_eq!(count_players(status, filter(true)), 0);
     }
 }
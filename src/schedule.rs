@@ -0,0 +1,172 @@
+use crate::config::ScheduleConfig;
+use chrono::{DateTime, Duration, Utc};
+use cron::Schedule;
+use tracing::{info, warn};
+
+/// Number of poll intervals the clock can go backwards before the schedule is resynced from the new time
+const RESYNC_POLL_INTERVALS: i64 = 3;
+
+/// Part of the schedule we're in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    /// After a start time, the servers should be running
+    Start,
+    /// After a stop time, the servers should be stopped
+    Stop,
+}
+
+/// Keeps track of which scheduled start and stop times have passed between checks
+///
+/// Only scheduled times after the previous check are considered, so a clock that jumps back doesn't repeat
+/// a start or stop that already happened, and times that passed while the process was asleep aren't missed.
+/// A clock that jumps back further than a few poll intervals is treated as a correction instead,
+/// and the window is decided again from the new time.
+pub struct ScheduleTracker {
+    start: Schedule,
+    stop: Schedule,
+    window: Option<Window>,
+    /// Latest time that was checked, which never moves backwards
+    checked_until: Option<DateTime<Utc>>,
+}
+
+impl ScheduleTracker {
+    pub fn new(start: Schedule, stop: Schedule) -> Self {
+        ScheduleTracker {
+            start,
+            stop,
+            window: None,
+            checked_until: None,
+        }
+    }
+
+    /// The window for the current time
+    pub fn window(&mut self, config: &ScheduleConfig, now: DateTime<Utc>) -> Window {
+        let max_backwards =
+            Duration::seconds(config.poll_interval_secs as i64 * RESYNC_POLL_INTERVALS);
+        if let Some(checked_until) = self.checked_until {
+            if now < checked_until - max_backwards {
+                warn!(
+                    %now,
+                    %checked_until,
+                    "Clock went backwards by a large amount, resyncing the schedule"
+                );
+                self.checked_until = None;
+            }
+        }
+
+        let window = match (self.window, self.checked_until) {
+            (Some(window), Some(checked_until)) if now <= checked_until => {
+                if now < checked_until {
+                    warn!(
+                        %now,
+                        %checked_until,
+                        "Clock went backwards, waiting for it to catch up before following the schedule"
+                    );
+                }
+                window
+            }
+            (Some(window), Some(checked_until)) => {
+                let start = config.last_between(&self.start, checked_until, now);
+                let stop = config.last_between(&self.stop, checked_until, now);
+                let new_window = match (start, stop) {
+                    (Some(start), Some(stop)) if start > stop => Window::Start,
+                    (Some(_), Some(_)) => Window::Stop,
+                    (Some(_), None) => Window::Start,
+                    (None, Some(_)) => Window::Stop,
+                    (None, None) => window,
+                };
+                if new_window != window {
+                    info!(
+                        window = ?new_window,
+                        since = %checked_until,
+                        "Passed a scheduled time since the last check"
+                    );
+                }
+                new_window
+            }
+            // without an earlier check, the window is decided by which of the two comes next
+            _ => match (
+                config.next_after(&self.start, now),
+                config.next_after(&self.stop, now),
+            ) {
+                (Some(next_start), Some(next_stop)) if next_start > next_stop => Window::Start,
+                (None, Some(_)) => Window::Start,
+                _ => Window::Stop,
+            },
+        };
+        self.window = Some(window);
+        self.checked_until = self.checked_until.max(Some(now));
+        window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn new_tracker(timezone: &str) -> (ScheduleTracker, ScheduleConfig) {
+        let config: ScheduleConfig = toml::from_str(&format!(
+            r#"
+            start = "0 0 17 * * *"
+            stop = "0 0 23 * * *"
+            timezone = "{timezone}"
+            "#
+        ))
+        .unwrap();
+        let (start, stop) = config.schedules().unwrap();
+        (ScheduleTracker::new(start, stop), config)
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 6, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_window() {
+        let (mut tracker, config) = new_tracker("UTC");
+        assert_eq!(tracker.window(&config, at(1, 16, 0)), Window::Stop);
+        assert_eq!(tracker.window(&config, at(1, 17, 1)), Window::Start);
+        assert_eq!(tracker.window(&config, at(1, 17, 1)), Window::Start);
+        assert_eq!(tracker.window(&config, at(1, 23, 1)), Window::Stop);
+
+        let (mut tracker, config) = new_tracker("UTC");
+        assert_eq!(tracker.window(&config, at(1, 18, 0)), Window::Start);
+    }
+
+    #[test]
+    fn test_clock_backwards() {
+        let (mut tracker, config) = new_tracker("UTC");
+        assert_eq!(tracker.window(&config, at(1, 22, 59)), Window::Start);
+        assert_eq!(tracker.window(&config, at(1, 23, 1)), Window::Stop);
+        // jumping back to before the stop time doesn't restart the server
+        assert_eq!(tracker.window(&config, at(1, 22, 58)), Window::Stop);
+        // and the stop time isn't repeated once the clock catches up
+        assert_eq!(tracker.window(&config, at(1, 23, 2)), Window::Stop);
+
+        assert_eq!(tracker.window(&config, at(1, 16, 0)), Window::Stop);
+        assert_eq!(tracker.window(&config, at(2, 17, 1)), Window::Start);
+    }
+
+    #[test]
+    fn test_clock_correction() {
+        let (mut tracker, config) = new_tracker("UTC");
+        assert_eq!(tracker.window(&config, at(3, 23, 1)), Window::Stop);
+        // a large correction back into a start window follows the schedule from the new time
+        assert_eq!(tracker.window(&config, at(1, 18, 0)), Window::Start);
+        assert_eq!(tracker.window(&config, at(1, 23, 1)), Window::Stop);
+        assert_eq!(tracker.window(&config, at(2, 17, 1)), Window::Start);
+    }
+
+    #[test]
+    fn test_clock_forwards() {
+        let (mut tracker, config) = new_tracker("UTC");
+        assert_eq!(tracker.window(&config, at(1, 16, 0)), Window::Stop);
+        // asleep over the entire start window, the latest time that passed is the stop time
+        assert_eq!(tracker.window(&config, at(1, 23, 30)), Window::Stop);
+        // asleep over a stop and the next start
+        assert_eq!(tracker.window(&config, at(2, 18, 0)), Window::Start);
+        assert_eq!(tracker.window(&config, at(3, 18, 0)), Window::Start);
+        assert_eq!(tracker.window(&config, at(4, 8, 0)), Window::Stop);
+    }
+}
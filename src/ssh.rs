@@ -1,19 +1,75 @@
+use crate::config::HostKeyVerification;
 use crate::CreatedAuth;
 use futures_util::future::{self};
+use futures_util::stream::{unfold, Stream};
 use std::convert::identity;
 use std::fmt::{Debug, Formatter};
-use std::io::Write;
 use std::net::IpAddr;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Duration;
 use thiserror::Error;
 use thrussh::client::Handle;
 use thrussh::*;
-use thrussh_keys::key::PublicKey;
+use thrussh_keys::key::{KeyPair, PublicKey};
 use tokio::time::{sleep, timeout};
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 
-struct Client {}
+struct Client {
+    host_key: HostKeyCheck,
+}
+
+/// How to verify the host key presented by the server
+#[derive(Debug, Clone)]
+pub enum HostKeyCheck {
+    /// Accept any host key
+    Insecure,
+    /// Record the first host key seen and require the same key for later connections
+    TrustOnFirstUse(Arc<Mutex<Option<String>>>),
+}
+
+impl HostKeyCheck {
+    pub fn new(verification: HostKeyVerification, fingerprint: Option<String>) -> Self {
+        match verification {
+            HostKeyVerification::None => HostKeyCheck::Insecure,
+            // a fingerprint seen earlier counts as the first use
+            HostKeyVerification::TrustOnFirstUse => HostKeyCheck::TrustOnFirstUse(Arc::new(
+                Mutex::new(fingerprint.map(|fingerprint| {
+                    fingerprint
+                        .strip_prefix("SHA256:")
+                        .unwrap_or(&fingerprint)
+                        .to_string()
+                })),
+            )),
+        }
+    }
+
+    /// The sha256 fingerprint that connections are checked against, once known
+    pub fn fingerprint(&self) -> Option<String> {
+        match self {
+            HostKeyCheck::Insecure => None,
+            HostKeyCheck::TrustOnFirstUse(known) => known.lock().unwrap().clone(),
+        }
+    }
+
+    fn check(&self, key: &PublicKey) -> bool {
+        let fingerprint = key.fingerprint();
+        match self {
+            HostKeyCheck::Insecure => true,
+            HostKeyCheck::TrustOnFirstUse(known) => {
+                let mut known = known.lock().unwrap();
+                match known.as_deref() {
+                    Some(known) => known == fingerprint,
+                    None => {
+                        info!(fingerprint = display(&fingerprint), "trusting host key");
+                        *known = Some(fingerprint);
+                        true
+                    }
+                }
+            }
+        }
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum SshError {
@@ -27,6 +83,10 @@ pub enum SshError {
     Disconnected,
     #[error("Connection refused")]
     Refused,
+    #[error("Host key doesn't match the expected key")]
+    HostKeyMismatch,
+    #[error("Failed to load ssh key: {0}")]
+    Key(#[from] thrussh_keys::Error),
 }
 
 #[derive(Debug, Error)]
@@ -38,6 +98,7 @@ impl From<thrussh::Error> for SshError {
         match e {
             Error::Disconnect | Error::HUP => SshError::Disconnected,
             Error::ConnectionTimeout => SshError::ConnectionTimeout,
+            Error::UnknownKey => SshError::HostKeyMismatch,
             Error::IO(io) if io.raw_os_error() == Some(110) => SshError::ConnectionTimeout,
             Error::IO(io) if io.raw_os_error() == Some(111) => SshError::Refused,
             e => SshError::Other(SshErrorImpl(e)),
@@ -56,13 +117,22 @@ impl client::Handler for Client {
     fn finished(self, session: client::Session) -> Self::FutureUnit {
         future::ready(Ok((self, session)))
     }
-    fn check_server_key(self, _server_public_key: &PublicKey) -> Self::FutureBool {
-        self.finished_bool(true)
+    fn check_server_key(self, server_public_key: &PublicKey) -> Self::FutureBool {
+        let valid = self.host_key.check(server_public_key);
+        if !valid {
+            warn!(
+                fingerprint = display(server_public_key.fingerprint()),
+                "host key mismatch"
+            );
+        }
+        self.finished_bool(valid)
     }
 }
 
 pub struct SshSession {
     ip: IpAddr,
+    /// User the session is logged in as, commands are run trough sudo for users other than root
+    user: String,
     handle: Handle<Client>,
 }
 
@@ -70,17 +140,24 @@ impl Debug for SshSession {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("SshSession")
             .field("ip", &format_args!("{}", self.ip))
+            .field("user", &self.user)
             .finish_non_exhaustive()
     }
 }
 
 impl SshSession {
     #[instrument(skip(auth))]
-    pub async fn open(ip: IpAddr, auth: &CreatedAuth) -> Result<Self, SshError> {
-        timeout(Duration::from_secs(10 * 60), async move {
+    pub async fn open(
+        ip: IpAddr,
+        user: &str,
+        auth: &CreatedAuth,
+        host_key: &HostKeyCheck,
+        boot_timeout: Duration,
+    ) -> Result<Self, SshError> {
+        timeout(boot_timeout, async move {
             loop {
                 sleep(Duration::from_secs(5)).await;
-                match SshSession::open_impl(ip, auth).await {
+                match SshSession::open_impl(ip, user, auth, host_key).await {
                     Ok(ssh) => return Ok(ssh),
                     Err(err @ (SshError::ConnectionTimeout | SshError::Refused)) => {
                         info!(error = ?err, "ssh server not ready yes");
@@ -94,20 +171,36 @@ impl SshSession {
         .and_then(identity)
     }
 
-    async fn open_impl(ip: IpAddr, auth: &CreatedAuth) -> Result<Self, SshError> {
-        let config = client::Config::default();
+    async fn open_impl(
+        ip: IpAddr,
+        user: &str,
+        auth: &CreatedAuth,
+        host_key: &HostKeyCheck,
+    ) -> Result<Self, SshError> {
+        let mut config = client::Config::default();
+        if let Some(preferred) = PREFERRED.get() {
+            config.preferred = Preferred { ..*preferred };
+        }
         let config = Arc::new(config);
-        let sh = Client {};
+        let sh = Client {
+            host_key: host_key.clone(),
+        };
 
         let mut handle = client::connect(config, (ip, 22), sh).await?;
         let result = match auth {
-            CreatedAuth::Password(password) => {
-                handle.authenticate_password("root", password).await?
+            CreatedAuth::Password(password) => handle.authenticate_password(user, password).await?,
+            CreatedAuth::Ssh(key) => handle.authenticate_publickey(user, key.clone()).await?,
+            CreatedAuth::PasswordOrKey(password, key) => {
+                handle.authenticate_publickey(user, key.clone()).await?
+                    || handle.authenticate_password(user, password).await?
             }
-            CreatedAuth::Ssh(key) => handle.authenticate_publickey("root", key.clone()).await?,
         };
         if result {
-            Ok(SshSession { ip, handle })
+            Ok(SshSession {
+                ip,
+                user: user.into(),
+                handle,
+            })
         } else {
             Err(SshError::Unauthorized)
         }
@@ -117,23 +210,55 @@ impl SshSession {
     pub async fn exec<S: Into<String> + Debug>(
         &mut self,
         cmd: S,
+    ) -> Result<CommandResult, SshError> {
+        self.exec_with_limit(cmd, DEFAULT_OUTPUT_LIMIT).await
+    }
+
+    /// Execute a command, keeping at most `limit` bytes of each output stream
+    ///
+    /// Output past the limit is dropped and marked as truncated
+    #[instrument]
+    pub async fn exec_with_limit<S: Into<String> + Debug>(
+        &mut self,
+        cmd: S,
+        limit: usize,
     ) -> Result<CommandResult, SshError> {
         let mut channel = self.handle.channel_open_session().await?;
-        channel.exec(true, cmd).await?;
-        let mut output = Vec::new();
-        let mut code = None;
+        channel
+            .exec(true, privileged(&self.user, cmd.into()))
+            .await?;
+        let mut result = CommandResult::with_limit(limit);
         while let Some(msg) = channel.wait().await {
-            match msg {
-                ChannelMsg::Data { ref data } => {
-                    output.write_all(data).unwrap();
-                }
-                ChannelMsg::ExitStatus { exit_status } => {
-                    code = Some(exit_status);
+            result.handle(msg);
+        }
+        if result.truncated() {
+            warn!(limit, "command output exceeded the limit and was truncated");
+        }
+        Ok(result)
+    }
+
+    /// Execute a command and stream the output as it arrives
+    ///
+    /// Both stdout and stderr are included in the stream
+    #[instrument]
+    pub async fn exec_stream<S: Into<String> + Debug>(
+        &mut self,
+        cmd: S,
+    ) -> Result<impl Stream<Item = Vec<u8>>, SshError> {
+        let mut channel = self.handle.channel_open_session().await?;
+        channel
+            .exec(true, privileged(&self.user, cmd.into()))
+            .await?;
+        Ok(unfold(channel, |mut channel| async move {
+            loop {
+                match channel.wait().await? {
+                    ChannelMsg::Data { data } | ChannelMsg::ExtendedData { data, .. } => {
+                        return Some((data.to_vec(), channel));
+                    }
+                    _ => {}
                 }
-                _ => {}
             }
-        }
-        Ok(CommandResult { output, code })
+        }))
     }
 
     #[instrument]
@@ -146,17 +271,204 @@ impl SshSession {
     }
 }
 
+/// Load a private key from disk to authenticate with
+pub fn load_key<P: AsRef<Path>>(path: P) -> Result<CreatedAuth, SshError> {
+    Ok(CreatedAuth::Ssh(load_secret_key(path)?))
+}
+
+/// Load an unencrypted private key from disk
+pub fn load_secret_key<P: AsRef<Path>>(path: P) -> Result<Arc<KeyPair>, SshError> {
+    Ok(Arc::new(thrussh_keys::load_secret_key(path, None)?))
+}
+
+static PREFERRED: OnceLock<Preferred> = OnceLock::new();
+
+/// Restrict the algorithms negotiated for all ssh connections, needs to be set before any connection is made
+pub fn set_preferred(preferred: Preferred) {
+    if PREFERRED.set(preferred).is_err() {
+        panic!("ssh algorithms can only be set once");
+    }
+}
+
+/// Maximum output kept for a single command, to avoid unbounded memory use for commands with a lot of output
+const DEFAULT_OUTPUT_LIMIT: usize = 1024 * 1024;
+
+const TRUNCATED_MARKER: &str = "\n[output truncated]";
+
+#[derive(Debug)]
 pub struct CommandResult {
+    /// Stdout and stderr combined, in the order they were received
     output: Vec<u8>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    /// Maximum number of bytes to keep in each of the output buffers
+    limit: usize,
+    truncated: bool,
     pub code: Option<u32>,
 }
 
+impl Default for CommandResult {
+    fn default() -> Self {
+        CommandResult::with_limit(DEFAULT_OUTPUT_LIMIT)
+    }
+}
+
 impl CommandResult {
+    fn with_limit(limit: usize) -> Self {
+        CommandResult {
+            output: Vec::new(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            limit,
+            truncated: false,
+            code: None,
+        }
+    }
+
+    /// Capture the output and exit status from a channel message
+    fn handle(&mut self, msg: ChannelMsg) {
+        match msg {
+            ChannelMsg::Data { ref data } => {
+                self.truncated |= !append_limited(&mut self.stdout, data, self.limit);
+                self.truncated |= !append_limited(&mut self.output, data, self.limit);
+            }
+            // extended data type 1 is stderr
+            ChannelMsg::ExtendedData { ref data, ext: 1 } => {
+                self.truncated |= !append_limited(&mut self.stderr, data, self.limit);
+                self.truncated |= !append_limited(&mut self.output, data, self.limit);
+            }
+            ChannelMsg::ExitStatus { exit_status } => {
+                self.code = Some(exit_status);
+            }
+            _ => {}
+        }
+    }
+
     pub fn output(&self) -> String {
-        String::from_utf8_lossy(&self.output).into()
+        self.format(&self.output)
+    }
+
+    pub fn stdout(&self) -> String {
+        self.format(&self.stdout)
+    }
+
+    pub fn stderr(&self) -> String {
+        self.format(&self.stderr)
+    }
+
+    /// Whether output was dropped because it exceeded the limit
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    fn format(&self, buffer: &[u8]) -> String {
+        let mut formatted = String::from_utf8_lossy(buffer).into_owned();
+        if buffer.len() >= self.limit && self.truncated {
+            formatted.push_str(TRUNCATED_MARKER);
+        }
+        formatted
     }
 
     pub fn success(&self) -> bool {
         self.code == Some(0)
     }
 }
+
+/// Append data to a buffer without growing it past the limit, returns false if data was dropped
+fn append_limited(buffer: &mut Vec<u8>, data: &[u8], limit: usize) -> bool {
+    let available = limit.saturating_sub(buffer.len());
+    let take = data.len().min(available);
+    buffer.extend_from_slice(&data[..take]);
+    take == data.len()
+}
+
+/// Quote a value so it's passed as a single argument by the remote shell
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Run a command as root, using sudo when logged in as a different user
+///
+/// The command is wrapped in a shell so redirects and chained commands also run as root
+fn privileged(user: &str, cmd: String) -> String {
+    if user == "root" {
+        cmd
+    } else {
+        format!("sudo -n sh -c {}", shell_quote(&cmd))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_privileged() {
+        assert_eq!(privileged("root", "docker ps".into()), "docker ps");
+        assert_eq!(
+            privileged("ubuntu", "echo 'a' >> /etc/fstab".into()),
+            r#"sudo -n sh -c 'echo '\''a'\'' >> /etc/fstab'"#
+        );
+    }
+
+    #[test]
+    fn test_saved_host_key() {
+        let key = KeyPair::generate_ed25519().unwrap().clone_public_key();
+        let other = KeyPair::generate_ed25519().unwrap().clone_public_key();
+
+        let first_use = HostKeyCheck::new(HostKeyVerification::TrustOnFirstUse, None);
+        assert!(first_use.check(&key));
+        assert_eq!(first_use.fingerprint(), Some(key.fingerprint()));
+
+        let saved = HostKeyCheck::new(
+            HostKeyVerification::TrustOnFirstUse,
+            Some(format!("SHA256:{}", key.fingerprint())),
+        );
+        assert!(!saved.check(&other));
+        assert!(saved.check(&key));
+    }
+
+    #[test]
+    fn test_command_result_captures_output() {
+        let mut result = CommandResult::default();
+        result.handle(ChannelMsg::Data {
+            data: CryptoVec::from_slice(b"out "),
+        });
+        result.handle(ChannelMsg::ExtendedData {
+            data: CryptoVec::from_slice(b"err "),
+            ext: 1,
+        });
+        result.handle(ChannelMsg::Data {
+            data: CryptoVec::from_slice(b"more"),
+        });
+        assert!(!result.success());
+        result.handle(ChannelMsg::ExitStatus { exit_status: 0 });
+
+        assert_eq!(result.output(), "out err more");
+        assert_eq!(result.stdout(), "out more");
+        assert_eq!(result.stderr(), "err ");
+        assert!(result.success());
+    }
+
+    #[test]
+    fn test_command_result_truncates_output() {
+        let mut result = CommandResult::with_limit(8);
+        result.handle(ChannelMsg::Data {
+            data: CryptoVec::from_slice(b"0123"),
+        });
+        assert!(!result.truncated());
+        result.handle(ChannelMsg::Data {
+            data: CryptoVec::from_slice(b"456789"),
+        });
+        assert!(result.truncated());
+        assert_eq!(result.stdout(), format!("01234567{}", TRUNCATED_MARKER));
+        assert_eq!(result.stderr(), "");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("foo bar"), "'foo bar'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$HOME; rm"), "'$HOME; rm'");
+    }
+}
@@ -0,0 +1,106 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, rename, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StateError {
+    #[error("Failed to access state file {0}: {1}")]
+    Io(Utf8PathBuf, std::io::Error),
+    #[error("Invalid state file {0}: {1}")]
+    Json(Utf8PathBuf, serde_json::Error),
+}
+
+/// Daemon state that is persisted between restarts, keyed by the server name
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaemonState {
+    #[serde(default)]
+    pub servers: BTreeMap<String, ServerState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerState {
+    /// Id of the cloud server
+    pub server_id: String,
+    pub spawned: DateTime<Utc>,
+    /// When the daemon started trying to stop the server
+    pub stop_started: Option<DateTime<Utc>>,
+    /// Number of consecutive checks that found the server empty
+    #[serde(default)]
+    pub empty_checks: u32,
+    /// Connect password of the server, if it was rotated
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Whether the players voted to extend the current shutdown
+    #[serde(default)]
+    pub stop_extended: bool,
+}
+
+impl DaemonState {
+    /// Load the state, a missing state file results in an empty state
+    pub fn load(path: &Utf8Path) -> Result<Self, StateError> {
+        match read_to_string(path) {
+            Ok(content) => {
+                serde_json::from_str(&content).map_err(|e| StateError::Json(path.into(), e))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(DaemonState::default()),
+            Err(e) => Err(StateError::Io(path.into(), e)),
+        }
+    }
+
+    /// Save the state, the file is replaced atomically so a crash can't leave a partial file
+    ///
+    /// The state contains the server passwords, so the file is only readable by the owner
+    pub fn save(&self, path: &Utf8Path) -> Result<(), StateError> {
+        let content =
+            serde_json::to_string_pretty(self).map_err(|e| StateError::Json(path.into(), e))?;
+        let tmp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .map_err(|e| StateError::Io(tmp_path.clone(), e))?;
+        file.write_all(content.as_bytes())
+            .map_err(|e| StateError::Io(tmp_path.clone(), e))?;
+        rename(&tmp_path, path).map_err(|e| StateError::Io(path.into(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::temp_dir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_save_load() {
+        let path = Utf8PathBuf::from_path_buf(temp_dir())
+            .unwrap()
+            .join(format!("dispenser-state-{}.json", std::process::id()));
+        assert_eq!(DaemonState::load(&path).unwrap(), DaemonState::default());
+
+        let mut state = DaemonState::default();
+        state.servers.insert(
+            "Spire".into(),
+            ServerState {
+                server_id: "1234".into(),
+                spawned: Utc::now(),
+                stop_started: None,
+                empty_checks: 0,
+                password: None,
+                stop_extended: false,
+            },
+        );
+        state.save(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(DaemonState::load(&path).unwrap(), state);
+        std::fs::remove_file(&path).unwrap();
+    }
+}